@@ -0,0 +1,179 @@
+from typing import Any, Dict, Iterator, List, Optional, Sequence, Tuple, Union
+
+class SubsampleResult:
+    output_path: str
+    reads_scanned: int
+    reads_written: int
+    elapsed_seconds: float
+    chunks: int
+    chunk_timings: List[Tuple[float, float, int, int]]
+    barcode_counts: Optional[Dict[str, int]]
+    split_counts: Optional[Dict[str, int]]
+    molecules: Optional[int]
+    skipped_records: int
+    shard_counts: Optional[List[int]]
+
+class ThreadPoolHandle:
+    n_threads: int
+    def __init__(self, n_threads: int) -> None: ...
+
+def subsample_bam(
+    bam_file: Union[str, Sequence[str]],
+    bam_tag: str,
+    outfile: str,
+    *,
+    barcodes_file: Optional[str] = None,
+    to_replace: Optional[str] = None,
+    replacement: Optional[str] = None,
+    n_threads: int = 1,
+    min_tlen: Optional[int] = None,
+    max_tlen: Optional[int] = None,
+    tlen_keep_unpaired: bool = False,
+    header_template: Optional[str] = None,
+    trim_softclips: bool = False,
+    barcode_mismatches: int = 0,
+    barcode_suffix_delimiter: Optional[str] = None,
+    validate_output: bool = False,
+    barcode_column: Optional[int] = None,
+    min_aligned_length: Optional[int] = None,
+    idxstats_output: Optional[str] = None,
+    fail_fast: bool = False,
+    split_output_dir: Optional[str] = None,
+    max_open_files: int = 0,
+    strand: Optional[str] = None,
+    strand_from_read1: bool = False,
+    decompress_threads: int = 0,
+    rewrite_only: bool = False,
+    fastq_output: Optional[str] = None,
+    fastq_compression: Optional[int] = None,
+    resume_dir: Optional[str] = None,
+    mask_low_qual: Optional[int] = None,
+    case_insensitive: bool = False,
+    multiqc_output: Optional[str] = None,
+    bed_file: Optional[str] = None,
+    per_barcode_targets: Optional[str] = None,
+    remove_duplicates: bool = False,
+    sort_by_tag: bool = False,
+    saturation_output: Optional[str] = None,
+    umi_tag: Optional[str] = None,
+    split_by_chrom: bool = False,
+    set_sort_order: Optional[str] = None,
+    positions_file: Optional[str] = None,
+    replacement_template: Optional[str] = None,
+    prune_header: bool = False,
+    strict_barcode_lengths: bool = False,
+    head: Optional[int] = None,
+    progress_interval: Optional[int] = None,
+    keep_tags: Optional[Sequence[str]] = None,
+    strip_tags: Optional[Sequence[str]] = None,
+    expected_input_md5: Optional[str] = None,
+    tag_field: Optional[Tuple[str, int]] = None,
+    chunks_per_core: int = 1,
+    barcode_has_header: bool = False,
+    collapse_molecules: Optional[Tuple[str, str]] = None,
+    assert_sorted: bool = False,
+    translation_map: Optional[str] = None,
+    mate: Optional[str] = None,
+    mate_keep_unpaired: bool = False,
+    max_write_errors: Optional[int] = None,
+    sample_n_barcodes: Optional[int] = None,
+    seed: Optional[int] = None,
+    annotate_source_tag: Optional[str] = None,
+    coverage_bed: Optional[str] = None,
+    create_output_dirs: bool = False,
+    temp_prefix: Optional[str] = None,
+    regex_routes: Sequence[Tuple[str, str]] = (),
+    regex_default_output: Optional[str] = None,
+    subsample_fraction: Optional[float] = None,
+    subsample_seed: Optional[int] = None,
+    max_memory_mb: Optional[int] = None,
+    interleave: bool = False,
+    untagged_only: bool = False,
+    num_shards: Optional[int] = None,
+    denylist: bool = False,
+    strip_alignment: bool = False,
+    validate_dna: bool = False,
+    reorder_to: Optional[str] = None,
+    min_gc: Optional[float] = None,
+    max_gc: Optional[float] = None,
+    flagstat_output: Optional[str] = None,
+    ordered_writer: bool = False,
+    tag_chunk: Optional[str] = None,
+    indel_only: bool = False,
+    min_indel_length: Optional[int] = None,
+    max_output_bytes: Optional[int] = None,
+    htslib_options: Optional[Sequence[Tuple[str, str]]] = None,
+    uppercase_tag: bool = False,
+    strict_tags: bool = False,
+    min_barcodes: Optional[int] = None,
+    unmatched_histogram: Optional[str] = None,
+    sort_by_name: bool = False,
+    extra_tag_whitelists: Optional[Sequence[Tuple[str, str]]] = None,
+    match_any_tag: bool = False,
+    mapped_only: bool = False,
+    discordant_only: bool = False,
+    discordant_min_insert: Optional[int] = None,
+    fixmate: bool = False,
+    thread_pool: Optional[ThreadPoolHandle] = None,
+) -> SubsampleResult:
+    """Subsamples one or more BAM files to the reads whose tag is in the barcode file.
+
+    `seed` drives barcode sampling, the saturation curve and, unless
+    `subsample_seed` is given, `subsample_fraction`, so the same inputs and seed
+    give the same output. `subsample_fraction` takes a 32-bit seed, so a larger
+    `seed` has its upper 32 bits XORed into the lower ones. `per_barcode_targets` keeps the first reads reached
+    for each barcode, which only repeats exactly with `n_threads=1`.
+
+    `bam_file` may be an `https://`, `s3://` or `file://` URL, which is read in
+    a single streaming pass. S3 credentials are taken from the `AWS_*`
+    environment variables or `~/.aws/credentials`, as htslib does.
+    """
+    ...
+
+def count_matching(bam_file: str, barcodes_file: str, bam_tag: str, n_threads: int) -> int:
+    """Counts the reads whose tag is in the barcode file without writing any output."""
+    ...
+
+def count_distinct_barcodes(
+    bam_file: str,
+    bam_tag: str,
+    barcodes_file: Optional[str] = None,
+    approximate: bool = False,
+    n_threads: int = 1,
+) -> int:
+    """Counts the distinct tag values, e.g. cells, optionally only those in a barcode file."""
+    ...
+
+class MatchedRecordIterator(Iterator[Dict[str, Any]]):
+    """Iterates the reads whose tag is in the barcode file as dicts, without
+    writing a file. Each dict has qname, flag, tid, pos, mapq, barcode and seq."""
+    def __init__(
+        self,
+        bam_file: str,
+        barcodes_file: Optional[str] = None,
+        bam_tag: str = "CB",
+        barcode_mismatches: int = 0,
+        case_insensitive: bool = False,
+    ) -> None: ...
+    def __iter__(self) -> "MatchedRecordIterator": ...
+    def __next__(self) -> Dict[str, Any]: ...
+
+class BarcodeReport:
+    n_barcodes: int
+    modal_length: int
+    length_counts: Dict[int, int]
+    mean_gc: float
+    gc_histogram: List[int]
+    known_overlaps: List[Tuple[str, float]]
+    chemistry: Optional[str]
+
+def classify_barcodes(
+    barcodes_file: str,
+    known_lists: Sequence[Tuple[str, str]] = (),
+) -> BarcodeReport:
+    """Summarises a barcode file and guesses its chemistry from `(name, path)` known whitelists."""
+    ...
+
+def merge_counts(inputs: Sequence[str], output: str) -> int:
+    """Sums `barcode<TAB>count` TSVs, e.g. one per shard, returning the number of barcodes."""
+    ...
@@ -0,0 +1,159 @@
+use crate::subsample_bam::open_text_input;
+use failure::{bail, Error};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// Summary of a barcode file, to catch a whitelist from the wrong chemistry.
+#[derive(Clone, Debug)]
+pub struct BarcodeReport {
+    pub n_barcodes: usize,
+    pub modal_length: usize,
+    /// Number of barcodes of each length.
+    pub length_counts: HashMap<usize, usize>,
+    pub mean_gc: f64,
+    /// Barcodes per GC fraction decile, 0-10% first.
+    pub gc_histogram: [usize; 10],
+    /// Fraction of the barcodes found in each known list.
+    pub known_overlaps: Vec<(String, f64)>,
+    /// Known list with the largest overlap, if any barcode was found in one.
+    pub chemistry: Option<String>,
+}
+
+/// Reads one barcode per line, decompressing gzipped files such as the
+/// whitelists shipped with Cell Ranger.
+fn read_barcode_lines(path: &Path) -> Result<HashSet<Vec<u8>>, Error> {
+    let mut barcodes = HashSet::new();
+    for l in open_text_input(path)?.lines() {
+        let l = l?;
+        // keep just the barcode of barcodes.tsv lines such as AACCGGTT-1
+        let bc = l.split(|c: char| c.is_whitespace() || c == '-').next();
+        if let Some(bc) = bc.filter(|bc| !bc.is_empty()) {
+            barcodes.insert(bc.as_bytes().to_ascii_uppercase());
+        }
+    }
+    Ok(barcodes)
+}
+
+fn gc_fraction(bc: &[u8]) -> f64 {
+    let gc = bc.iter().filter(|b| **b == b'G' || **b == b'C').count();
+    gc as f64 / bc.len().max(1) as f64
+}
+
+/// Reports the length and GC distribution of `barcodes_file` and, for each
+/// `(name, path)` known whitelist, how many of its barcodes that list holds.
+///
+/// Known lists are not bundled: the 10x ones run to millions of barcodes and
+/// ship with Cell Ranger under its licence, so pass e.g.
+/// `("v3", "3M-february-2018.txt.gz")` from an install to guess the chemistry.
+pub fn classify_barcodes(
+    barcodes_file: &Path,
+    known_lists: &[(String, PathBuf)],
+) -> Result<BarcodeReport, Error> {
+    let barcodes = read_barcode_lines(barcodes_file)?;
+    if barcodes.is_empty() {
+        bail!("No barcodes found in {}", barcodes_file.display());
+    }
+
+    let mut length_counts = HashMap::new();
+    let mut gc_histogram = [0; 10];
+    let mut gc_total = 0.0;
+    for bc in &barcodes {
+        *length_counts.entry(bc.len()).or_insert(0) += 1;
+        let gc = gc_fraction(bc);
+        gc_total += gc;
+        gc_histogram[((gc * 10.0) as usize).min(9)] += 1;
+    }
+    // ties go to the shorter length, so the result does not depend on hash order
+    let modal_length = length_counts
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(len, _)| *len)
+        .unwrap();
+
+    let mut known_overlaps = Vec::new();
+    for (name, path) in known_lists {
+        let known = read_barcode_lines(path)?;
+        let n_found = barcodes.iter().filter(|bc| known.contains(*bc)).count();
+        known_overlaps.push((name.clone(), n_found as f64 / barcodes.len() as f64));
+    }
+    let chemistry = known_overlaps
+        .iter()
+        .filter(|(_, overlap)| *overlap > 0.0)
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(name, _)| name.clone());
+
+    let report = BarcodeReport {
+        n_barcodes: barcodes.len(),
+        modal_length,
+        length_counts,
+        mean_gc: gc_total / barcodes.len() as f64,
+        gc_histogram,
+        known_overlaps,
+        chemistry,
+    };
+    info!(
+        "{} barcodes, modal length {}, mean GC {:.2}{}",
+        report.n_barcodes,
+        report.modal_length,
+        report.mean_gc,
+        report
+            .chemistry
+            .as_ref()
+            .map(|c| format!(", most like {}", c))
+            .unwrap_or_default()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn barcodes(n: usize, len: usize) -> Vec<String> {
+        // distinct, GC-balanced barcodes built from the digits of i in base 4
+        (0..n)
+            .map(|i| {
+                (0..len)
+                    .map(|j| b"ACGT"[(i >> (2 * (j % 8))) & 3] as char)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn v3_length_barcodes_match_the_v3_list() {
+        let dir = tempdir().unwrap();
+        let v3 = barcodes(200, 16);
+
+        let input = dir.path().join("barcodes.tsv");
+        let lines: Vec<_> = v3[..50].iter().map(|bc| format!("{}-1\n", bc)).collect();
+        fs::write(&input, lines.concat()).unwrap();
+
+        let v3_list = dir.path().join("3M-february-2018.txt.gz");
+        let mut gz = GzEncoder::new(fs::File::create(&v3_list).unwrap(), Compression::fast());
+        gz.write_all(v3.join("\n").as_bytes()).unwrap();
+        gz.finish().unwrap();
+        let v2_list = dir.path().join("737K-august-2016.txt");
+        fs::write(&v2_list, barcodes(200, 14).join("\n")).unwrap();
+
+        let report = classify_barcodes(
+            &input,
+            &[("v2".to_string(), v2_list), ("v3".to_string(), v3_list)],
+        )
+        .unwrap();
+        assert_eq!(report.n_barcodes, 50);
+        assert_eq!(report.modal_length, 16);
+        assert_eq!(report.length_counts.get(&16), Some(&50));
+        assert_eq!(report.gc_histogram.iter().sum::<usize>(), 50);
+        assert_eq!(report.known_overlaps[0], ("v2".to_string(), 0.0));
+        assert_eq!(report.known_overlaps[1], ("v3".to_string(), 1.0));
+        assert_eq!(report.chemistry.as_deref(), Some("v3"));
+    }
+}
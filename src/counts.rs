@@ -0,0 +1,117 @@
+use crate::fastq::sidecar_writer;
+use crate::subsample_bam::is_gzip_file;
+use failure::{bail, Error};
+use flate2::read::MultiGzDecoder;
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Sums `barcode<TAB>count` TSVs, e.g. one per shard, into `output`, returning
+/// the number of distinct barcodes.
+///
+/// Inputs are read one line at a time, so only the per-barcode totals are held
+/// in memory however many files there are. Gzipped inputs and a
+/// `barcode<TAB>count` header line are accepted; the output has the header and
+/// lists barcodes by decreasing count.
+pub fn merge_counts(inputs: &[PathBuf], output: &Path) -> Result<usize, Error> {
+    let mut totals: HashMap<String, u64> = HashMap::new();
+    for input in inputs {
+        let file = fs::File::open(input)?;
+        let reader: Box<dyn BufRead> = if is_gzip_file(input)? {
+            Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        for (line_no, l) in reader.lines().enumerate() {
+            let l = l?;
+            if l.is_empty() || (line_no == 0 && l == "barcode\tcount") {
+                continue;
+            }
+            let (barcode, count) = match l.split_once('\t') {
+                Some((barcode, count)) => match count.trim().parse::<u64>() {
+                    Ok(count) => (barcode, count),
+                    Err(_) => bail!(
+                        "Line {} of {} has an invalid count",
+                        line_no + 1,
+                        input.display()
+                    ),
+                },
+                None => bail!(
+                    "Line {} of {} is not a barcode<TAB>count pair",
+                    line_no + 1,
+                    input.display()
+                ),
+            };
+            match totals.get_mut(barcode) {
+                Some(total) => *total += count,
+                None => {
+                    totals.insert(barcode.to_string(), count);
+                }
+            }
+        }
+    }
+
+    let mut totals: Vec<(String, u64)> = totals.into_iter().collect();
+    totals.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let mut out = sidecar_writer(output)?;
+    writeln!(out, "barcode\tcount")?;
+    for (barcode, count) in &totals {
+        writeln!(out, "{}\t{}", barcode, count)?;
+    }
+    out.finish()?;
+    info!(
+        "Merged {} counts files into {} barcodes",
+        inputs.len(),
+        totals.len()
+    );
+    Ok(totals.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    #[test]
+    fn gzipped_counts_output_reads_back() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("counts.tsv");
+        fs::write(&input, "AAAA\t3\nCCCC\t5\n").unwrap();
+        let gzipped = dir.path().join("merged.tsv.gz");
+        assert_eq!(merge_counts(&[input], &gzipped).unwrap(), 2);
+
+        let mut text = String::new();
+        MultiGzDecoder::new(fs::File::open(&gzipped).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "barcode\tcount\nCCCC\t5\nAAAA\t3\n");
+
+        // and a gzipped counts file is a valid input in turn
+        let plain = dir.path().join("plain.tsv");
+        merge_counts(&[gzipped], &plain).unwrap();
+        assert_eq!(fs::read_to_string(&plain).unwrap(), text);
+    }
+
+    #[test]
+    fn overlapping_counts_files_are_summed() {
+        let dir = tempdir().unwrap();
+        let first = dir.path().join("shard_0.tsv");
+        let second = dir.path().join("shard_1.tsv");
+        fs::write(&first, "barcode\tcount\nAAAA\t3\nCCCC\t1\n").unwrap();
+        fs::write(&second, "AAAA\t2\nGGGG\t4\n\n").unwrap();
+        let merged = dir.path().join("merged.tsv");
+
+        assert_eq!(merge_counts(&[first, second.clone()], &merged).unwrap(), 3);
+        assert_eq!(
+            fs::read_to_string(&merged).unwrap(),
+            "barcode\tcount\nAAAA\t5\nGGGG\t4\nCCCC\t1\n"
+        );
+
+        fs::write(&second, "AAAA\tmany\n").unwrap();
+        let err = merge_counts(&[second], &merged).unwrap_err();
+        assert!(err.to_string().contains("Line 1"), "{}", err);
+    }
+}
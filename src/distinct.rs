@@ -0,0 +1,246 @@
+use crate::subsample_bam::{
+    bgzf_noffsets, build_thread_pool, get_record_tag, load_barcodes, run_chunks, stable_hash,
+    SubsampleOptions,
+};
+use failure::{bail, Error};
+use log::info;
+use rust_htslib::bam::{self, Read};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Bits of the hash picking a HyperLogLog register: 2^14 registers (16 KiB)
+/// give a standard error of about 0.8%.
+const HLL_PRECISION: u32 = 14;
+
+/// HyperLogLog sketch of the distinct values added to it. Values are hashed
+/// with the fixed `stable_hash`, so an estimate is the same in every build.
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; 1 << HLL_PRECISION],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn add(&mut self, value: &[u8]) {
+        let hash = stable_hash(value, 0);
+        let register = (hash >> (64 - HLL_PRECISION)) as usize;
+        // the sentinel bit caps the rank when the remaining bits are all zero
+        let rest = (hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // linear counting is more accurate while many registers are empty
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+/// Distinct tag values of one chunk, exact or sketched.
+enum Distinct {
+    Exact(HashSet<Vec<u8>>),
+    Approximate(HyperLogLog),
+}
+
+impl Distinct {
+    fn insert(&mut self, tag: Vec<u8>) {
+        match self {
+            Distinct::Exact(set) => {
+                set.insert(tag);
+            }
+            Distinct::Approximate(hll) => hll.add(&tag),
+        }
+    }
+
+    fn merge(&mut self, other: Distinct) {
+        match (self, other) {
+            (Distinct::Exact(mine), Distinct::Exact(theirs)) => mine.extend(theirs),
+            (Distinct::Approximate(mine), Distinct::Approximate(theirs)) => mine.merge(&theirs),
+            _ => unreachable!("chunks use the same mode"),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Distinct::Exact(set) => set.len() as u64,
+            Distinct::Approximate(hll) => hll.estimate(),
+        }
+    }
+}
+
+fn distinct_bam_slice(
+    bam_file: &Path,
+    bam_tag: &str,
+    whitelist: Option<&HashSet<Vec<u8>>>,
+    approximate: bool,
+    virtual_start: Option<i64>,
+    virtual_stop: Option<i64>,
+) -> Result<Distinct, Error> {
+    let mut bam = bam::Reader::from_path(bam_file)?;
+    let mut distinct = if approximate {
+        Distinct::Approximate(HyperLogLog::default())
+    } else {
+        Distinct::Exact(HashSet::new())
+    };
+    for r in bam.iter_chunk(virtual_start, virtual_stop) {
+        let rec = r?;
+        if let Some(tag) = get_record_tag(&rec, bam_tag) {
+            if whitelist.map_or(true, |w| w.contains(&tag)) {
+                distinct.insert(tag);
+            }
+        }
+    }
+    Ok(distinct)
+}
+
+/// Counts the distinct values of `bam_tag`, e.g. the cells in a BAM, only
+/// counting values in `barcodes_file` when one is given.
+///
+/// The exact count holds every distinct value in memory; `approximate` uses
+/// a 16 KiB HyperLogLog sketch per chunk instead, with a standard error of
+/// about 0.8%.
+pub fn count_distinct_barcodes(
+    bam_file: &Path,
+    bam_tag: &str,
+    barcodes_file: Option<&Path>,
+    approximate: bool,
+    cores: usize,
+) -> Result<u64, Error> {
+    let whitelist = match barcodes_file {
+        Some(path) => Some(load_barcodes(path, &SubsampleOptions::default())?),
+        None => None,
+    };
+    let whitelist = whitelist.as_ref();
+    let virtual_offsets = bgzf_noffsets(&bam_file, &(cores as u64))?;
+    let pool = build_thread_pool(cores);
+    let chunks = run_chunks(
+        pool.as_ref(),
+        &virtual_offsets,
+        |(virtual_start, virtual_stop)| {
+            distinct_bam_slice(
+                bam_file,
+                bam_tag,
+                whitelist,
+                approximate,
+                *virtual_start,
+                *virtual_stop,
+            )
+        },
+    );
+
+    let mut chunks = chunks
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    let mut distinct = match chunks.next() {
+        Some(distinct) => distinct,
+        None => bail!("No chunks to read in {}", bam_file.display()),
+    };
+    for chunk in chunks {
+        distinct.merge(chunk);
+    }
+    let n_distinct = distinct.len();
+    info!(
+        "{}{} distinct {} values",
+        if approximate { "~" } else { "" },
+        n_distinct,
+        bam_tag
+    );
+    Ok(n_distinct)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+    use rust_htslib::bam::Record;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn write_tagged_bam(path: &Path, barcodes: &[Option<&str>]) {
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(
+            b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n",
+        ));
+        let mut out = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+        let cigar = CigarString(vec![Cigar::Match(4)]);
+        for (i, barcode) in barcodes.iter().enumerate() {
+            let mut rec = Record::new();
+            rec.set(
+                format!("read{}", i).as_bytes(),
+                Some(&cigar),
+                b"ACGT",
+                &[30; 4],
+            );
+            rec.set_tid(0);
+            rec.set_pos(10 * i as i64);
+            rec.set_mtid(-1);
+            rec.set_mpos(-1);
+            if let Some(barcode) = barcode {
+                rec.push_aux(b"CB", Aux::String(barcode)).unwrap();
+            }
+            out.write(&rec).unwrap();
+        }
+    }
+
+    #[test]
+    fn counts_distinct_barcodes_exactly() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_tagged_bam(
+            &input,
+            &[
+                Some("AAAA"),
+                Some("CCCC"),
+                None,
+                Some("AAAA"),
+                Some("GGGG"),
+                Some("CCCC"),
+            ],
+        );
+        let whitelist = dir.path().join("barcodes.txt");
+        fs::write(&whitelist, "AAAA\nTTTT\n").unwrap();
+
+        for cores in &[1, 2] {
+            assert_eq!(
+                count_distinct_barcodes(&input, "CB", None, false, *cores).unwrap(),
+                3
+            );
+            assert_eq!(
+                count_distinct_barcodes(&input, "CB", Some(&whitelist), false, *cores).unwrap(),
+                1
+            );
+        }
+        // linear counting is exact for a handful of values
+        assert_eq!(
+            count_distinct_barcodes(&input, "CB", None, true, 1).unwrap(),
+            3
+        );
+        assert_eq!(
+            count_distinct_barcodes(&input, "UB", None, false, 1).unwrap(),
+            0
+        );
+    }
+}
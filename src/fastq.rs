@@ -0,0 +1,175 @@
+use failure::{bail, Error};
+use flate2::write::GzEncoder;
+use flate2::{Compression, GzBuilder};
+use rust_htslib::bam::Record;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_FASTQ_COMPRESSION: u32 = 6;
+
+/// A text output, FASTQ or a sidecar, written plain or gzipped.
+pub enum MaybeGzWriter {
+    Plain(BufWriter<fs::File>),
+    Gzip(GzEncoder<BufWriter<fs::File>>),
+}
+
+pub fn is_gzip_path(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "gz")
+}
+
+/// Opens a text sidecar output (counts, stats, BED), gzipped when the path
+/// ends in `.gz`. Call `finish` so gzip errors are not lost on drop.
+pub fn sidecar_writer(path: &Path) -> Result<MaybeGzWriter, Error> {
+    MaybeGzWriter::from_path(path, is_gzip_path(path), DEFAULT_FASTQ_COMPRESSION)
+}
+
+/// Checks a gzip compression level, naming the option `name` in the error.
+pub fn check_compression_level(name: &str, level: u32) -> Result<(), Error> {
+    if level > 9 {
+        bail!("{} must be between 0 and 9, got {}", name, level);
+    }
+    Ok(())
+}
+
+impl MaybeGzWriter {
+    pub fn from_path(path: &Path, gzip: bool, level: u32) -> Result<Self, Error> {
+        let out = BufWriter::new(fs::File::create(path)?);
+        if gzip {
+            Ok(MaybeGzWriter::Gzip(
+                GzBuilder::new().write(out, Compression::new(level)),
+            ))
+        } else {
+            Ok(MaybeGzWriter::Plain(out))
+        }
+    }
+
+    /// Writes `rec` as a FASTQ entry. Secondary and supplementary alignments
+    /// are skipped, as `samtools fastq` does, so each read appears once, and
+    /// reads without base qualities get `I` (Phred 40) for every base.
+    pub fn write_record(&mut self, rec: &Record) -> io::Result<()> {
+        if rec.is_secondary() || rec.is_supplementary() {
+            return Ok(());
+        }
+        let mut seq = rec.seq().as_bytes();
+        let qual = rec.qual();
+        // a missing quality string is stored as 0xff bytes
+        let mut qual: Vec<u8> = if qual.first() == Some(&0xff) {
+            vec![b'I'; qual.len()]
+        } else {
+            qual.iter().map(|q| q.min(&93) + 33).collect()
+        };
+        // BAM stores reverse-strand reads reverse complemented
+        if rec.is_reverse() {
+            seq = revcomp(&seq);
+            qual.reverse();
+        }
+        self.write_all(b"@")?;
+        self.write_all(rec.qname())?;
+        self.write_all(b"\n")?;
+        self.write_all(&seq)?;
+        self.write_all(b"\n+\n")?;
+        self.write_all(&qual)?;
+        self.write_all(b"\n")
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            MaybeGzWriter::Plain(mut out) => out.flush(),
+            MaybeGzWriter::Gzip(out) => out.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for MaybeGzWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MaybeGzWriter::Plain(out) => out.write(buf),
+            MaybeGzWriter::Gzip(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MaybeGzWriter::Plain(out) => out.flush(),
+            MaybeGzWriter::Gzip(out) => out.flush(),
+        }
+    }
+}
+
+/// Holds primary paired reads until their mate arrives, so mates are written
+/// next to each other with read 1 first.
+///
+/// Only mates in the same chunk can be paired up; with coordinate-sorted input
+/// the cache grows with the insert size, with name-sorted input it stays tiny.
+#[derive(Default)]
+pub struct MateInterleaver {
+    pending: HashMap<Vec<u8>, Record>,
+}
+
+impl MateInterleaver {
+    pub fn write_record(&mut self, out: &mut MaybeGzWriter, rec: &Record) -> io::Result<()> {
+        // only primary reads are written, so these must not take a mate's slot
+        if rec.is_secondary() || rec.is_supplementary() {
+            return Ok(());
+        }
+        if !rec.is_paired() {
+            return out.write_record(rec);
+        }
+        match self.pending.remove(rec.qname()) {
+            Some(mate) => {
+                let (first, second) = if rec.is_first_in_template() {
+                    (rec, &mate)
+                } else {
+                    (&mate, rec)
+                };
+                out.write_record(first)?;
+                out.write_record(second)
+            }
+            None => {
+                self.pending.insert(rec.qname().to_vec(), rec.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the reads whose mate never arrived, returning how many there were.
+    pub fn finish(self, out: &mut MaybeGzWriter) -> io::Result<usize> {
+        let mut singletons: Vec<_> = self.pending.into_iter().collect();
+        singletons.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, rec) in &singletons {
+            out.write_record(rec)?;
+        }
+        Ok(singletons.len())
+    }
+}
+
+pub fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|b| match b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' => b'a',
+            other => *other,
+        })
+        .collect()
+}
+
+/// Joins per-chunk FASTQ files. Concatenated gzip members are themselves a
+/// valid gzip stream, so compressed parts are copied byte for byte.
+pub fn concat_fastq(parts: &[PathBuf], out_path: &Path) -> Result<(), Error> {
+    let mut out = BufWriter::new(fs::File::create(out_path)?);
+    for part in parts {
+        let mut part_file = fs::File::open(part)?;
+        io::copy(&mut part_file, &mut out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use failure::Error;
+use log::info;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{self, Read, Record};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const MATE_UNMAPPED: u16 = 0x8;
+const MATE_REVERSE: u16 = 0x20;
+const PROPER_PAIR: u16 = 0x2;
+const READ1_READ2: u16 = 0xc0;
+
+/// What a read's mate fields say about its mate.
+struct MateInfo {
+    tid: i32,
+    pos: i64,
+    end: i64,
+    reverse: bool,
+    unmapped: bool,
+    mapq: u8,
+    cigar: String,
+}
+
+fn is_primary_pair(rec: &Record) -> bool {
+    rec.is_paired() && !rec.is_secondary() && !rec.is_supplementary()
+}
+
+fn set_flag(rec: &mut Record, flag: u16, on: bool) {
+    let flags = if on {
+        rec.flags() | flag
+    } else {
+        rec.flags() & !flag
+    };
+    rec.set_flags(flags);
+}
+
+/// Insert size as `samtools fixmate` computes it: from the leftmost start to
+/// the rightmost end of the pair, positive for the leftmost read, and zero
+/// unless both reads are mapped to the same contig.
+fn template_length(rec: &Record, mate: &MateInfo) -> i64 {
+    if rec.is_unmapped() || mate.unmapped || rec.tid() != mate.tid {
+        return 0;
+    }
+    let start = rec.pos().min(mate.pos);
+    let end = rec.cigar().end_pos().max(mate.end);
+    let leftmost = rec.pos() < mate.pos || (rec.pos() == mate.pos && rec.is_first_in_template());
+    if leftmost {
+        end - start
+    } else {
+        start - end
+    }
+}
+
+fn remove_tag(rec: &mut Record, tag: &[u8]) -> Result<(), Error> {
+    if rec.aux(tag).is_ok() {
+        rec.remove_aux(tag)?;
+    }
+    Ok(())
+}
+
+/// Rewrites the mate fields of paired reads in `bam_path` from the mates that
+/// are still in it, as `samtools fixmate` does, returning the number of reads
+/// whose mate is gone.
+///
+/// A read with its mate keeps `MC`, `MQ`, the mate position, the insert size
+/// and the mate unmapped and reverse flags in step with that mate. A read whose mate was
+/// filtered out is marked as having an unmapped mate and loses its proper-pair
+/// flag, mate position, insert size, `MC` and `MQ`. The first pass holds the
+/// mate fields of every primary paired read in memory.
+pub fn fix_mates(bam_path: &Path, tmp_dir: &Path) -> Result<u64, Error> {
+    let mut mates: HashMap<(Vec<u8>, u16), MateInfo> = HashMap::new();
+    let mut bam = bam::Reader::from_path(bam_path)?;
+    for r in bam.records() {
+        let rec = r?;
+        if !is_primary_pair(&rec) {
+            continue;
+        }
+        mates.insert(
+            (rec.qname().to_vec(), rec.flags() & READ1_READ2),
+            MateInfo {
+                tid: rec.tid(),
+                pos: rec.pos(),
+                end: rec.cigar().end_pos(),
+                reverse: rec.is_reverse(),
+                unmapped: rec.is_unmapped(),
+                mapq: rec.mapq(),
+                cigar: rec.cigar().to_string(),
+            },
+        );
+    }
+
+    let mut n_orphans = 0;
+    let tmp_path = tmp_dir.join("fixmate.bam");
+    {
+        let mut bam = bam::Reader::from_path(bam_path)?;
+        let header = bam::Header::from_template(bam.header());
+        let mut out = bam::Writer::from_path(&tmp_path, &header, bam::Format::Bam)?;
+        for r in bam.records() {
+            let mut rec = r?;
+            if is_primary_pair(&rec) {
+                // the mate of read 1 is read 2 and the other way round
+                let key = (
+                    rec.qname().to_vec(),
+                    (rec.flags() & READ1_READ2) ^ READ1_READ2,
+                );
+                remove_tag(&mut rec, b"MC")?;
+                remove_tag(&mut rec, b"MQ")?;
+                match mates.get(&key) {
+                    Some(mate) => {
+                        rec.set_mtid(mate.tid);
+                        rec.set_mpos(mate.pos);
+                        rec.set_insert_size(template_length(&rec, mate));
+                        set_flag(&mut rec, MATE_REVERSE, mate.reverse);
+                        set_flag(&mut rec, MATE_UNMAPPED, mate.unmapped);
+                        if !mate.unmapped {
+                            rec.push_aux(b"MC", Aux::String(&mate.cigar))?;
+                            rec.push_aux(b"MQ", Aux::U8(mate.mapq))?;
+                        }
+                    }
+                    None => {
+                        n_orphans += 1;
+                        rec.set_mtid(-1);
+                        rec.set_mpos(-1);
+                        rec.set_insert_size(0);
+                        set_flag(&mut rec, MATE_UNMAPPED, true);
+                        set_flag(&mut rec, MATE_REVERSE | PROPER_PAIR, false);
+                    }
+                }
+            }
+            out.write(&rec)?;
+        }
+    }
+    fs::rename(&tmp_path, bam_path).or_else(|_| fs::copy(&tmp_path, bam_path).map(|_| ()))?;
+    info!(
+        "Fixed mate fields of {} paired reads, {} of them without their mate",
+        mates.len(),
+        n_orphans
+    );
+    Ok(n_orphans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    use tempfile::tempdir;
+
+    const TEST_HEADER: &[u8] = b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n";
+
+    /// A paired read with stale mate fields, as left behind by filtering.
+    fn paired_read(name: &str, pos: i64, flags: u16, mapq: u8) -> Record {
+        let mut rec = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(4)]);
+        rec.set(name.as_bytes(), Some(&cigar), b"ACGT", &[30; 4]);
+        rec.set_tid(0);
+        rec.set_pos(pos);
+        rec.set_mapq(mapq);
+        rec.set_flags(0x1 | PROPER_PAIR | flags);
+        rec.set_mtid(0);
+        rec.set_mpos(999);
+        rec.set_insert_size(7);
+        rec.push_aux(b"MC", Aux::String("10M")).unwrap();
+        rec.push_aux(b"MQ", Aux::U8(0)).unwrap();
+        rec
+    }
+
+    #[test]
+    fn fix_mates_updates_kept_pairs_and_orphans() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pairs.bam");
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(TEST_HEADER));
+        {
+            let mut out = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+            out.write(&paired_read("pair", 100, 0x40, 30)).unwrap();
+            out.write(&paired_read("orphan", 150, 0x40, 30)).unwrap();
+            // read 2 of the pair, on the reverse strand
+            out.write(&paired_read("pair", 200, 0x80 | 0x10, 50))
+                .unwrap();
+        }
+
+        let n_orphans = fix_mates(&path, dir.path()).unwrap();
+        assert_eq!(n_orphans, 1);
+        let mut bam = bam::Reader::from_path(&path).unwrap();
+        let recs: Vec<Record> = bam.records().map(|r| r.unwrap()).collect();
+
+        let (read1, orphan, read2) = (&recs[0], &recs[1], &recs[2]);
+        assert_eq!(read1.mpos(), 200);
+        assert_eq!(read1.insert_size(), 104);
+        assert!(read1.is_mate_reverse());
+        assert_eq!(read1.aux(b"MC").unwrap(), Aux::String("4M"));
+        assert_eq!(read1.aux(b"MQ").unwrap(), Aux::U8(50));
+        assert_eq!(read2.mpos(), 100);
+        assert_eq!(read2.insert_size(), -104);
+        assert!(!read2.is_mate_reverse());
+        assert_eq!(read2.aux(b"MQ").unwrap(), Aux::U8(30));
+
+        assert!(orphan.is_mate_unmapped());
+        assert!(!orphan.is_proper_pair());
+        assert_eq!(
+            (orphan.mtid(), orphan.mpos(), orphan.insert_size()),
+            (-1, -1, 0)
+        );
+        assert!(orphan.aux(b"MC").is_err());
+        assert!(orphan.aux(b"MQ").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::fastq::sidecar_writer;
+use failure::Error;
+use rust_htslib::bam::Record;
+use std::io::Write;
+use std::path::Path;
+
+const TOTAL: usize = 0;
+const PRIMARY: usize = 1;
+const SECONDARY: usize = 2;
+const SUPPLEMENTARY: usize = 3;
+const DUPLICATES: usize = 4;
+const PRIMARY_DUPLICATES: usize = 5;
+const MAPPED: usize = 6;
+const PRIMARY_MAPPED: usize = 7;
+const PAIRED: usize = 8;
+const READ1: usize = 9;
+const READ2: usize = 10;
+const PROPERLY_PAIRED: usize = 11;
+const BOTH_MAPPED: usize = 12;
+const SINGLETONS: usize = 13;
+const MATE_OTHER_CHR: usize = 14;
+const MATE_OTHER_CHR_MAPQ5: usize = 15;
+
+/// `samtools flagstat` counters, each split into QC-passed and QC-failed reads.
+#[derive(Clone, Debug, Default)]
+pub struct Flagstat {
+    counts: [[u64; 2]; 16],
+}
+
+fn percent(n: u64, total: u64) -> String {
+    if total == 0 {
+        "N/A".to_string()
+    } else {
+        format!("{:.2}%", n as f64 / total as f64 * 100.0)
+    }
+}
+
+impl Flagstat {
+    /// Counts a record the way `samtools flagstat` (1.13 and later) does.
+    pub fn add_record(&mut self, rec: &Record) {
+        let w = rec.is_quality_check_failed() as usize;
+        let mut count = |counter: usize| self.counts[counter][w] += 1;
+        count(TOTAL);
+        if rec.is_secondary() {
+            count(SECONDARY);
+        } else if rec.is_supplementary() {
+            count(SUPPLEMENTARY);
+        } else {
+            count(PRIMARY);
+            if rec.is_paired() {
+                count(PAIRED);
+                if rec.is_proper_pair() && !rec.is_unmapped() {
+                    count(PROPERLY_PAIRED);
+                }
+                if rec.is_first_in_template() {
+                    count(READ1);
+                }
+                if rec.is_last_in_template() {
+                    count(READ2);
+                }
+                if rec.is_mate_unmapped() && !rec.is_unmapped() {
+                    count(SINGLETONS);
+                }
+                if !rec.is_unmapped() && !rec.is_mate_unmapped() {
+                    count(BOTH_MAPPED);
+                    if rec.mtid() != rec.tid() {
+                        count(MATE_OTHER_CHR);
+                        if rec.mapq() >= 5 {
+                            count(MATE_OTHER_CHR_MAPQ5);
+                        }
+                    }
+                }
+            }
+            if !rec.is_unmapped() {
+                count(PRIMARY_MAPPED);
+            }
+            if rec.is_duplicate() {
+                count(PRIMARY_DUPLICATES);
+            }
+        }
+        if !rec.is_unmapped() {
+            count(MAPPED);
+        }
+        if rec.is_duplicate() {
+            count(DUPLICATES);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Flagstat) {
+        for (mine, theirs) in self.counts.iter_mut().zip(other.counts.iter()) {
+            mine[0] += theirs[0];
+            mine[1] += theirs[1];
+        }
+    }
+
+    /// Writes the counts in the default `samtools flagstat` text format.
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut out = sidecar_writer(path)?;
+        let c = &self.counts;
+        let plain = |counter: usize, label: &str| {
+            format!("{} + {} {}", c[counter][0], c[counter][1], label)
+        };
+        let with_percent = |counter: usize, label: &str, of: usize| {
+            format!(
+                "{} ({} : {})",
+                plain(counter, label),
+                percent(c[counter][0], c[of][0]),
+                percent(c[counter][1], c[of][1])
+            )
+        };
+        let lines = [
+            plain(TOTAL, "in total (QC-passed reads + QC-failed reads)"),
+            plain(PRIMARY, "primary"),
+            plain(SECONDARY, "secondary"),
+            plain(SUPPLEMENTARY, "supplementary"),
+            plain(DUPLICATES, "duplicates"),
+            plain(PRIMARY_DUPLICATES, "primary duplicates"),
+            with_percent(MAPPED, "mapped", TOTAL),
+            with_percent(PRIMARY_MAPPED, "primary mapped", PRIMARY),
+            plain(PAIRED, "paired in sequencing"),
+            plain(READ1, "read1"),
+            plain(READ2, "read2"),
+            with_percent(PROPERLY_PAIRED, "properly paired", PAIRED),
+            plain(BOTH_MAPPED, "with itself and mate mapped"),
+            with_percent(SINGLETONS, "singletons", PAIRED),
+            plain(MATE_OTHER_CHR, "with mate mapped to a different chr"),
+            plain(
+                MATE_OTHER_CHR_MAPQ5,
+                "with mate mapped to a different chr (mapQ>=5)",
+            ),
+        ];
+        for line in lines.iter() {
+            writeln!(out, "{}", line)?;
+        }
+        out.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn read(flags: u16, tid: i32, mtid: i32, mapq: u8) -> Record {
+        let mut rec = Record::new();
+        rec.set(b"read", None, b"ACGT", &[30; 4]);
+        rec.set_flags(flags);
+        rec.set_tid(tid);
+        rec.set_pos(100);
+        rec.set_mtid(mtid);
+        rec.set_mpos(200);
+        rec.set_mapq(mapq);
+        rec
+    }
+
+    #[test]
+    fn counts_match_samtools_flagstat() {
+        let records = [
+            // a proper pair, the secondary of its read 1 and a supplementary
+            read(0x43, 0, 0, 60),
+            read(0x93, 0, 0, 60),
+            read(0x143, 0, 0, 0),
+            read(0x800, 0, -1, 60),
+            // a singleton and its unmapped mate
+            read(0x49, 0, 0, 60),
+            read(0x85, 0, 0, 0),
+            // mates on different contigs, one below mapQ 5
+            read(0x41, 0, 1, 3),
+            read(0x81, 1, 0, 30),
+            // a QC-failed duplicate
+            read(0x600, 0, -1, 60),
+        ];
+        // per-chunk counters merged at the end give the same totals
+        let mut first = Flagstat::default();
+        let mut second = Flagstat::default();
+        for (i, rec) in records.iter().enumerate() {
+            if i < 4 {
+                first.add_record(rec);
+            } else {
+                second.add_record(rec);
+            }
+        }
+        first.merge(&second);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("out.flagstat");
+        first.write(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "8 + 1 in total (QC-passed reads + QC-failed reads)\n\
+             6 + 1 primary\n\
+             1 + 0 secondary\n\
+             1 + 0 supplementary\n\
+             0 + 1 duplicates\n\
+             0 + 1 primary duplicates\n\
+             7 + 1 mapped (87.50% : 100.00%)\n\
+             5 + 1 primary mapped (83.33% : 100.00%)\n\
+             6 + 0 paired in sequencing\n\
+             3 + 0 read1\n\
+             3 + 0 read2\n\
+             2 + 0 properly paired (33.33% : N/A)\n\
+             4 + 0 with itself and mate mapped\n\
+             1 + 0 singletons (16.67% : N/A)\n\
+             2 + 0 with mate mapped to a different chr\n\
+             1 + 0 with mate mapped to a different chr (mapQ>=5)\n"
+        );
+    }
+}
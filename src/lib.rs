@@ -1,38 +1,619 @@
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
+use rust_htslib::tpool;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+pub mod classify;
+pub mod counts;
+pub mod distinct;
+pub mod fastq;
+pub mod fixmate;
+pub mod flagstat;
+pub mod matcher;
+pub mod molecules;
+pub mod regions;
+pub mod saturation;
+pub mod sort;
 pub mod subsample_bam;
+pub mod unmatched;
+pub mod writer_cache;
 
-/// Formats the sum of two numbers as string.
+/// Summary of a subsampling run.
+#[pyclass]
+#[derive(Clone)]
+pub struct SubsampleResult {
+    #[pyo3(get)]
+    output_path: String,
+    #[pyo3(get)]
+    reads_scanned: u64,
+    #[pyo3(get)]
+    reads_written: u64,
+    #[pyo3(get)]
+    elapsed_seconds: f64,
+    #[pyo3(get)]
+    chunks: usize,
+    /// `(start_seconds, end_seconds, reads_scanned, reads_written)` per chunk.
+    #[pyo3(get)]
+    chunk_timings: Vec<(f64, f64, u64, u64)>,
+    #[pyo3(get)]
+    barcode_counts: Option<HashMap<String, u64>>,
+    #[pyo3(get)]
+    split_counts: Option<HashMap<String, u64>>,
+    #[pyo3(get)]
+    molecules: Option<u64>,
+    #[pyo3(get)]
+    skipped_records: u64,
+    #[pyo3(get)]
+    shard_counts: Option<Vec<u64>>,
+}
+
+#[pymethods]
+impl SubsampleResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "SubsampleResult(output_path='{}', reads_scanned={}, reads_written={}, elapsed_seconds={:.2}, chunks={})",
+            self.output_path, self.reads_scanned, self.reads_written, self.elapsed_seconds, self.chunks
+        )
+    }
+}
+
+fn string_keys(counts: HashMap<Vec<u8>, u64>) -> HashMap<String, u64> {
+    counts
+        .into_iter()
+        .map(|(key, n)| (String::from_utf8_lossy(&key).into_owned(), n))
+        .collect()
+}
+
+impl From<subsample_bam::SubsampleStats> for SubsampleResult {
+    fn from(stats: subsample_bam::SubsampleStats) -> Self {
+        SubsampleResult {
+            output_path: stats.output_path.as_path().display().to_string(),
+            reads_scanned: stats.reads_scanned,
+            reads_written: stats.reads_written,
+            elapsed_seconds: stats.elapsed_seconds,
+            chunks: stats.chunks,
+            chunk_timings: stats
+                .chunk_timings
+                .iter()
+                .map(|t| (t.start_seconds, t.end_seconds, t.reads_scanned, t.reads_written))
+                .collect(),
+            barcode_counts: stats.barcode_counts.map(string_keys),
+            split_counts: stats.split_counts.map(string_keys),
+            molecules: stats.molecules,
+            skipped_records: stats.skipped_records,
+            shard_counts: stats.shard_counts,
+        }
+    }
+}
+
+/// An htslib thread pool that can be shared by consecutive single-threaded calls.
+///
+/// The pool is tied to the thread that created it, as htslib pools are not
+/// `Send`; it is freed once the last call holding it has returned.
+#[pyclass(unsendable)]
+pub struct ThreadPoolHandle {
+    pool: tpool::ThreadPool,
+    #[pyo3(get)]
+    n_threads: u32,
+}
+
+#[pymethods]
+impl ThreadPoolHandle {
+    #[new]
+    fn new(n_threads: u32) -> PyResult<Self> {
+        let pool = tpool::ThreadPool::new(n_threads)
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+        Ok(ThreadPoolHandle { pool, n_threads })
+    }
+
+    fn __repr__(&self) -> String {
+        format!("ThreadPoolHandle(n_threads={})", self.n_threads)
+    }
+}
+
+/// Fields of a matched read handed to Python.
+struct MatchedRead {
+    qname: String,
+    flag: u16,
+    tid: i32,
+    pos: i64,
+    mapq: u8,
+    barcode: Option<String>,
+    seq: String,
+}
+
+/// Records read per GIL release; each `__next__` is then a buffer pop.
+const MATCHED_READ_BATCH: usize = 1024;
+
+fn fill_matched_reads(
+    records: &mut subsample_bam::FilteredRecords,
+    buffer: &mut VecDeque<MatchedRead>,
+) -> Result<(), failure::Error> {
+    for r in records.take(MATCHED_READ_BATCH) {
+        let (rec, tag) = r?;
+        buffer.push_back(MatchedRead {
+            qname: String::from_utf8_lossy(rec.qname()).into_owned(),
+            flag: rec.flags(),
+            tid: rec.tid(),
+            pos: rec.pos(),
+            mapq: rec.mapq(),
+            barcode: tag.map(|t| String::from_utf8_lossy(&t).into_owned()),
+            seq: String::from_utf8_lossy(&rec.seq().as_bytes()).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Iterates the reads whose tag is in the barcode file as dicts, without
+/// writing a file. Reads are fetched in batches with the GIL released.
+#[pyclass(unsendable)]
+pub struct MatchedRecordIterator {
+    records: subsample_bam::FilteredRecords,
+    buffer: VecDeque<MatchedRead>,
+}
+
+#[pymethods]
+impl MatchedRecordIterator {
+    #[new]
+    #[pyo3(signature = (
+        bam_file, barcodes_file = None, bam_tag = "CB".to_string(),
+        barcode_mismatches = 0, case_insensitive = false
+    ))]
+    fn new(
+        bam_file: String,
+        barcodes_file: Option<String>,
+        bam_tag: String,
+        barcode_mismatches: usize,
+        case_insensitive: bool,
+    ) -> PyResult<Self> {
+        let opts = subsample_bam::SubsampleOptions {
+            barcode_mismatches,
+            case_insensitive,
+            ..Default::default()
+        };
+        let records = subsample_bam::FilteredRecords::new(
+            Path::new(&bam_file),
+            barcodes_file.as_deref().map(Path::new),
+            &bam_tag,
+            opts,
+        )
+        .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+        Ok(MatchedRecordIterator {
+            records,
+            buffer: VecDeque::new(),
+        })
+    }
+
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(
+        mut slf: PyRefMut<Self>,
+        py: Python,
+    ) -> PyResult<Option<HashMap<&'static str, PyObject>>> {
+        if slf.buffer.is_empty() {
+            let this = &mut *slf;
+            let (records, buffer) = (&mut this.records, &mut this.buffer);
+            py.allow_threads(|| fill_matched_reads(records, buffer))
+                .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+        }
+        Ok(slf.buffer.pop_front().map(|read| {
+            let mut fields = HashMap::new();
+            fields.insert("qname", read.qname.into_py(py));
+            fields.insert("flag", read.flag.into_py(py));
+            fields.insert("tid", read.tid.into_py(py));
+            fields.insert("pos", read.pos.into_py(py));
+            fields.insert("mapq", read.mapq.into_py(py));
+            fields.insert("barcode", read.barcode.into_py(py));
+            fields.insert("seq", read.seq.into_py(py));
+            fields
+        }))
+    }
+}
+
+#[derive(FromPyObject)]
+enum BamInputs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl BamInputs {
+    fn into_paths(self) -> Vec<String> {
+        match self {
+            BamInputs::One(path) => vec![path],
+            BamInputs::Many(paths) => paths,
+        }
+    }
+}
+
+/// Subsamples one or more BAM files to the reads whose tag is in the barcode file.
+///
+/// Every argument after `outfile` is keyword-only.
 #[pyfunction]
 #[pyo3(name = "subsample_bam")]
+#[pyo3(signature = (
+    bam_file, bam_tag, outfile, *, barcodes_file = None, to_replace = None,
+    replacement = None, n_threads = 1,
+    min_tlen = None, max_tlen = None, tlen_keep_unpaired = false, header_template = None,
+    trim_softclips = false, barcode_mismatches = 0, barcode_suffix_delimiter = None,
+    validate_output = false, barcode_column = None, min_aligned_length = None,
+    idxstats_output = None, fail_fast = false, split_output_dir = None, max_open_files = 0,
+    strand = None, strand_from_read1 = false, decompress_threads = 0, rewrite_only = false,
+    fastq_output = None, fastq_compression = None, resume_dir = None, mask_low_qual = None,
+    case_insensitive = false, multiqc_output = None, bed_file = None,
+    per_barcode_targets = None, remove_duplicates = false, sort_by_tag = false,
+    saturation_output = None, umi_tag = None, split_by_chrom = false, set_sort_order = None,
+    positions_file = None, replacement_template = None, prune_header = false,
+    strict_barcode_lengths = false, head = None, progress_interval = None,
+    keep_tags = None, strip_tags = None, expected_input_md5 = None,
+    tag_field = None, chunks_per_core = 1,
+    barcode_has_header = false, collapse_molecules = None,
+    assert_sorted = false, translation_map = None,
+    mate = None, mate_keep_unpaired = false, max_write_errors = None,
+    sample_n_barcodes = None, seed = None, annotate_source_tag = None,
+    coverage_bed = None, create_output_dirs = false,
+    temp_prefix = None, regex_routes = Vec::new(), regex_default_output = None,
+    subsample_fraction = None, subsample_seed = None, max_memory_mb = None,
+    interleave = false, untagged_only = false, num_shards = None,
+    denylist = false, strip_alignment = false, validate_dna = false,
+    reorder_to = None, min_gc = None, max_gc = None, flagstat_output = None,
+    ordered_writer = false, tag_chunk = None, indel_only = false,
+    min_indel_length = None, max_output_bytes = None, htslib_options = None,
+    uppercase_tag = false, strict_tags = false, min_barcodes = None,
+    unmatched_histogram = None, sort_by_name = false, extra_tag_whitelists = None,
+    match_any_tag = false, mapped_only = false, discordant_only = false,
+    discordant_min_insert = None, fixmate = false, thread_pool = None
+))]
 fn subsample_bam_py(
-    bam_file: String,
-    barcodes_file: String,
+    bam_file: BamInputs,
     bam_tag: String,
+    outfile: String,
+    barcodes_file: Option<String>,
     to_replace: Option<String>,
     replacement: Option<String>,
-    outfile: String,
     n_threads: usize,
-)  -> PyResult<String> 
-{   
+    min_tlen: Option<i64>,
+    max_tlen: Option<i64>,
+    tlen_keep_unpaired: bool,
+    header_template: Option<String>,
+    trim_softclips: bool,
+    barcode_mismatches: usize,
+    barcode_suffix_delimiter: Option<String>,
+    validate_output: bool,
+    barcode_column: Option<usize>,
+    min_aligned_length: Option<usize>,
+    idxstats_output: Option<String>,
+    fail_fast: bool,
+    split_output_dir: Option<String>,
+    max_open_files: usize,
+    strand: Option<String>,
+    strand_from_read1: bool,
+    decompress_threads: usize,
+    rewrite_only: bool,
+    fastq_output: Option<String>,
+    fastq_compression: Option<u32>,
+    resume_dir: Option<String>,
+    mask_low_qual: Option<u8>,
+    case_insensitive: bool,
+    multiqc_output: Option<String>,
+    bed_file: Option<String>,
+    per_barcode_targets: Option<String>,
+    remove_duplicates: bool,
+    sort_by_tag: bool,
+    saturation_output: Option<String>,
+    umi_tag: Option<String>,
+    split_by_chrom: bool,
+    set_sort_order: Option<String>,
+    positions_file: Option<String>,
+    replacement_template: Option<String>,
+    prune_header: bool,
+    strict_barcode_lengths: bool,
+    head: Option<u64>,
+    progress_interval: Option<u64>,
+    keep_tags: Option<Vec<String>>,
+    strip_tags: Option<Vec<String>>,
+    expected_input_md5: Option<String>,
+    tag_field: Option<(char, usize)>,
+    chunks_per_core: usize,
+    barcode_has_header: bool,
+    collapse_molecules: Option<(String, String)>,
+    assert_sorted: bool,
+    translation_map: Option<String>,
+    mate: Option<String>,
+    mate_keep_unpaired: bool,
+    max_write_errors: Option<u64>,
+    sample_n_barcodes: Option<usize>,
+    seed: Option<u64>,
+    annotate_source_tag: Option<String>,
+    coverage_bed: Option<String>,
+    create_output_dirs: bool,
+    temp_prefix: Option<String>,
+    regex_routes: Vec<(String, String)>,
+    regex_default_output: Option<String>,
+    subsample_fraction: Option<f64>,
+    subsample_seed: Option<u32>,
+    max_memory_mb: Option<usize>,
+    interleave: bool,
+    untagged_only: bool,
+    num_shards: Option<usize>,
+    denylist: bool,
+    strip_alignment: bool,
+    validate_dna: bool,
+    reorder_to: Option<String>,
+    min_gc: Option<f64>,
+    max_gc: Option<f64>,
+    flagstat_output: Option<String>,
+    ordered_writer: bool,
+    tag_chunk: Option<String>,
+    indel_only: bool,
+    min_indel_length: Option<u32>,
+    max_output_bytes: Option<u64>,
+    htslib_options: Option<Vec<(String, String)>>,
+    uppercase_tag: bool,
+    strict_tags: bool,
+    min_barcodes: Option<usize>,
+    unmatched_histogram: Option<String>,
+    sort_by_name: bool,
+    extra_tag_whitelists: Option<Vec<(String, String)>>,
+    match_any_tag: bool,
+    mapped_only: bool,
+    discordant_only: bool,
+    discordant_min_insert: Option<i64>,
+    fixmate: bool,
+    thread_pool: Option<PyRef<ThreadPoolHandle>>,
+)  -> PyResult<SubsampleResult>
+{
+    let strand = strand
+        .map(|s| s.parse::<subsample_bam::Strand>())
+        .transpose()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let mate = mate
+        .map(|m| m.parse::<subsample_bam::Mate>())
+        .transpose()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+
+    let opts = subsample_bam::SubsampleOptions {
+        min_tlen,
+        max_tlen,
+        tlen_keep_unpaired,
+        header_template: header_template.map(PathBuf::from),
+        trim_softclips,
+        barcode_mismatches,
+        barcode_suffix_delimiter: barcode_suffix_delimiter.and_then(|d| d.bytes().next()),
+        validate_output,
+        barcode_column,
+        min_aligned_length,
+        idxstats_output: idxstats_output.map(PathBuf::from),
+        fail_fast,
+        split_output_dir: split_output_dir.map(PathBuf::from),
+        max_open_files,
+        strand,
+        strand_from_read1,
+        decompress_threads,
+        rewrite_only,
+        fastq_output: fastq_output.map(PathBuf::from),
+        fastq_compression,
+        resume_dir: resume_dir.map(PathBuf::from),
+        mask_low_qual,
+        case_insensitive,
+        multiqc_output: multiqc_output.map(PathBuf::from),
+        bed_file: bed_file.map(PathBuf::from),
+        per_barcode_targets: per_barcode_targets.map(PathBuf::from),
+        remove_duplicates,
+        sort_by_tag,
+        saturation_output: saturation_output.map(PathBuf::from),
+        umi_tag,
+        seed: seed.unwrap_or(0),
+        split_by_chrom,
+        set_sort_order,
+        positions_file: positions_file.map(PathBuf::from),
+        replacement_template,
+        prune_header,
+        strict_barcode_lengths,
+        head,
+        progress_interval,
+        keep_tags,
+        strip_tags,
+        expected_input_md5,
+        tag_field,
+        chunks_per_core,
+        barcode_has_header,
+        collapse_molecules,
+        assert_sorted,
+        translation_map: translation_map.map(PathBuf::from),
+        mate,
+        mate_keep_unpaired,
+        max_write_errors,
+        sample_n_barcodes,
+        annotate_source_tag,
+        coverage_bed: coverage_bed.map(PathBuf::from),
+        create_output_dirs,
+        temp_prefix,
+        regex_routes: regex_routes
+            .into_iter()
+            .map(|(pattern, path)| (pattern, PathBuf::from(path)))
+            .collect(),
+        regex_default_output: regex_default_output.map(PathBuf::from),
+        subsample_fraction,
+        // one seed covers every sampling decision unless the hash seed is given
+        // too; the high bits are folded in so seeds differing only there still
+        // differ, while seeds that fit in 32 bits are used as they are
+        subsample_seed: subsample_seed.unwrap_or_else(|| {
+            let seed = seed.unwrap_or(0);
+            (seed ^ (seed >> 32)) as u32
+        }),
+        max_memory_mb,
+        interleave,
+        untagged_only,
+        num_shards,
+        denylist,
+        strip_alignment,
+        validate_dna,
+        reorder_to: reorder_to.map(PathBuf::from),
+        min_gc,
+        max_gc,
+        flagstat_output: flagstat_output.map(PathBuf::from),
+        ordered_writer,
+        tag_chunk,
+        indel_only,
+        min_indel_length,
+        max_output_bytes,
+        htslib_options,
+        uppercase_tag,
+        strict_tags,
+        min_barcodes,
+        unmatched_histogram: unmatched_histogram.map(PathBuf::from),
+        sort_by_name,
+        extra_tag_whitelists: extra_tag_whitelists.map(|pairs| {
+            pairs
+                .into_iter()
+                .map(|(tag, path)| (tag, PathBuf::from(path)))
+                .collect()
+        }),
+        match_any_tag,
+        mapped_only,
+        discordant_only,
+        discordant_min_insert,
+        fixmate,
+    };
 
     ctrlc::set_handler(|| std::process::exit(2)).unwrap_or_default();
-    let out = subsample_bam::subsample_bam(
-        bam_file,
+    let out = subsample_bam::subsample_bam_with_pool(
+        &bam_file.into_paths(),
         barcodes_file,
         bam_tag,
         to_replace,
         replacement,
         outfile,
         n_threads,
+        &opts,
+        thread_pool.as_ref().map(|handle| &handle.pool),
     );
 
-    Ok(out.unwrap().as_path().display().to_string())
+    let out = out.map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+    Ok(out.into())
+
+}
+
+/// Counts the reads whose tag is in the barcode file without writing any output.
+#[pyfunction]
+#[pyo3(name = "count_matching")]
+fn count_matching_py(
+    bam_file: String,
+    barcodes_file: String,
+    bam_tag: String,
+    n_threads: usize,
+) -> PyResult<u64> {
+    ctrlc::set_handler(|| std::process::exit(2)).unwrap_or_default();
+    let out = subsample_bam::count_matching(bam_file, barcodes_file, bam_tag, n_threads);
+
+    out.map_err(|e| PyRuntimeError::new_err(e.to_string()))
+}
+
+/// Counts the distinct tag values, e.g. cells, optionally only those in a barcode file.
+#[pyfunction]
+#[pyo3(name = "count_distinct_barcodes")]
+#[pyo3(signature = (bam_file, bam_tag, barcodes_file = None, approximate = false, n_threads = 1))]
+fn count_distinct_barcodes_py(
+    bam_file: String,
+    bam_tag: String,
+    barcodes_file: Option<String>,
+    approximate: bool,
+    n_threads: usize,
+) -> PyResult<u64> {
+    ctrlc::set_handler(|| std::process::exit(2)).unwrap_or_default();
+    distinct::count_distinct_barcodes(
+        bam_file.as_ref(),
+        &bam_tag,
+        barcodes_file.as_deref().map(std::path::Path::new),
+        approximate,
+        n_threads,
+    )
+    .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+}
+
+/// Length and GC summary of a barcode file, with its overlap with known whitelists.
+#[pyclass]
+#[derive(Clone)]
+pub struct BarcodeReport {
+    #[pyo3(get)]
+    n_barcodes: usize,
+    #[pyo3(get)]
+    modal_length: usize,
+    #[pyo3(get)]
+    length_counts: HashMap<usize, usize>,
+    #[pyo3(get)]
+    mean_gc: f64,
+    #[pyo3(get)]
+    gc_histogram: Vec<usize>,
+    #[pyo3(get)]
+    known_overlaps: Vec<(String, f64)>,
+    #[pyo3(get)]
+    chemistry: Option<String>,
+}
+
+#[pymethods]
+impl BarcodeReport {
+    fn __repr__(&self) -> String {
+        format!(
+            "BarcodeReport(n_barcodes={}, modal_length={}, mean_gc={:.2}, chemistry={:?})",
+            self.n_barcodes, self.modal_length, self.mean_gc, self.chemistry
+        )
+    }
+}
+
+impl From<classify::BarcodeReport> for BarcodeReport {
+    fn from(report: classify::BarcodeReport) -> Self {
+        BarcodeReport {
+            n_barcodes: report.n_barcodes,
+            modal_length: report.modal_length,
+            length_counts: report.length_counts,
+            mean_gc: report.mean_gc,
+            gc_histogram: report.gc_histogram.to_vec(),
+            known_overlaps: report.known_overlaps,
+            chemistry: report.chemistry,
+        }
+    }
+}
+
+/// Summarises a barcode file and guesses its chemistry from `(name, path)` known whitelists.
+#[pyfunction]
+#[pyo3(name = "classify_barcodes")]
+#[pyo3(signature = (barcodes_file, known_lists = Vec::new()))]
+fn classify_barcodes_py(
+    barcodes_file: String,
+    known_lists: Vec<(String, String)>,
+) -> PyResult<BarcodeReport> {
+    let known_lists: Vec<_> = known_lists
+        .into_iter()
+        .map(|(name, path)| (name, PathBuf::from(path)))
+        .collect();
+    classify::classify_barcodes(barcodes_file.as_ref(), &known_lists)
+        .map(BarcodeReport::from)
+        .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+}
 
+/// Sums `barcode<TAB>count` TSVs into `output`, returning the number of barcodes.
+#[pyfunction]
+#[pyo3(name = "merge_counts")]
+fn merge_counts_py(inputs: Vec<String>, output: String) -> PyResult<usize> {
+    let inputs: Vec<PathBuf> = inputs.into_iter().map(PathBuf::from).collect();
+    counts::merge_counts(&inputs, output.as_ref())
+        .map_err(|e| PyRuntimeError::new_err(e.to_string()))
 }
 
 #[pymodule]
 fn rust_bam_tools(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(subsample_bam_py, m)?)?;
+    m.add_function(wrap_pyfunction!(count_matching_py, m)?)?;
+    m.add_function(wrap_pyfunction!(count_distinct_barcodes_py, m)?)?;
+    m.add_function(wrap_pyfunction!(classify_barcodes_py, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_counts_py, m)?)?;
+    m.add_class::<SubsampleResult>()?;
+    m.add_class::<BarcodeReport>()?;
+    m.add_class::<ThreadPoolHandle>()?;
+    m.add_class::<MatchedRecordIterator>()?;
 
     Ok(())
 }
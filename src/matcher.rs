@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+
+const BASES: [u8; 5] = [b'A', b'C', b'G', b'T', b'N'];
+
+pub trait BarcodeMatcher: Send + Sync {
+    fn matches(&self, tag: &[u8]) -> bool;
+}
+
+/// Matches tags that are exactly present in the whitelist.
+pub struct ExactMatcher {
+    barcodes: HashSet<Vec<u8>>,
+}
+
+impl ExactMatcher {
+    pub fn new(barcodes: HashSet<Vec<u8>>) -> Self {
+        ExactMatcher { barcodes }
+    }
+}
+
+impl BarcodeMatcher for ExactMatcher {
+    fn matches(&self, tag: &[u8]) -> bool {
+        self.barcodes.contains(tag)
+    }
+}
+
+/// Matches tags within `max_mismatches` substitutions of a whitelisted barcode.
+pub struct MismatchMatcher {
+    barcodes: HashSet<Vec<u8>>,
+    max_mismatches: usize,
+}
+
+impl MismatchMatcher {
+    pub fn new(barcodes: HashSet<Vec<u8>>, max_mismatches: usize) -> Self {
+        MismatchMatcher {
+            barcodes,
+            max_mismatches,
+        }
+    }
+
+    fn matches_from(&self, tag: &mut Vec<u8>, from: usize, budget: usize) -> bool {
+        if self.barcodes.contains(tag.as_slice()) {
+            return true;
+        }
+        if budget == 0 {
+            return false;
+        }
+        for i in from..tag.len() {
+            let original = tag[i];
+            for &base in BASES.iter().filter(|&&b| b != original) {
+                tag[i] = base;
+                if self.matches_from(tag, i + 1, budget - 1) {
+                    tag[i] = original;
+                    return true;
+                }
+            }
+            tag[i] = original;
+        }
+        false
+    }
+}
+
+impl BarcodeMatcher for MismatchMatcher {
+    fn matches(&self, tag: &[u8]) -> bool {
+        // enumerate neighbours of the tag rather than scanning the whitelist
+        self.matches_from(&mut tag.to_vec(), 0, self.max_mismatches)
+    }
+}
+
+/// Strips everything from the last `delimiter` (e.g. the `-1` GEM well suffix)
+/// before handing the tag to the wrapped matcher.
+pub struct SuffixStripMatcher {
+    inner: Box<dyn BarcodeMatcher>,
+    delimiter: u8,
+}
+
+impl SuffixStripMatcher {
+    pub fn new(inner: Box<dyn BarcodeMatcher>, delimiter: u8) -> Self {
+        SuffixStripMatcher { inner, delimiter }
+    }
+}
+
+impl BarcodeMatcher for SuffixStripMatcher {
+    fn matches(&self, tag: &[u8]) -> bool {
+        match tag.iter().rposition(|&c| c == self.delimiter) {
+            Some(pos) => self.inner.matches(&tag[..pos]),
+            None => self.inner.matches(tag),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whitelist(barcodes: &[&str]) -> HashSet<Vec<u8>> {
+        barcodes.iter().map(|b| b.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn exact_matcher_needs_the_whole_tag() {
+        let matcher = ExactMatcher::new(whitelist(&["ACGT", "TTTT"]));
+        assert!(matcher.matches(b"ACGT"));
+        assert!(matcher.matches(b"TTTT"));
+        assert!(!matcher.matches(b"ACGA"));
+        assert!(!matcher.matches(b"ACG"));
+        assert!(!matcher.matches(b"ACGT-1"));
+    }
+
+    #[test]
+    fn mismatch_matcher_allows_up_to_the_budget() {
+        let matcher = MismatchMatcher::new(whitelist(&["ACGTACGT"]), 1);
+        assert!(matcher.matches(b"ACGTACGT"));
+        assert!(matcher.matches(b"ACGTACGA"));
+        assert!(matcher.matches(b"NCGTACGT"));
+        assert!(!matcher.matches(b"TCGTACGA"));
+        assert!(!matcher.matches(b"ACGTACG"));
+
+        let matcher = MismatchMatcher::new(whitelist(&["ACGTACGT"]), 2);
+        assert!(matcher.matches(b"TCGTACGA"));
+        assert!(!matcher.matches(b"TGGTACGA"));
+    }
+
+    #[test]
+    fn mismatch_matcher_with_no_budget_is_exact() {
+        let matcher = MismatchMatcher::new(whitelist(&["ACGT"]), 0);
+        assert!(matcher.matches(b"ACGT"));
+        assert!(!matcher.matches(b"ACGA"));
+    }
+
+    #[test]
+    fn suffix_strip_matcher_drops_the_last_suffix() {
+        let inner = Box::new(ExactMatcher::new(whitelist(&["ACGT", "ACGT-1"])));
+        let matcher = SuffixStripMatcher::new(inner, b'-');
+        assert!(matcher.matches(b"ACGT-1"));
+        assert!(matcher.matches(b"ACGT-2"));
+        assert!(matcher.matches(b"ACGT-1-1"));
+        assert!(matcher.matches(b"ACGT"));
+        assert!(!matcher.matches(b"ACGA-1"));
+    }
+
+    #[test]
+    fn suffix_strip_matcher_wraps_a_mismatch_matcher() {
+        let inner = Box::new(MismatchMatcher::new(whitelist(&["ACGT"]), 1));
+        let matcher = SuffixStripMatcher::new(inner, b'-');
+        assert!(matcher.matches(b"ACGA-1"));
+        assert!(!matcher.matches(b"AGGA-1"));
+    }
+}
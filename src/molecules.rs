@@ -0,0 +1,125 @@
+use crate::subsample_bam::get_record_tag;
+use failure::Error;
+use log::info;
+use rust_htslib::bam::{self, Read};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Rewrites `bam_path` to hold one read per `(cell_tag, umi_tag)` molecule,
+/// returning the number of molecules.
+///
+/// The first pass picks the highest-MAPQ read of every molecule (the first
+/// one seen on ties) and the second writes just those reads, so the output
+/// keeps its order and only a record index per molecule is held in memory.
+/// Reads missing either tag are dropped.
+pub fn collapse_molecules(
+    bam_path: &Path,
+    tmp_dir: &Path,
+    cell_tag: &str,
+    umi_tag: &str,
+) -> Result<u64, Error> {
+    let mut best: HashMap<(Vec<u8>, Vec<u8>), (u8, u64)> = HashMap::new();
+    let mut n_reads = 0;
+    let mut bam = bam::Reader::from_path(bam_path)?;
+    for (i, r) in bam.records().enumerate() {
+        let rec = r?;
+        n_reads += 1;
+        let key = match (
+            get_record_tag(&rec, cell_tag),
+            get_record_tag(&rec, umi_tag),
+        ) {
+            (Some(cell), Some(umi)) => (cell, umi),
+            _ => continue,
+        };
+        match best.entry(key) {
+            Entry::Occupied(mut e) => {
+                if rec.mapq() > e.get().0 {
+                    e.insert((rec.mapq(), i as u64));
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert((rec.mapq(), i as u64));
+            }
+        }
+    }
+    let keep: HashSet<u64> = best.values().map(|(_, i)| *i).collect();
+
+    let tmp_path = tmp_dir.join("collapsed.bam");
+    {
+        let mut bam = bam::Reader::from_path(bam_path)?;
+        let header = bam::Header::from_template(bam.header());
+        let mut out = bam::Writer::from_path(&tmp_path, &header, bam::Format::Bam)?;
+        for (i, r) in bam.records().enumerate() {
+            let rec = r?;
+            if keep.contains(&(i as u64)) {
+                out.write(&rec)?;
+            }
+        }
+    }
+    fs::rename(&tmp_path, bam_path).or_else(|_| fs::copy(&tmp_path, bam_path).map(|_| ()))?;
+    info!("Collapsed {} reads into {} molecules", n_reads, keep.len());
+    Ok(keep.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString, Record};
+    use tempfile::tempdir;
+
+    const TEST_HEADER: &[u8] = b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n";
+
+    fn molecule_read(name: &str, pos: i64, cell: &str, umi: Option<&str>, mapq: u8) -> Record {
+        let mut rec = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(4)]);
+        rec.set(name.as_bytes(), Some(&cigar), b"ACGT", &[30; 4]);
+        rec.set_tid(0);
+        rec.set_pos(pos);
+        rec.set_mtid(-1);
+        rec.set_mpos(-1);
+        rec.set_mapq(mapq);
+        rec.push_aux(b"CB", Aux::String(cell)).unwrap();
+        if let Some(umi) = umi {
+            rec.push_aux(b"UB", Aux::String(umi)).unwrap();
+        }
+        rec
+    }
+
+    #[test]
+    fn duplicate_molecules_collapse_to_the_best_mapq_read() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("molecules.bam");
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(TEST_HEADER));
+        {
+            let mut out = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+            let reads = [
+                ("a_low", "AAAA", Some("GGGG"), 10),
+                ("b_only", "CCCC", Some("GGGG"), 5),
+                ("a_best", "AAAA", Some("GGGG"), 60),
+                ("a_tie", "AAAA", Some("GGGG"), 60),
+                ("a_other_umi", "AAAA", Some("TTTT"), 1),
+                ("no_umi", "AAAA", None, 60),
+            ];
+            for (i, (name, cell, umi, mapq)) in reads.iter().enumerate() {
+                out.write(&molecule_read(name, i as i64, cell, *umi, *mapq))
+                    .unwrap();
+            }
+        }
+
+        let n_molecules = collapse_molecules(&path, dir.path(), "CB", "UB").unwrap();
+        assert_eq!(n_molecules, 3);
+        let mut bam = bam::Reader::from_path(&path).unwrap();
+        let names: Vec<Vec<u8>> = bam.records().map(|r| r.unwrap().qname().to_vec()).collect();
+        // in input order; the first read of a MAPQ tie is kept
+        assert_eq!(
+            names,
+            vec![
+                b"b_only".to_vec(),
+                b"a_best".to_vec(),
+                b"a_other_umi".to_vec()
+            ]
+        );
+    }
+}
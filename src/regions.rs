@@ -0,0 +1,345 @@
+use crate::fastq::sidecar_writer;
+use failure::{bail, Error};
+use log::{debug, warn};
+use rust_htslib::bam::{self, Record};
+use rust_lapper::{Interval, Lapper};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// BED intervals indexed by the tid of the BAM header they were resolved against.
+pub struct RegionSet {
+    trees: Vec<Option<Lapper<u64, ()>>>,
+}
+
+pub fn load_bed(bed_file: &Path, header: &bam::HeaderView) -> Result<RegionSet, Error> {
+    let reader = BufReader::new(fs::File::open(bed_file)?);
+    let mut intervals: HashMap<u32, Vec<Interval<u64, ()>>> = HashMap::new();
+    let mut n_intervals = 0;
+
+    for (line_no, l) in reader.lines().enumerate() {
+        let l = l?;
+        if l.is_empty() || l.starts_with('#') || l.starts_with("track") || l.starts_with("browser")
+        {
+            continue;
+        }
+        let fields: Vec<&str> = l.split('\t').collect();
+        if fields.len() < 3 {
+            bail!(
+                "Line {} of {} has fewer than 3 columns",
+                line_no + 1,
+                bed_file.display()
+            );
+        }
+        let tid = match header.tid(fields[0].as_bytes()) {
+            Some(tid) => tid,
+            None => {
+                warn!(
+                    "Skipping BED contig {} which is not in the BAM header",
+                    fields[0]
+                );
+                continue;
+            }
+        };
+        let (start, stop) = match (fields[1].parse::<u64>(), fields[2].parse::<u64>()) {
+            (Ok(start), Ok(stop)) => (start, stop),
+            _ => bail!(
+                "Line {} of {} has invalid coordinates",
+                line_no + 1,
+                bed_file.display()
+            ),
+        };
+        intervals
+            .entry(tid)
+            .or_insert_with(Vec::new)
+            .push(Interval {
+                start,
+                stop,
+                val: (),
+            });
+        n_intervals += 1;
+    }
+    debug!("Loaded {} BED intervals", n_intervals);
+
+    let mut trees: Vec<Option<Lapper<u64, ()>>> =
+        (0..header.target_count()).map(|_| None).collect();
+    for (tid, ivs) in intervals {
+        trees[tid as usize] = Some(Lapper::new(ivs));
+    }
+    Ok(RegionSet { trees })
+}
+
+impl RegionSet {
+    pub fn overlaps(&self, tid: i32, start: u64, stop: u64) -> bool {
+        if tid < 0 {
+            return false;
+        }
+        match self.trees.get(tid as usize) {
+            Some(Some(tree)) => tree.find(start, stop).next().is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn overlaps_record(&self, rec: &Record) -> bool {
+        if rec.is_unmapped() {
+            return false;
+        }
+        let start = rec.pos() as u64;
+        let stop = rec.cigar().end_pos() as u64;
+        self.overlaps(rec.tid(), start, stop.max(start + 1))
+    }
+}
+
+/// Sorted 0-based positions per tid.
+pub struct PositionSet {
+    positions: Vec<Vec<u64>>,
+}
+
+/// Loads `chr<TAB>pos` lines with 1-based positions.
+pub fn load_positions(
+    positions_file: &Path,
+    header: &bam::HeaderView,
+) -> Result<PositionSet, Error> {
+    let reader = BufReader::new(fs::File::open(positions_file)?);
+    let mut positions: Vec<Vec<u64>> = (0..header.target_count()).map(|_| Vec::new()).collect();
+
+    for (line_no, l) in reader.lines().enumerate() {
+        let l = l?;
+        if l.is_empty() || l.starts_with('#') {
+            continue;
+        }
+        let mut fields = l.split('\t');
+        let (chrom, pos) = match (
+            fields.next(),
+            fields.next().map(|p| p.trim().parse::<u64>()),
+        ) {
+            (Some(chrom), Some(Ok(pos))) if pos > 0 => (chrom, pos),
+            _ => bail!(
+                "Line {} of {} is not a chr<TAB>pos pair",
+                line_no + 1,
+                positions_file.display()
+            ),
+        };
+        match header.tid(chrom.as_bytes()) {
+            Some(tid) => positions[tid as usize].push(pos - 1),
+            None => warn!(
+                "Skipping position on {} which is not in the BAM header",
+                chrom
+            ),
+        }
+    }
+    for tid_positions in positions.iter_mut() {
+        tid_positions.sort_unstable();
+        tid_positions.dedup();
+    }
+    Ok(PositionSet { positions })
+}
+
+impl PositionSet {
+    pub fn covers_record(&self, rec: &Record) -> bool {
+        if rec.is_unmapped() || rec.tid() < 0 {
+            return false;
+        }
+        let tid_positions = match self.positions.get(rec.tid() as usize) {
+            Some(p) => p,
+            None => return false,
+        };
+        let start = rec.pos() as u64;
+        let end = rec.cigar().end_pos() as u64;
+        // first listed position at or after the read start
+        let i = match tid_positions.binary_search(&start) {
+            Ok(i) | Err(i) => i,
+        };
+        i < tid_positions.len() && tid_positions[i] < end
+    }
+}
+
+/// Reference spans covered by written reads, merged into disjoint intervals.
+///
+/// Spans are merged into the previous interval as they arrive, which keeps
+/// coordinate-sorted input compact; `write` sorts and merges whatever is left.
+#[derive(Default)]
+pub struct Coverage {
+    intervals: Vec<Vec<(u64, u64)>>,
+}
+
+fn merge_intervals(intervals: &mut Vec<(u64, u64)>) {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for &(start, stop) in intervals.iter() {
+        match merged.last_mut() {
+            // touching intervals merge too, so back-to-back reads give one interval
+            Some(last) if start <= last.1 => last.1 = last.1.max(stop),
+            _ => merged.push((start, stop)),
+        }
+    }
+    *intervals = merged;
+}
+
+impl Coverage {
+    pub fn add_record(&mut self, rec: &Record) {
+        if rec.is_unmapped() || rec.tid() < 0 {
+            return;
+        }
+        let tid = rec.tid() as usize;
+        let start = rec.pos() as u64;
+        let stop = (rec.cigar().end_pos() as u64).max(start + 1);
+        if self.intervals.len() <= tid {
+            self.intervals.resize_with(tid + 1, Vec::new);
+        }
+        let tid_intervals = &mut self.intervals[tid];
+        match tid_intervals.last_mut() {
+            Some(last) if start >= last.0 && start <= last.1 => last.1 = last.1.max(stop),
+            _ => tid_intervals.push((start, stop)),
+        }
+    }
+
+    pub fn merge(&mut self, other: Coverage) {
+        if self.intervals.len() < other.intervals.len() {
+            self.intervals.resize_with(other.intervals.len(), Vec::new);
+        }
+        for (mine, theirs) in self.intervals.iter_mut().zip(other.intervals) {
+            mine.extend(theirs);
+        }
+    }
+
+    /// Writes the merged intervals as a BED sorted in header order.
+    pub fn write(mut self, path: &Path, header: &bam::HeaderView) -> Result<(), Error> {
+        let mut out = sidecar_writer(path)?;
+        let names = header.target_names();
+        for (tid, tid_intervals) in self.intervals.iter_mut().enumerate() {
+            merge_intervals(tid_intervals);
+            let name = String::from_utf8_lossy(names[tid]);
+            for (start, stop) in tid_intervals.iter() {
+                writeln!(out, "{}\t{}\t{}", name, start, stop)?;
+            }
+        }
+        out.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    use tempfile::tempdir;
+
+    fn header() -> bam::HeaderView {
+        bam::HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n")
+    }
+
+    fn read_at(tid: i32, pos: i64, len: u32) -> Record {
+        let mut rec = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(len)]);
+        rec.set(
+            b"read",
+            Some(&cigar),
+            &vec![b'A'; len as usize],
+            &vec![30; len as usize],
+        );
+        rec.set_tid(tid);
+        rec.set_pos(pos);
+        rec
+    }
+
+    #[test]
+    fn bed_regions_keep_only_overlapping_reads() {
+        let dir = tempdir().unwrap();
+        let bed = dir.path().join("regions.bed");
+        fs::write(
+            &bed,
+            "track name=test\nchr1\t100\t200\nchr1\t500\t600\tpeak\nchrM\t0\t10\nchr2\t0\t50\n",
+        )
+        .unwrap();
+        let regions = load_bed(&bed, &header()).unwrap();
+
+        // BED intervals are half-open, so a read starting at an interval's end misses it
+        let reads = [
+            (0, 96, true),
+            (0, 196, true),
+            (0, 200, false),
+            (0, 300, false),
+            (0, 599, true),
+            (1, 10, true),
+            (1, 100, false),
+        ];
+        for (tid, pos, kept) in reads.iter() {
+            assert_eq!(
+                regions.overlaps_record(&read_at(*tid, *pos, 4)),
+                *kept,
+                "read at {}:{}",
+                tid,
+                pos
+            );
+        }
+        let mut unmapped = read_at(0, 150, 4);
+        unmapped.set_flags(0x4);
+        assert!(!regions.overlaps_record(&unmapped));
+
+        fs::write(&bed, "chr1\t100\n").unwrap();
+        let err = load_bed(&bed, &header()).err().unwrap();
+        assert!(err.to_string().contains("fewer than 3 columns"));
+    }
+
+    #[test]
+    fn positions_keep_reads_spanning_one() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("positions.tsv");
+        // 1-based, out of order and with a repeat
+        fs::write(&path, "chr1\t300\nchr1\t101\nchr2\t50\nchr1\t101\n").unwrap();
+        let positions = load_positions(&path, &header()).unwrap();
+
+        let reads = [
+            (0, 95, 10, true),
+            (0, 100, 1, true),
+            (0, 101, 50, false),
+            (0, 250, 50, true),
+            (0, 300, 5, false),
+            (0, 0, 900, true),
+            (1, 0, 100, true),
+            (1, 50, 10, false),
+        ];
+        for (tid, pos, len, kept) in reads.iter() {
+            assert_eq!(
+                positions.covers_record(&read_at(*tid, *pos, *len)),
+                *kept,
+                "{}bp read at {}:{}",
+                len,
+                tid,
+                pos
+            );
+        }
+
+        fs::write(&path, "chr1\t0\n").unwrap();
+        let err = load_positions(&path, &header()).err().unwrap();
+        assert!(err.to_string().contains("not a chr<TAB>pos pair"));
+    }
+
+    #[test]
+    fn coverage_merges_adjacent_and_overlapping_reads() {
+        let mut first = Coverage::default();
+        let mut second = Coverage::default();
+        // touching, overlapping, then a gap
+        for (tid, pos, len) in [(0, 100, 4), (0, 104, 4), (0, 106, 10), (0, 200, 4)].iter() {
+            first.add_record(&read_at(*tid, *pos, *len));
+        }
+        // a second chunk, out of order and overlapping the first's reads
+        for (tid, pos, len) in [(1, 10, 10), (1, 0, 12), (0, 202, 4)].iter() {
+            second.add_record(&read_at(*tid, *pos, *len));
+        }
+        let mut unmapped = read_at(0, 500, 4);
+        unmapped.set_flags(0x4);
+        second.add_record(&unmapped);
+        first.merge(second);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("covered.bed");
+        first.write(&path, &header()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "chr1\t100\t116\nchr1\t200\t206\nchr2\t0\t20\n"
+        );
+    }
+}
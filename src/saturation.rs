@@ -0,0 +1,133 @@
+use crate::fastq::sidecar_writer;
+use crate::subsample_bam::stable_hash;
+use failure::Error;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Sampling depths, as fractions of the matched reads, reported in the curve.
+pub const SATURATION_FRACTIONS: [f64; 10] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+
+/// Streaming saturation counts.
+///
+/// Every read draws a value in [0, 1) from a fixed hash of its name and the
+/// seed, so mates and repeated runs, whatever the build, draw the same value. A
+/// read is in the sample at depth `f` when its value is below `f`, so an
+/// observation is unique at depth `f` when the smallest value of any read
+/// showing it is below `f`.
+#[derive(Default)]
+pub struct Saturation {
+    reads: [u64; 10],
+    observations: HashMap<Vec<u8>, f64>,
+}
+
+pub fn sample_value(qname: &[u8], seed: u64) -> f64 {
+    (stable_hash(qname, seed) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Saturation {
+    pub fn add(&mut self, qname: &[u8], observation: Vec<u8>, seed: u64) {
+        let value = sample_value(qname, seed);
+        for (reads, fraction) in self.reads.iter_mut().zip(SATURATION_FRACTIONS.iter()) {
+            if value < *fraction {
+                *reads += 1;
+            }
+        }
+        self.observe(observation, value);
+    }
+
+    fn observe(&mut self, observation: Vec<u8>, value: f64) {
+        match self.observations.entry(observation) {
+            Entry::Occupied(mut e) => {
+                if value < *e.get() {
+                    e.insert(value);
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(value);
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: Saturation) {
+        for (reads, n) in self.reads.iter_mut().zip(other.reads.iter()) {
+            *reads += n;
+        }
+        for (observation, value) in other.observations {
+            self.observe(observation, value);
+        }
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut unique = [0u64; 10];
+        for value in self.observations.values() {
+            for (n, fraction) in unique.iter_mut().zip(SATURATION_FRACTIONS.iter()) {
+                if value < fraction {
+                    *n += 1;
+                }
+            }
+        }
+
+        let mut out = sidecar_writer(path.as_ref())?;
+        writeln!(out, "fraction\treads\tunique\tunique_fraction")?;
+        for i in 0..SATURATION_FRACTIONS.len() {
+            let unique_fraction = if self.reads[i] > 0 {
+                unique[i] as f64 / self.reads[i] as f64
+            } else {
+                0.0
+            };
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{:.4}",
+                SATURATION_FRACTIONS[i], self.reads[i], unique[i], unique_fraction
+            )?;
+        }
+        out.finish()?;
+        Ok(())
+    }
+}
+
+/// Key of a `(barcode, umi, position)` observation. The barcode and UMI are
+/// length-prefixed, so no tag value, whatever bytes it holds, can run into
+/// the next field and collide with another observation.
+pub fn observation_key(barcode: &[u8], umi: &[u8], tid: i32, pos: i64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(barcode.len() + umi.len() + 20);
+    for field in &[barcode, umi] {
+        key.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        key.extend_from_slice(field);
+    }
+    key.extend_from_slice(&tid.to_le_bytes());
+    key.extend_from_slice(&pos.to_le_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observation_keys_do_not_collide_across_fields() {
+        assert_ne!(
+            observation_key(b"AAAA:CC", b"GG", 0, 10),
+            observation_key(b"AAAA", b"CC:GG", 0, 10)
+        );
+        assert_ne!(
+            observation_key(b"AAAA", b"", 0, 10),
+            observation_key(b"", b"AAAA", 0, 10)
+        );
+        assert_eq!(
+            observation_key(b"AAAA", b"GG", 1, 10),
+            observation_key(b"AAAA", b"GG", 1, 10)
+        );
+        assert_ne!(
+            observation_key(b"AAAA", b"GG", 1, 10),
+            observation_key(b"AAAA", b"GG", 0, 10)
+        );
+
+        let mut saturation = Saturation::default();
+        saturation.add(b"read1", observation_key(b"AAAA:CC", b"GG", 0, 10), 0);
+        saturation.add(b"read2", observation_key(b"AAAA", b"CC:GG", 0, 10), 0);
+        assert_eq!(saturation.observations.len(), 2);
+    }
+}
@@ -0,0 +1,235 @@
+use failure::Error;
+use log::debug;
+use rust_htslib::bam::{self, Read, Record};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Records held in memory per sorted run before spilling to disk.
+pub const DEFAULT_SORT_BUFFER_RECORDS: usize = 500_000;
+
+/// Runs merged at once, so a large sort stays well under the open file limit.
+const MAX_MERGE_FAN_IN: usize = 64;
+
+/// Approximate memory held by a buffered record: its packed data plus the
+/// `Record` itself. Sort keys are not counted.
+pub fn record_bytes(rec: &Record) -> usize {
+    rec.inner().l_data.max(0) as usize + std::mem::size_of::<Record>()
+}
+
+struct HeapEntry<K> {
+    key: K,
+    run: usize,
+    rec: Record,
+}
+
+impl<K: Ord> Ord for HeapEntry<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed so the BinaryHeap pops the smallest key first
+        other
+            .key
+            .cmp(&self.key)
+            .then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+impl<K: Ord> PartialOrd for HeapEntry<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord> PartialEq for HeapEntry<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord> Eq for HeapEntry<K> {}
+
+fn write_run<K: Ord>(
+    buffer: &mut Vec<(K, Record)>,
+    tmp_dir: &Path,
+    header: &bam::Header,
+    runs: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    // stable, so records with equal keys keep their input order
+    buffer.sort_by(|a, b| a.0.cmp(&b.0));
+    let path = tmp_dir.join(format!("sort_run_{}.bam", runs.len()));
+    let mut out = bam::Writer::from_path(&path, header, bam::Format::Bam)?;
+    for (_, rec) in buffer.drain(..) {
+        out.write(&rec)?;
+    }
+    runs.push(path);
+    Ok(())
+}
+
+/// Sorts `input` into `output` by `key` with an external merge sort.
+///
+/// At most `buffer_records` records, or about `buffer_bytes` of them (see
+/// `record_bytes`), are held in memory; each full buffer is sorted and spilled
+/// to a run file in `tmp_dir`. The runs are then merged, at most
+/// `MAX_MERGE_FAN_IN` at a time: while there are more, each group is merged
+/// into an intermediate run first. Run files are removed once merged.
+pub fn external_sort<K, F>(
+    input: &Path,
+    output: &Path,
+    tmp_dir: &Path,
+    header: &bam::Header,
+    buffer_records: usize,
+    buffer_bytes: usize,
+    key: F,
+) -> Result<(), Error>
+where
+    K: Ord,
+    F: Fn(&Record) -> K,
+{
+    let buffer_records = buffer_records.max(1);
+    let mut runs = Vec::new();
+    let mut buffer = Vec::with_capacity(buffer_records.min(DEFAULT_SORT_BUFFER_RECORDS));
+
+    let mut buffered_bytes = 0;
+    let mut bam = bam::Reader::from_path(input)?;
+    for r in bam.records() {
+        let rec = r?;
+        buffered_bytes += record_bytes(&rec);
+        buffer.push((key(&rec), rec));
+        if buffer.len() >= buffer_records || buffered_bytes >= buffer_bytes {
+            write_run(&mut buffer, tmp_dir, header, &mut runs)?;
+            buffered_bytes = 0;
+        }
+    }
+    if !buffer.is_empty() || runs.is_empty() {
+        write_run(&mut buffer, tmp_dir, header, &mut runs)?;
+    }
+    debug!("Merging {} sorted runs", runs.len());
+
+    // merge groups of runs until one merge keeps few enough files open
+    let mut level = 0;
+    while runs.len() > MAX_MERGE_FAN_IN {
+        let mut merged = Vec::new();
+        for (i, group) in runs.chunks(MAX_MERGE_FAN_IN).enumerate() {
+            let path = tmp_dir.join(format!("sort_merge_{}_{}.bam", level, i));
+            merge_runs(group, &path, header, &key)?;
+            merged.push(path);
+        }
+        debug!("Merged {} runs into {}", runs.len(), merged.len());
+        runs = merged;
+        level += 1;
+    }
+    merge_runs(&runs, output, header, &key)
+}
+
+/// Merges the sorted `runs` into `output` with one open reader per run, ties
+/// going to the earlier run, and removes the runs.
+fn merge_runs<K, F>(
+    runs: &[PathBuf],
+    output: &Path,
+    header: &bam::Header,
+    key: &F,
+) -> Result<(), Error>
+where
+    K: Ord,
+    F: Fn(&Record) -> K,
+{
+    let mut readers = runs
+        .iter()
+        .map(bam::Reader::from_path)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        let mut rec = Record::new();
+        if let Some(r) = reader.read(&mut rec) {
+            r?;
+            heap.push(HeapEntry {
+                key: key(&rec),
+                run,
+                rec,
+            });
+        }
+    }
+
+    let mut out = bam::Writer::from_path(output, header, bam::Format::Bam)?;
+    while let Some(HeapEntry { run, mut rec, .. }) = heap.pop() {
+        out.write(&rec)?;
+        if let Some(r) = readers[run].read(&mut rec) {
+            r?;
+            heap.push(HeapEntry {
+                key: key(&rec),
+                run,
+                rec,
+            });
+        }
+    }
+
+    for run in runs {
+        fs::remove_file(run)?;
+    }
+    Ok(())
+}
+
+/// Sort key of `@HD SO:queryname` output: read name, compared byte by byte
+/// (`SS:queryname:lexicographical`), then read 1 before read 2.
+pub fn name_sort_key(rec: &Record) -> (Vec<u8>, u16) {
+    (rec.qname().to_vec(), rec.flags() & 0xc0)
+}
+
+/// Sort key grouping records by tag value, then coordinate with unmapped last.
+pub fn tag_sort_key(bam_tag: &str) -> impl Fn(&Record) -> (Vec<u8>, u32, i64) + '_ {
+    move |rec| {
+        let tag = crate::subsample_bam::get_record_tag(rec, bam_tag).unwrap_or_default();
+        (tag, rec.tid() as u32, rec.pos())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+    use tempfile::tempdir;
+
+    const TEST_HEADER: &[u8] = b"@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:chr1\tLN:100000\n";
+
+    #[test]
+    fn external_sort_merges_more_runs_than_the_fan_in() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let output = dir.path().join("sorted.bam");
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(TEST_HEADER));
+        let n_reads = 3 * MAX_MERGE_FAN_IN + 5;
+        {
+            let mut out = bam::Writer::from_path(&input, &header, bam::Format::Bam).unwrap();
+            let cigar = CigarString(vec![Cigar::Match(4)]);
+            for i in 0..n_reads {
+                let mut rec = Record::new();
+                // names in reverse order, so every record changes place
+                let name = format!("read{:04}", n_reads - i);
+                rec.set(name.as_bytes(), Some(&cigar), b"ACGT", &[30; 4]);
+                rec.set_tid(0);
+                rec.set_pos(i as i64);
+                rec.set_mtid(-1);
+                rec.set_mpos(-1);
+                out.write(&rec).unwrap();
+            }
+        }
+
+        external_sort(
+            &input,
+            &output,
+            dir.path(),
+            &header,
+            1,
+            usize::MAX,
+            name_sort_key,
+        )
+        .unwrap();
+
+        let mut bam = bam::Reader::from_path(&output).unwrap();
+        let names: Vec<Vec<u8>> = bam.records().map(|r| r.unwrap().qname().to_vec()).collect();
+        assert_eq!(names.len(), n_reads);
+        assert!(names.windows(2).all(|w| w[0] < w[1]));
+        // only the input and output are left in the temp dir
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
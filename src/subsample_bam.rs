@@ -1,266 +1,6944 @@
-use failure::Error;
-use log::{debug, error, info};
+use crate::fastq::{
+    check_compression_level, concat_fastq, is_gzip_path, revcomp, sidecar_writer, MateInterleaver,
+    MaybeGzWriter, DEFAULT_FASTQ_COMPRESSION,
+};
+use crate::fixmate::fix_mates;
+use crate::flagstat::Flagstat;
+use crate::matcher::{BarcodeMatcher, ExactMatcher, MismatchMatcher, SuffixStripMatcher};
+use crate::molecules::collapse_molecules;
+use crate::regions::{load_bed, load_positions, Coverage, PositionSet, RegionSet};
+use crate::saturation::{observation_key, Saturation};
+use crate::sort::{external_sort, name_sort_key, tag_sort_key, DEFAULT_SORT_BUFFER_RECORDS};
+use crate::unmatched::UnmatchedBarcodes;
+use crate::writer_cache::{concat_parts, sanitize_key, WriterCache};
+use failure::{bail, Error};
+use flate2::read::MultiGzDecoder;
+use log::{debug, info, warn};
 use rayon::prelude::*;
-use rust_htslib::bam::record::Aux;
+use regex::bytes::Regex;
+use rust_htslib::bam::record::{Aux, Cigar, CigarString};
 use rust_htslib::bam::Record;
 use rust_htslib::bam::{self, Read};
+use rust_htslib::htslib;
+use rust_htslib::tpool;
+use serde::{Deserialize, Serialize};
 use simplelog::{Config, LevelFilter, SimpleLogger};
 use std::cmp;
-use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CString;
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io::prelude::*;
 use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::tempdir;
+use url::Url;
+
+/// Minimum number of reads a chunk must scan before fail_fast may abort the run.
+const FAIL_FAST_MIN_READS: u64 = 100_000;
+
+/// Reads a chunk scans between updates of the shared progress counter.
+const PROGRESS_BATCH: u64 = 10_000;
+
+/// Empty BGZF block every complete BAM ends with.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Open file handle budget for split outputs when none is given.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+/// Rough memory held by one open BAM writer: its BGZF block buffers plus
+/// htslib's output buffer.
+const WRITER_BUFFER_BYTES: usize = 256 * 1024;
+
+/// Records written between output size checks under `max_output_bytes`.
+const OUTPUT_SIZE_CHECK_RECORDS: u64 = 1000;
+
+/// Records a chunk sends to the ordered writer at a time.
+const ORDERED_BATCH_RECORDS: usize = 1024;
+
+/// Batches a chunk may get ahead of the ordered writer before it blocks.
+const ORDERED_CHANNEL_BATCHES: usize = 16;
 
 pub struct SliceArgs<'a> {
-    cell_barcodes: &'a HashSet<Vec<u8>>,
+    matcher: Option<&'a dyn BarcodeMatcher>,
+    tag_filters: &'a [TagFilter],
+    regions: Option<&'a RegionSet>,
+    positions: Option<&'a PositionSet>,
+    targets: Option<&'a HashMap<Vec<u8>, BarcodeTarget>>,
+    translation: Option<&'a HashMap<Vec<u8>, Vec<u8>>>,
+    source_label: Option<&'a str>,
+    routes: &'a [Regex],
     i: usize,
     bam_file: &'a Path,
     tmp_dir: &'a Path,
+    chunk_key: Option<String>,
     bam_tag: String,
     virtual_start: Option<i64>,
     virtual_stop: Option<i64>,
     to_replace: Option<String>,
     replacement: Option<String>,
+    opts: &'a SubsampleOptions,
+    max_open_files: usize,
+    first_done: &'a AtomicBool,
+    abort: &'a AtomicBool,
+    progress: &'a AtomicU64,
+    write_errors: &'a AtomicU64,
+    run_start: Instant,
 }
 
-pub fn load_barcodes(filename: impl AsRef<Path>) -> Result<HashSet<Vec<u8>>, Error> {
-    let r = fs::File::open(filename.as_ref())?;
-    let reader = BufReader::with_capacity(32 * 1024, r);
+impl<'a> SliceArgs<'a> {
+    fn selector(&self) -> RecordSelector<'_> {
+        RecordSelector {
+            matcher: self.matcher,
+            tag_filters: self.tag_filters,
+            regions: self.regions,
+            positions: self.positions,
+            targets: self.targets,
+            translation: self.translation,
+            source_label: self.source_label,
+            bam_tag: &self.bam_tag,
+            to_replace: self.to_replace.as_deref(),
+            replacement: self.replacement.as_deref(),
+            opts: self.opts,
+        }
+    }
+}
 
-    let mut bc_set = HashSet::new();
+/// A further tag that must match its own whitelist, see `extra_tag_whitelists`.
+pub struct TagFilter {
+    pub tag: String,
+    pub matcher: Box<dyn BarcodeMatcher>,
+}
 
-    for l in reader.lines() {
-        let seq = l?.into_bytes();
-        bc_set.insert(seq);
+/// Decides which records are kept and how they are rewritten, independent of
+/// where the records are read from or written to.
+pub struct RecordSelector<'a> {
+    pub matcher: Option<&'a dyn BarcodeMatcher>,
+    /// Conditions combined with `matcher` under `match_any_tag`.
+    pub tag_filters: &'a [TagFilter],
+    pub regions: Option<&'a RegionSet>,
+    pub positions: Option<&'a PositionSet>,
+    pub targets: Option<&'a HashMap<Vec<u8>, BarcodeTarget>>,
+    pub translation: Option<&'a HashMap<Vec<u8>, Vec<u8>>>,
+    /// Name of the whitelist written to `annotate_source_tag`.
+    pub source_label: Option<&'a str>,
+    pub bam_tag: &'a str,
+    pub to_replace: Option<&'a str>,
+    pub replacement: Option<&'a str>,
+    pub opts: &'a SubsampleOptions,
+}
+
+impl<'a> RecordSelector<'a> {
+    /// Whether `rec` carries `bam_tag` more than once, an error under
+    /// `strict_tags`. Otherwise the first copy is the one matched, and any
+    /// rewrite of the tag replaces every copy.
+    pub fn check_tag(&self, rec: &Record) -> Result<bool, Error> {
+        let duplicated = has_duplicate_tag(rec, self.bam_tag);
+        if duplicated && self.opts.strict_tags {
+            bail!(
+                "Read {} has more than one {} tag",
+                String::from_utf8_lossy(rec.qname()),
+                self.bam_tag
+            );
+        }
+        Ok(duplicated)
     }
-    let num_bcs = bc_set.len();
-    if num_bcs == 0 {
-        error!("Loaded 0 barcodes. Is your barcode file gzipped or empty?");
-        process::exit(1);
+
+    /// Returns the (extracted) tag of a record that should be kept, or `None`
+    /// to drop it. A kept record may have no tag when there is no matcher.
+    pub fn select(&self, rec: &mut Record) -> Result<Option<Option<Vec<u8>>>, Error> {
+        self.select_counting(rec, None)
     }
-    debug!("Loaded {} barcodes", num_bcs);
-    Ok(bc_set)
-}
 
-pub fn get_record_tag<'a>(rec: &'a Record, bam_tag: &str) -> Option<Vec<u8>> {
-    let tag = rec.aux(bam_tag.as_bytes());
-    match tag {
-        Ok(t) => match t {
-            Aux::String(t) => Some(t.as_bytes().to_vec()),
-            _ => None,
-        },
-        Err(t) => None,
+    /// `select`, adding the tags of reads dropped for not matching the
+    /// whitelist to `unmatched`.
+    pub fn select_counting(
+        &self,
+        rec: &mut Record,
+        unmatched: Option<&mut UnmatchedBarcodes>,
+    ) -> Result<Option<Option<Vec<u8>>>, Error> {
+        let tag = get_record_tag(rec, self.bam_tag);
+
+        if let (Some(to_replace), Some(replacement), Some(_)) =
+            (self.to_replace, self.replacement, &tag)
+        {
+            substitute_text_in_tag(rec, self.bam_tag, to_replace, replacement)?;
+        }
+
+        if !passes_filters(rec, self.opts) {
+            return Ok(None);
+        }
+        if let Some(regions) = self.regions {
+            if !regions.overlaps_record(rec) {
+                return Ok(None);
+            }
+        }
+        if let Some(positions) = self.positions {
+            if !positions.covers_record(rec) {
+                return Ok(None);
+            }
+        }
+        if self.opts.untagged_only {
+            return Ok(if tag.is_none() { Some(None) } else { None });
+        }
+
+        let tag = match self.opts.tag_field {
+            Some((delimiter, field)) => tag.and_then(|t| extract_tag_field(&t, delimiter, field)),
+            None => tag,
+        };
+        let tag = if self.opts.case_insensitive {
+            tag.map(|t| t.to_ascii_uppercase())
+        } else {
+            tag
+        };
+
+        // the translated barcode replaces the whole tag in the output
+        let tag = match (self.translation, tag) {
+            (Some(translation), Some(t)) => match translation.get(&t) {
+                Some(translated) => {
+                    remove_tag(rec, self.bam_tag.as_bytes())?;
+                    rec.push_aux(
+                        self.bam_tag.as_bytes(),
+                        Aux::String(std::str::from_utf8(translated)?),
+                    )?;
+                    Some(translated.clone())
+                }
+                None => Some(t),
+            },
+            (_, tag) => tag,
+        };
+
+        // without a matcher every record is written (rewrite_only)
+        let tag = match (self.matcher, tag) {
+            (None, tag) => tag,
+            (Some(matcher), tag) if !self.tag_filters.is_empty() => {
+                let matched = tag.as_ref().map_or(false, |t| matcher.matches(t));
+                if !self.passes_tag_filters(rec, matched) {
+                    return Ok(None);
+                }
+                tag
+            }
+            (Some(matcher), tag) if self.opts.denylist => match tag {
+                Some(t) if matcher.matches(&t) => return Ok(None),
+                tag => tag,
+            },
+            (Some(matcher), Some(t)) if matcher.matches(&t) => Some(t),
+            (Some(_), Some(t)) => {
+                if let Some(unmatched) = unmatched {
+                    unmatched.add(&t);
+                }
+                return Ok(None);
+            }
+            _ => return Ok(None),
+        };
+
+        // barcodes without a target, or whose target is used up, are dropped
+        if let Some(targets) = self.targets {
+            match tag
+                .as_ref()
+                .and_then(|t| targets.get(&normalize_barcode(t, self.opts)))
+            {
+                Some(target) if target.try_take() => {}
+                _ => return Ok(None),
+            }
+        }
+        Ok(Some(tag))
     }
-}
 
-pub fn load_writer(bam: &bam::Reader, out_bam_path: &Path) -> Result<bam::Writer, Error> {
-    use rust_htslib::bam::Read; // collides with fs::Read
-    let hdr = rust_htslib::bam::Header::from_template(bam.header());
-    let out_handle = bam::Writer::from_path(out_bam_path, &hdr, bam::Format::Bam)?;
-    Ok(out_handle)
+    /// Combines whether the main tag matched with the `tag_filters`: all must
+    /// match, or under `match_any_tag` any one.
+    fn passes_tag_filters(&self, rec: &Record, matched: bool) -> bool {
+        let mut filters = self.tag_filters.iter().map(|f| {
+            get_record_tag(rec, &f.tag).map_or(false, |t| {
+                if self.opts.case_insensitive {
+                    f.matcher.matches(&t.to_ascii_uppercase())
+                } else {
+                    f.matcher.matches(&t)
+                }
+            })
+        });
+        if self.opts.match_any_tag {
+            matched || filters.any(|m| m)
+        } else {
+            matched && filters.all(|m| m)
+        }
+    }
+
+    /// Applies the output-side edits to a selected record.
+    pub fn rewrite(
+        &self,
+        rec: &mut Record,
+        tag: &Option<Vec<u8>>,
+        chunk: usize,
+    ) -> Result<(), Error> {
+        if let (Some(template), Some(_)) = (&self.opts.replacement_template, tag) {
+            expand_tag_template(rec, self.bam_tag, template, chunk)?;
+        }
+        if self.opts.uppercase_tag && tag.is_some() {
+            uppercase_tag(rec, self.bam_tag)?;
+        }
+        if self.opts.trim_softclips {
+            trim_softclips(rec);
+        }
+        if let Some(min_qual) = self.opts.mask_low_qual {
+            mask_low_quality(rec, min_qual);
+        }
+        if self.opts.strip_alignment {
+            strip_alignment(rec)?;
+        }
+        if let Some(keep) = &self.opts.keep_tags {
+            retain_tags(rec, keep)?;
+        }
+        if let Some(strip) = &self.opts.strip_tags {
+            strip_tags(rec, strip)?;
+        }
+        if let (Some(source_tag), Some(label), Some(_)) =
+            (&self.opts.annotate_source_tag, self.source_label, tag)
+        {
+            if rec.aux(source_tag.as_bytes()).is_ok() {
+                rec.remove_aux(source_tag.as_bytes())?;
+            }
+            rec.push_aux(source_tag.as_bytes(), Aux::String(label))?;
+        }
+        if let Some(chunk_tag) = &self.opts.tag_chunk {
+            if rec.aux(chunk_tag.as_bytes()).is_ok() {
+                rec.remove_aux(chunk_tag.as_bytes())?;
+            }
+            rec.push_aux(chunk_tag.as_bytes(), Aux::I32(chunk as i32))?;
+        }
+        Ok(())
+    }
 }
 
-pub fn bgzf_noffsets<P: AsRef<Path>>(
-    bam_path: &P,
-    num_chunks: &u64,
-) -> Result<Vec<(Option<i64>, Option<i64>)>, Error> {
-    use std::io::Read;
+/// Where `subsample_from_reader` reads records from: any htslib reader, or
+/// e.g. records held in memory.
+pub trait RecordSource {
+    /// Reads the next record into `rec`, `None` at the end of the input.
+    fn read_record(&mut self, rec: &mut Record) -> Option<Result<(), Error>>;
+    fn header_view(&self) -> &bam::HeaderView;
+}
 
-    fn vec_diff(input: &Vec<u64>) -> Vec<u64> {
-        let vals = input.iter();
-        let next_vals = input.iter().skip(1);
+impl<R: bam::Read> RecordSource for R {
+    fn read_record(&mut self, rec: &mut Record) -> Option<Result<(), Error>> {
+        self.read(rec).map(|r| r.map_err(Error::from))
+    }
 
-        vals.zip(next_vals).map(|(cur, next)| next - cur).collect()
+    fn header_view(&self) -> &bam::HeaderView {
+        self.header()
     }
+}
 
-    // if we only have one thread, this is easy
-    if *num_chunks == 1 as u64 {
-        let final_offsets = vec![(None, None)];
-        return Ok(final_offsets);
+/// Where `subsample_from_reader` writes the selected records.
+pub trait RecordSink {
+    fn write_record(&mut self, rec: &Record) -> Result<(), Error>;
+}
+
+impl RecordSink for bam::Writer {
+    fn write_record(&mut self, rec: &Record) -> Result<(), Error> {
+        Ok(self.write(rec)?)
     }
+}
 
-    let bam_bytes = fs::metadata(bam_path)?.len();
-    let mut initial_offsets = Vec::new();
-    let step_size = bam_bytes / num_chunks;
-    for n in 1..*num_chunks {
-        initial_offsets.push((step_size * n) as u64);
+impl RecordSink for Vec<Record> {
+    fn write_record(&mut self, rec: &Record) -> Result<(), Error> {
+        self.push(rec.clone());
+        Ok(())
     }
+}
 
-    let num_bytes = if initial_offsets.len() > 1 {
-        let diff = vec_diff(&initial_offsets);
-        let m = diff.iter().max().unwrap();
-        cmp::min(1 << 16, *m)
-    } else {
-        1 << 16
-    };
+/// Streams every record of `reader` through `selector` into `writer`,
+/// returning the reads scanned and written.
+///
+/// This is the core of `subsample_bam` without the file handling, chunking
+/// and side outputs, so it can be driven by any reader and writer.
+pub fn subsample_from_reader<R: RecordSource, W: RecordSink>(
+    reader: &mut R,
+    writer: &mut W,
+    selector: &RecordSelector,
+) -> Result<(u64, u64), Error> {
+    let progress = AtomicU64::new(0);
+    let write_errors = AtomicU64::new(0);
+    let header = reader.header_view().clone();
+    let mut pipeline = RecordPipeline::new(selector, &[], 0, &progress, &header);
+    let stop_at_unplaced = selector.opts.mapped_only && is_coordinate_sorted(&header);
+    stream_records(
+        reader,
+        writer,
+        &mut pipeline,
+        stop_at_unplaced,
+        None,
+        &write_errors,
+    )?;
+    let result = pipeline.finish(Path::new(""), None, Instant::now(), 0.0)?;
+    Ok((result.reads_scanned, result.reads_written))
+}
 
-    // linear search to the right of each possible offset until
-    // a valid virtual offset is found
-    let mut adjusted_offsets = Vec::new();
-    let mut fp = fs::File::open(bam_path)?;
-    for offset in initial_offsets {
-        fp.seek(io::SeekFrom::Start(offset))?;
-        let mut buffer = [0; 2 << 16];
-        fp.read(&mut buffer)?;
-        for i in 0..num_bytes {
-            if is_valid_bgzf_block(&buffer[i as usize..]) {
-                adjusted_offsets.push(offset + i);
-                break;
+/// Runs the records of `reader` through `pipeline` into `writer`, until the
+/// input ends or `head` or `mapped_only` stops it. Returns `true` when the
+/// file at `out_path` reached `max_output_bytes`.
+fn stream_records<R: RecordSource, W: RecordSink>(
+    reader: &mut R,
+    writer: &mut W,
+    pipeline: &mut RecordPipeline,
+    stop_at_unplaced: bool,
+    out_path: Option<&Path>,
+    write_errors: &AtomicU64,
+) -> Result<bool, Error> {
+    let opts = pipeline.selector.opts;
+    let mut rec = Record::new();
+    while let Some(r) = reader.read_record(&mut rec) {
+        r?;
+        // there are no other chunks to wait for, so abort as soon as enough reads are in
+        pipeline.check_fail_fast()?;
+        match pipeline.scan(&rec, stop_at_unplaced) {
+            Scan::Keep => {}
+            Scan::Skip => continue,
+            Scan::Stop => break,
+        }
+        let tag = match pipeline.select(&mut rec)? {
+            Some(tag) => tag,
+            None => continue,
+        };
+        if let Err(e) = writer.write_record(&rec) {
+            handle_write_error(&rec, e, write_errors, opts)?;
+            pipeline.write_errors += 1;
+            continue;
+        }
+        pipeline.written(&rec, &tag)?;
+        if let (Some(max_bytes), Some(out_path)) = (opts.max_output_bytes, out_path) {
+            // only whole BGZF blocks reach the file, so the size lags the records written
+            if pipeline.reads_written % OUTPUT_SIZE_CHECK_RECORDS == 0
+                && fs::metadata(out_path)?.len() >= max_bytes
+            {
+                info!(
+                    "Output reached {} bytes after {} reads, stopping",
+                    max_bytes, pipeline.reads_written
+                );
+                return Ok(true);
             }
         }
     }
-    // bit-shift and produce start/stop intervals
-    let mut final_offsets = Vec::new();
+    pipeline.check_fail_fast()?;
+    Ok(false)
+}
 
-    // handle special case where we only found one offset
-    if adjusted_offsets.len() == 1 {
-        final_offsets.push((None, None));
-        return Ok(final_offsets);
-    }
+/// The records of one BAM that pass the barcode match and read filters, read
+/// front to back, for callers that want the records rather than an output file.
+pub struct FilteredRecords {
+    reader: bam::Reader,
+    matcher: Option<Box<dyn BarcodeMatcher>>,
+    bam_tag: String,
+    opts: SubsampleOptions,
+    warned_duplicate_tag: bool,
+}
 
-    final_offsets.push((None, Some(((adjusted_offsets[1]) as i64) << 16)));
-    for n in 2..num_chunks - 1 {
-        let n = n as usize;
-        final_offsets.push((
-            Some((adjusted_offsets[n - 1] as i64) << 16),
-            Some((adjusted_offsets[n] as i64) << 16),
-        ));
+impl FilteredRecords {
+    /// Every record passing `opts`' filters is yielded when no barcode file is given.
+    pub fn new(
+        bam_file: &Path,
+        barcodes_file: Option<&Path>,
+        bam_tag: &str,
+        opts: SubsampleOptions,
+    ) -> Result<Self, Error> {
+        let matcher = match barcodes_file {
+            Some(path) => Some(build_matcher(load_barcodes(path, &opts)?, &opts)),
+            None => None,
+        };
+        Ok(FilteredRecords {
+            reader: bam::Reader::from_path(bam_file)?,
+            matcher,
+            bam_tag: bam_tag.to_string(),
+            opts,
+            warned_duplicate_tag: false,
+        })
     }
-    final_offsets.push((
-        Some(((adjusted_offsets[adjusted_offsets.len() - 1]) as i64) << 16),
-        None,
-    ));
-    Ok(final_offsets)
 }
 
-pub fn is_valid_bgzf_block(block: &[u8]) -> bool {
-    // look for the bgzip magic characters \x1f\x8b\x08\x04
-    // TODO: is this sufficient?
-    if block.len() < 18 {
-        return false;
-    }
-    if (block[0] != 31) | (block[1] != 139) | (block[2] != 8) | (block[3] != 4) {
-        return false;
+impl Iterator for FilteredRecords {
+    /// A matched record and its (extracted) tag.
+    type Item = Result<(Record, Option<Vec<u8>>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let selector = RecordSelector {
+            matcher: self.matcher.as_deref(),
+            tag_filters: &[],
+            regions: None,
+            positions: None,
+            targets: None,
+            translation: None,
+            source_label: None,
+            bam_tag: &self.bam_tag,
+            to_replace: None,
+            replacement: None,
+            opts: &self.opts,
+        };
+        let mut rec = Record::new();
+        loop {
+            if let Err(e) = self.reader.read(&mut rec)? {
+                return Some(Err(e.into()));
+            }
+            match selector.check_tag(&rec) {
+                Ok(true) if !self.warned_duplicate_tag => {
+                    warn!(
+                        "Read {} has more than one {} tag, using the first; further reads like it are not reported",
+                        String::from_utf8_lossy(rec.qname()),
+                        self.bam_tag
+                    );
+                    self.warned_duplicate_tag = true;
+                }
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            match selector.select(&mut rec) {
+                Ok(Some(tag)) => return Some(Ok((rec, tag))),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
-    true
 }
 
-pub fn read_bam_slice(args: &SliceArgs) -> Result<PathBuf, rust_htslib::tpool::Error> {
-    let mut bam = bam::Reader::from_path(args.bam_file).unwrap();
-    let out_bam_file = args.tmp_dir.join(format!("{}.bam", args.i));
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
 
-    let mut out_bam = load_writer(&bam, &out_bam_file).unwrap();
+impl std::str::FromStr for Strand {
+    type Err = Error;
 
-    for r in bam.iter_chunk(args.virtual_start, args.virtual_stop) {
-        let mut rec = r?;
-        let tag = get_record_tag(&rec, &args.bam_tag);
-
-        if args.to_replace.is_some() && tag.is_some() {
-            substitute_text_in_tag(
-                &mut rec,
-                &args.bam_tag,
-                &args.to_replace.clone().unwrap(),
-                &args.replacement.clone().unwrap(),
-            )
-            .expect("Missing tag");
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forward" | "+" => Ok(Strand::Forward),
+            "reverse" | "-" => Ok(Strand::Reverse),
+            _ => bail!("Unknown strand '{}', expected 'forward' or 'reverse'", s),
         }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mate {
+    Read1,
+    Read2,
+}
 
-        if tag.is_some() && args.cell_barcodes.contains(&tag.unwrap()) {
-            out_bam.write(&rec).expect("Cannot write to temp bam file")
+impl std::str::FromStr for Mate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "read1" | "r1" | "1" => Ok(Mate::Read1),
+            "read2" | "r2" | "2" => Ok(Mate::Read2),
+            _ => bail!("Unknown mate '{}', expected 'read1' or 'read2'", s),
         }
     }
+}
 
-    Ok(out_bam_file.to_path_buf())
+/// Optional read filters and transforms applied on top of barcode matching.
+#[derive(Clone, Debug, Default)]
+pub struct SubsampleOptions {
+    /// Minimum absolute insert size of properly-paired reads.
+    pub min_tlen: Option<i64>,
+    /// Maximum absolute insert size of properly-paired reads.
+    pub max_tlen: Option<i64>,
+    /// Keep reads that are not properly paired when an insert size filter is set.
+    pub tlen_keep_unpaired: bool,
+    /// SAM header file used in place of the input header for the output BAM.
+    pub header_template: Option<PathBuf>,
+    /// Remove leading and trailing soft-clipped bases from written reads.
+    pub trim_softclips: bool,
+    /// Number of substitutions allowed when matching tags to the whitelist.
+    pub barcode_mismatches: usize,
+    /// Strip tags and barcode file entries from the last occurrence of this
+    /// byte before matching, e.g. `b'-'` for the `-1` of Cell Ranger barcodes.
+    pub barcode_suffix_delimiter: Option<u8>,
+    /// Re-read the merged output and check it holds every written record.
+    pub validate_output: bool,
+    /// 0-based whitespace-delimited column of the barcode file holding the barcode.
+    pub barcode_column: Option<usize>,
+    /// Minimum number of M/=/X CIGAR bases.
+    pub min_aligned_length: Option<usize>,
+    /// Write per-reference counts of the written reads, in the style of samtools idxstats.
+    pub idxstats_output: Option<PathBuf>,
+    /// Abort when the first chunk to finish scanned many reads but matched none.
+    pub fail_fast: bool,
+    /// Also write one BAM per matched barcode into this directory.
+    pub split_output_dir: Option<PathBuf>,
+    /// Maximum number of split output files open at once (0 uses the default).
+    pub max_open_files: usize,
+    /// Keep only reads mapping to this strand.
+    pub strand: Option<Strand>,
+    /// Judge the strand of paired reads by the orientation of read 1.
+    pub strand_from_read1: bool,
+    /// htslib threads used to decompress the input of each chunk.
+    pub decompress_threads: usize,
+    /// Write every record after applying tag transforms, without a whitelist.
+    pub rewrite_only: bool,
+    /// Also write the matched reads as FASTQ, gzipped if the path ends in `.gz`.
+    pub fastq_output: Option<PathBuf>,
+    /// gzip level (0-9) for FASTQ output, 6 when unset.
+    pub fastq_compression: Option<u32>,
+    /// Persistent temp dir whose completed chunks are reused on a re-run.
+    /// Split, route and shard parts are kept there after they are joined, so
+    /// they can be reused too. Runs with `per_barcode_targets` always rescan
+    /// every chunk.
+    pub resume_dir: Option<PathBuf>,
+    /// Replace bases with a Phred quality below this with `N`.
+    pub mask_low_qual: Option<u8>,
+    /// Compare tags to the whitelist ignoring ASCII case.
+    pub case_insensitive: bool,
+    /// Write the run statistics as MultiQC custom content.
+    pub multiqc_output: Option<PathBuf>,
+    /// Keep only reads whose alignment overlaps an interval in this BED file.
+    pub bed_file: Option<PathBuf>,
+    /// `barcode\ttarget` file capping the reads kept per barcode; others are dropped.
+    pub per_barcode_targets: Option<PathBuf>,
+    /// Drop reads already flagged as duplicates (0x400).
+    pub remove_duplicates: bool,
+    /// Sort the output by (tag, tid, pos). Uses an external merge sort holding
+    /// `DEFAULT_SORT_BUFFER_RECORDS` records in memory and spilling sorted runs
+    /// to the temp dir, so it needs about as much temp space as the output.
+    pub sort_by_tag: bool,
+    /// Write a saturation curve of unique (barcode, UMI, position) observations.
+    pub saturation_output: Option<PathBuf>,
+    /// Tag holding the UMI, required for the saturation curve.
+    pub umi_tag: Option<String>,
+    /// Seed for the random sampling decisions (barcode sampling and the
+    /// saturation curve); the same inputs and seed give the same output.
+    pub seed: u64,
+    /// Key split outputs by contig (plus `unmapped`) instead of by barcode.
+    pub split_by_chrom: bool,
+    /// `@HD SO` value of the output header; `unknown` when unset, since the
+    /// input's sort order is not guaranteed to hold for the subset.
+    pub set_sort_order: Option<String>,
+    /// Keep only reads covering a position from this `chr<TAB>pos` (1-based) file.
+    pub positions_file: Option<PathBuf>,
+    /// Rewrite the tag of written reads from a template. `{barcode}` expands to
+    /// the tag value (after `to_replace` substitution) and `{chunk}` to the
+    /// chunk index, e.g. `{barcode}_S1` turns `AACC` into `AACC_S1`.
+    pub replacement_template: Option<String>,
+    /// Drop `@SQ` lines with no written reads; see `prune_header` for the cost.
+    pub prune_header: bool,
+    /// Error instead of warning when whitelist barcodes differ in length.
+    pub strict_barcode_lengths: bool,
+    /// Stop after scanning this many reads; forces a single chunk.
+    pub head: Option<u64>,
+    /// Log the running read count, with an approximate ETA, every this many seconds.
+    pub progress_interval: Option<u64>,
+    /// Aux tags to keep on written reads; all others are dropped.
+    pub keep_tags: Option<Vec<String>>,
+    /// Aux tags to remove from written reads; cannot be combined with keep_tags.
+    pub strip_tags: Option<Vec<String>>,
+    /// Hex MD5 the input BAM must match before any reads are processed.
+    pub expected_input_md5: Option<String>,
+    /// Delimiter and 0-based field index of the barcode within the tag value.
+    pub tag_field: Option<(char, usize)>,
+    /// Chunks to split each input into per core; more, smaller chunks even out stragglers.
+    pub chunks_per_core: usize,
+    /// Skip the first line of the barcode file.
+    pub barcode_has_header: bool,
+    /// Cell and UMI tags; keep only the best-MAPQ read of each molecule.
+    /// Molecules are collapsed exactly, across the whole merged output; there
+    /// is no approximate per-chunk mode.
+    pub collapse_molecules: Option<(String, String)>,
+    /// Fail if a written record goes backwards in coordinate order, whether
+    /// chunks are merged, written in order or the input is streamed.
+    pub assert_sorted: bool,
+    /// Tab-separated `from<TAB>to` barcode translations, optionally gzipped,
+    /// applied to the tag before matching.
+    pub translation_map: Option<PathBuf>,
+    /// Keep only this mate of paired reads.
+    pub mate: Option<Mate>,
+    /// Keep unpaired reads when a mate is selected.
+    pub mate_keep_unpaired: bool,
+    /// Skip records that fail to write, up to this many per run; by default
+    /// the first failure aborts.
+    pub max_write_errors: Option<u64>,
+    /// Keep a random subset of this many whitelist barcodes, chosen by `seed`.
+    pub sample_n_barcodes: Option<usize>,
+    /// Tag recording, on every matched read, the name (file stem) of the
+    /// whitelist it matched.
+    pub annotate_source_tag: Option<String>,
+    /// BED of the merged reference intervals covered by written reads.
+    pub coverage_bed: Option<PathBuf>,
+    /// Create missing parent directories of the output BAM.
+    pub create_output_dirs: bool,
+    /// Prefix of the temporary directory name, to tell runs apart on shared nodes.
+    pub temp_prefix: Option<String>,
+    /// `(regex, output)` pairs; each matched read is also written to the output
+    /// of the first regex its tag matches.
+    pub regex_routes: Vec<(String, PathBuf)>,
+    /// Output for matched reads that no route regex matches.
+    pub regex_default_output: Option<PathBuf>,
+    /// Keep this fraction of templates, chosen by read name hash as `samtools view -s`.
+    pub subsample_fraction: Option<f64>,
+    /// Seed of the read name hash, the integer part of samtools' `seed.fraction`.
+    pub subsample_seed: u32,
+    /// Approximate cap on buffered records and open writer buffers.
+    ///
+    /// Chunks run concurrently, so each gets an equal share for its split and
+    /// route writers (about 256 KiB per open writer); the tag sort runs alone
+    /// and gets the whole budget, counting each record's packed size.
+    pub max_memory_mb: Option<usize>,
+    /// Write mates next to each other, read 1 first, in the FASTQ output.
+    /// Mates are paired within a chunk; reads whose mate is elsewhere are
+    /// written at the end of their chunk's output.
+    pub interleave: bool,
+    /// Keep only reads without the barcode tag, ignoring the whitelist.
+    pub untagged_only: bool,
+    /// Also split written reads into this many BAMs, by a fixed read name hash
+    /// so mates, and reruns of any build, share a shard, named
+    /// `<output stem>.shard_<k>.bam`.
+    pub num_shards: Option<usize>,
+    /// Treat the barcode file as barcodes to drop (e.g. doublets); every other
+    /// read, including untagged ones, is kept.
+    pub denylist: bool,
+    /// Write reads unmapped, without position, CIGAR or alignment tags, for
+    /// re-mapping, like `samtools reset`: secondary and supplementary reads
+    /// are dropped and the output header keeps no `@SQ` lines.
+    pub strip_alignment: bool,
+    /// Error if any barcode holds anything but `ACGTN` (any case), to catch
+    /// the wrong file being passed as the whitelist. Blank lines are skipped.
+    pub validate_dna: bool,
+    /// `.fai` or SAM header whose contig order the output `@SQ` lines follow.
+    /// Coordinate-sorted input is no longer sorted once reordered.
+    pub reorder_to: Option<PathBuf>,
+    /// Drop reads whose GC fraction (ignoring `N`s) is below this.
+    pub min_gc: Option<f64>,
+    /// Drop reads whose GC fraction (ignoring `N`s) is above this.
+    pub max_gc: Option<f64>,
+    /// Write `samtools flagstat` output for the written reads.
+    pub flagstat_output: Option<PathBuf>,
+    /// Send matched records from the chunks to a single writer thread over
+    /// bounded channels instead of writing chunk BAMs and merging them. Saves
+    /// the temp space and the merge; chunks ahead of the writer stall, so it
+    /// pays off when the disk, not the CPU, is the bottleneck.
+    pub ordered_writer: bool,
+    /// Tag recording, on every written read, the index of the chunk that
+    /// wrote it (0 when streaming), to debug reads near chunk boundaries.
+    pub tag_chunk: Option<String>,
+    /// Keep only reads whose CIGAR has an insertion or deletion.
+    pub indel_only: bool,
+    /// Shortest insertion or deletion counted by `indel_only` (1 when unset).
+    pub min_indel_length: Option<u32>,
+    /// Stop writing once the output BAM reaches about this many bytes, for
+    /// making small fixtures. Runs single-threaded and streaming; the size is
+    /// only checked as whole BGZF blocks (up to 64 KiB) are flushed, so the
+    /// output ends near, but usually a little over, the cap.
+    pub max_output_bytes: Option<u64>,
+    /// htslib `key=value` format options applied to the input readers, e.g.
+    /// CRAM `required_fields` to skip decoding unused fields. The output is
+    /// always BAM, so CRAM encoding options have no effect.
+    pub htslib_options: Option<Vec<(String, String)>>,
+    /// Uppercase the written `bam_tag` value, after any substitution,
+    /// translation or template. Matching is unaffected; see `case_insensitive`.
+    pub uppercase_tag: bool,
+    /// Fail on a read carrying `bam_tag` more than once instead of warning
+    /// and using the first copy.
+    pub strict_tags: bool,
+    /// Fail if a barcode file holds fewer distinct barcodes than this, e.g.
+    /// a truncated whitelist download.
+    pub min_barcodes: Option<usize>,
+    /// Write the most frequent tag values that were not in the whitelist as a
+    /// `barcode<TAB>count` TSV, to spot a whitelist from the wrong chemistry.
+    pub unmatched_histogram: Option<PathBuf>,
+    /// Sort the output by read name and set `@HD SO:queryname
+    /// SS:queryname:lexicographical`, with the same external merge sort as
+    /// `sort_by_tag`. Names compare byte by byte, not in samtools' natural
+    /// order, so `read10` sorts before `read2`.
+    pub sort_by_name: bool,
+    /// Further `(tag, whitelist)` conditions on top of `bam_tag` and the
+    /// barcode file, e.g. `("BC", "sample_barcodes.txt")` for combinatorial
+    /// indexing. Each whitelist is matched like the barcode file, except that
+    /// `tag_field` only applies to `bam_tag`.
+    pub extra_tag_whitelists: Option<Vec<(String, PathBuf)>>,
+    /// Keep reads matching any of the tag conditions instead of all of them.
+    pub match_any_tag: bool,
+    /// Skip unmapped reads before any other work, without counting them as
+    /// scanned. On coordinate-sorted input a chunk stops at the first
+    /// unplaced read, so chunks over the unmapped reads at the end of the
+    /// file stop after their first record.
+    pub mapped_only: bool,
+    /// Keep only reads whose mate maps to a different contig, for
+    /// structural-variant and trans-contact work.
+    pub discordant_only: bool,
+    /// Also keep same-contig pairs under `discordant_only` whose absolute
+    /// insert size is at least this.
+    pub discordant_min_insert: Option<i64>,
+    /// Bring the mate fields and `MC`/`MQ` tags of paired reads in the output
+    /// in line with the mates that were kept, as `samtools fixmate` does.
+    /// Split, route and shard outputs are left as they are.
+    pub fixmate: bool,
 }
 
-pub fn merge_bams<P: AsRef<Path>>(tmp_bams: Vec<&PathBuf>, out_bam_file: P) {
-    use rust_htslib::bam::Read; // collides with fs::Read
-    let bam = bam::Reader::from_path(tmp_bams[0].clone()).unwrap();
-    let mut out_bam = load_writer(&bam, out_bam_file.as_ref()).unwrap();
-    for b in tmp_bams.iter() {
-        let mut rdr = bam::Reader::from_path(b).unwrap();
-        for _rec in rdr.records() {
-            let rec = _rec.unwrap();
-            out_bam.write(&rec).unwrap();
-        }
+/// Read budget of one barcode, shared by all chunks.
+pub struct BarcodeTarget {
+    pub target: u64,
+    pub kept: AtomicU64,
+}
+
+impl BarcodeTarget {
+    pub fn try_take(&self) -> bool {
+        self.kept
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                if n < self.target {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
     }
 }
 
-fn substitute_text_in_tag(
-    rec: &mut bam::Record,
-    bam_tag: &str,
-    to_replace: &str,
-    replacement: &str,
-) -> Result<(), rust_htslib::tpool::Error> {
-    let bam_tag_bytes = bam_tag.as_bytes();
-    let bc = get_record_tag(&rec, &bam_tag);
+pub struct SliceResult {
+    pub path: PathBuf,
+    pub reads_scanned: u64,
+    pub reads_written: u64,
+    /// Reads written per tid, with unplaced reads counted in the last slot.
+    pub tid_counts: Vec<u64>,
+    /// Per-barcode part files written by this chunk when splitting.
+    pub split_parts: HashMap<Vec<u8>, Vec<PathBuf>>,
+    pub split_counts: HashMap<Vec<u8>, u64>,
+    pub fastq_path: Option<PathBuf>,
+    pub elapsed_seconds: f64,
+    /// Seconds from the start of the run until the chunk started.
+    pub started_seconds: f64,
+    pub saturation: Option<Saturation>,
+    pub unmatched: Option<UnmatchedBarcodes>,
+    /// Records skipped because they failed to write.
+    pub write_errors: u64,
+    pub coverage: Option<Coverage>,
+    pub flagstat: Option<Flagstat>,
+    /// Part files per route (index, or `default`) or `shard_<k>` written by this chunk.
+    pub route_parts: HashMap<Vec<u8>, Vec<PathBuf>>,
+    pub route_counts: HashMap<Vec<u8>, u64>,
+}
 
-    match bc {
-        Some(b) => match rec.remove_aux(&bam_tag_bytes) {
-            Ok(res) => {
-                let new_tag = std::str::from_utf8(&b)
-                    .expect("Not UTF-8 formatted")
-                    .replace(to_replace, replacement);
-                rec.push_aux(&bam_tag_bytes, Aux::String(&new_tag)).unwrap();
-                Ok(())
-            }
+/// When a chunk ran, relative to the start of the run, and how many reads it handled.
+#[derive(Clone, Debug)]
+pub struct ChunkTiming {
+    pub start_seconds: f64,
+    pub end_seconds: f64,
+    pub reads_scanned: u64,
+    pub reads_written: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SubsampleStats {
+    pub output_path: PathBuf,
+    pub reads_scanned: u64,
+    pub reads_written: u64,
+    pub elapsed_seconds: f64,
+    pub chunks: usize,
+    pub chunk_seconds: Vec<f64>,
+    pub chunk_timings: Vec<ChunkTiming>,
+    /// Reads kept per barcode when per-barcode targets are used.
+    pub barcode_counts: Option<HashMap<Vec<u8>, u64>>,
+    /// Records written per split output (barcode or contig).
+    pub split_counts: Option<HashMap<Vec<u8>, u64>>,
+    /// Molecules in the output when collapsing molecules.
+    pub molecules: Option<u64>,
+    /// Records skipped under the write error policy.
+    pub skipped_records: u64,
+    /// Records written to each shard when sharding.
+    pub shard_counts: Option<Vec<u64>>,
+}
+
+pub fn is_gzip_file(path: &Path) -> Result<bool, Error> {
+    use std::io::Read;
+    let mut magic = [0; 2];
+    let n = fs::File::open(path)?.read(&mut magic)?;
+    Ok(n == 2 && magic == [0x1f, 0x8b])
+}
+
+pub fn is_zstd_file(path: &Path) -> Result<bool, Error> {
+    use std::io::Read;
+    let mut magic = [0; 4];
+    let n = fs::File::open(path)?.read(&mut magic)?;
+    Ok(n == 4 && magic == [0x28, 0xb5, 0x2f, 0xfd])
+}
+
+/// Opens a barcode list or map for reading line by line, decompressing it
+/// when it starts with the gzip magic. Zstd files are recognised and
+/// rejected with a clear error rather than read as garbage.
+pub fn open_text_input(path: &Path) -> Result<Box<dyn BufRead>, Error> {
+    if is_zstd_file(path)? {
+        bail!(
+            "{} is zstd-compressed, which is not supported; use gzip or decompress it first",
+            path.display()
+        );
+    }
+    let r = fs::File::open(path)?;
+    if is_gzip_file(path)? {
+        Ok(Box::new(BufReader::with_capacity(
+            32 * 1024,
+            MultiGzDecoder::new(r),
+        )))
+    } else {
+        Ok(Box::new(BufReader::with_capacity(32 * 1024, r)))
+    }
+}
 
-            Err(res) => Err(rust_htslib::tpool::Error::BamAuxTagNotFound),
+/// A barcode as the matcher sees it: without the `barcode_suffix_delimiter`
+/// suffix (e.g. Cell Ranger's `-1`), uppercased under `case_insensitive`.
+pub fn normalize_barcode(barcode: &[u8], opts: &SubsampleOptions) -> Vec<u8> {
+    let barcode = match opts.barcode_suffix_delimiter {
+        Some(delimiter) => match barcode.iter().rposition(|&c| c == delimiter) {
+            Some(pos) => &barcode[..pos],
+            None => barcode,
         },
+        None => barcode,
+    };
+    if opts.case_insensitive {
+        barcode.to_ascii_uppercase()
+    } else {
+        barcode.to_vec()
+    }
+}
 
-        None => Err(rust_htslib::tpool::Error::BamAuxTagNotFound),
+pub fn load_barcodes(
+    filename: impl AsRef<Path>,
+    opts: &SubsampleOptions,
+) -> Result<HashSet<Vec<u8>>, Error> {
+    // e.g. Cell Ranger's filtered_feature_bc_matrix/barcodes.tsv.gz
+    let reader = open_text_input(filename.as_ref())?;
+
+    let mut bc_set = HashSet::new();
+    let is_csv = filename
+        .as_ref()
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("csv"));
+    let skip = if opts.barcode_has_header { 1 } else { 0 };
+    let mut n_invalid = 0;
+    let mut first_invalid = None;
+
+    for (line_no, l) in reader.lines().enumerate().skip(skip) {
+        let l = l?;
+        // e.g. a trailing blank line, which is not a barcode
+        if l.trim().is_empty() {
+            continue;
+        }
+        let column = |col| {
+            if is_csv {
+                l.split(',').nth(col).map(|f| f.trim())
+            } else {
+                l.split_whitespace().nth(col)
+            }
+        };
+        let seq = match opts.barcode_column {
+            Some(col) => match column(col) {
+                Some(field) => field.as_bytes().to_vec(),
+                None => bail!(
+                    "Line {} of {} has no column {}",
+                    line_no + 1,
+                    filename.as_ref().display(),
+                    col
+                ),
+            },
+            None => l.into_bytes(),
+        };
+        let seq = normalize_barcode(&seq, opts);
+        if opts.validate_dna && !is_dna(&seq) {
+            n_invalid += 1;
+            first_invalid.get_or_insert(line_no + 1);
+        }
+        bc_set.insert(seq);
+    }
+    if let Some(line_no) = first_invalid {
+        bail!(
+            "{} barcodes in {} are not DNA (ACGTN), the first on line {}; is this the right file?",
+            n_invalid,
+            filename.as_ref().display(),
+            line_no
+        );
     }
+    let num_bcs = bc_set.len();
+    if num_bcs == 0 {
+        bail!(
+            "Loaded 0 barcodes from {}. Is your barcode file empty?",
+            filename.as_ref().display()
+        );
+    }
+    if let Some(min_barcodes) = opts.min_barcodes {
+        if num_bcs < min_barcodes {
+            bail!(
+                "Loaded {} barcodes from {}, fewer than min_barcodes ({}); is the file truncated?",
+                num_bcs,
+                filename.as_ref().display(),
+                min_barcodes
+            );
+        }
+    }
+    check_barcode_lengths(&bc_set, opts.strict_barcode_lengths)?;
+    debug!("Loaded {} barcodes", num_bcs);
+    Ok(bc_set)
 }
 
-pub fn subsample_bam<P: AsRef<Path>>(
-    bam_file: P,
-    barcodes_file: P,
-    bam_tag: String,
-    to_replace: Option<String>,
-    replacement: Option<String>,
-    out_bam_file: P,
-    cores: usize,
-) -> Result<PathBuf, Error> {
+fn is_dna(seq: &[u8]) -> bool {
+    !seq.is_empty()
+        && seq
+            .iter()
+            .all(|b| matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+}
 
-    let _ = SimpleLogger::init(LevelFilter::Info, Config::default());
+/// Seeded 64-bit FNV-1a of `bytes`, finished with the splitmix64 mixer so the
+/// high and low bits are both usable. Unlike `DefaultHasher` it is the same in
+/// every build and Rust version, so samples and shards can be reproduced.
+pub fn stable_hash(bytes: &[u8], seed: u64) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
 
-    let cell_barcodes = load_barcodes(&barcodes_file).unwrap();
-    let tmp_dir = tempdir().unwrap();
-    let virtual_offsets = bgzf_noffsets(&bam_file, &(cores as u64)).unwrap();
+/// Picks `n` barcodes by their seeded hash, so a seed always picks the same subset.
+pub fn sample_barcodes(cell_barcodes: HashSet<Vec<u8>>, n: usize, seed: u64) -> Vec<Vec<u8>> {
+    let mut ranked: Vec<_> = cell_barcodes
+        .into_iter()
+        .map(|bc| (stable_hash(&bc, seed), bc))
+        .collect();
+    ranked.sort_unstable();
+    ranked.truncate(n);
+    let mut sampled: Vec<_> = ranked.into_iter().map(|(_, bc)| bc).collect();
+    sampled.sort();
+    sampled
+}
 
-    let mut chunks = Vec::new();
+/// Counts of each barcode length, e.g. `1 of length 4, 2 of length 6`, when
+/// the barcodes do not all have the same length.
+fn mixed_length_summary(cell_barcodes: &HashSet<Vec<u8>>) -> Option<String> {
+    let mut lengths: HashMap<usize, usize> = HashMap::new();
+    for bc in cell_barcodes {
+        *lengths.entry(bc.len()).or_insert(0) += 1;
+    }
+    if lengths.len() <= 1 {
+        return None;
+    }
 
-    for (i, (virtual_start, virtual_stop)) in virtual_offsets.iter().enumerate() {
-        let c = SliceArgs {
-            cell_barcodes: &cell_barcodes,
-            i: i,
-            bam_file: &bam_file.as_ref(),
-            tmp_dir: tmp_dir.path(),
-            bam_tag: bam_tag.clone(),
-            virtual_start: *virtual_start,
-            virtual_stop: *virtual_stop,
-            to_replace: to_replace.clone(),
-            replacement: replacement.clone(),
-        };
-        chunks.push(c);
-    }
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(cores as usize)
-        .build()
-        .unwrap();
+    let mut lengths: Vec<_> = lengths.into_iter().collect();
+    lengths.sort();
+    Some(
+        lengths
+            .iter()
+            .map(|(len, n)| format!("{} of length {}", n, len))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
 
-    let results: Vec<_> = pool.install(|| {
-        chunks
-            .par_iter()
-            .map(|chunk| read_bam_slice(chunk))
-            .collect()
-    });
+pub fn check_barcode_lengths(cell_barcodes: &HashSet<Vec<u8>>, strict: bool) -> Result<(), Error> {
+    let summary = match mixed_length_summary(cell_barcodes) {
+        Some(summary) => summary,
+        None => return Ok(()),
+    };
+    if strict {
+        bail!("Barcodes have mixed lengths: {}", summary);
+    }
+    warn!(
+        "Barcodes have mixed lengths ({}); the barcode file may be malformed",
+        summary
+    );
+    Ok(())
+}
+
+pub fn build_matcher(
+    cell_barcodes: HashSet<Vec<u8>>,
+    opts: &SubsampleOptions,
+) -> Box<dyn BarcodeMatcher> {
+    let matcher: Box<dyn BarcodeMatcher> = if opts.barcode_mismatches > 0 {
+        Box::new(MismatchMatcher::new(cell_barcodes, opts.barcode_mismatches))
+    } else {
+        Box::new(ExactMatcher::new(cell_barcodes))
+    };
+    match opts.barcode_suffix_delimiter {
+        Some(delimiter) => Box::new(SuffixStripMatcher::new(matcher, delimiter)),
+        None => matcher,
+    }
+}
+
+/// Loads `barcode<TAB>target` read targets, with barcodes normalised like the
+/// whitelist so they meet record tags the same way.
+pub fn load_barcode_targets(
+    filename: impl AsRef<Path>,
+    opts: &SubsampleOptions,
+) -> Result<HashMap<Vec<u8>, BarcodeTarget>, Error> {
+    let reader = BufReader::new(fs::File::open(filename.as_ref())?);
+    let mut targets = HashMap::new();
+
+    for (line_no, l) in reader.lines().enumerate() {
+        let l = l?;
+        if l.is_empty() {
+            continue;
+        }
+        let mut fields = l.split('\t');
+        let target = match (
+            fields.next(),
+            fields.next().map(|t| t.trim().parse::<u64>()),
+        ) {
+            (Some(barcode), Some(Ok(target))) => {
+                (normalize_barcode(barcode.as_bytes(), opts), target)
+            }
+            _ => bail!(
+                "Line {} of {} is not a barcode<TAB>target pair",
+                line_no + 1,
+                filename.as_ref().display()
+            ),
+        };
+        targets.insert(
+            target.0,
+            BarcodeTarget {
+                target: target.1,
+                kept: AtomicU64::new(0),
+            },
+        );
+    }
+    debug!("Loaded read targets for {} barcodes", targets.len());
+    Ok(targets)
+}
+
+/// Loads `from<TAB>to` barcode translations, decompressing gzipped maps.
+///
+/// Maps can run to millions of lines, so each line is parsed as it is read
+/// rather than collecting the file first.
+pub fn load_translation_map(
+    filename: impl AsRef<Path>,
+) -> Result<HashMap<Vec<u8>, Vec<u8>>, Error> {
+    let filename = filename.as_ref();
+    let mut translation = HashMap::new();
+    for (line_no, l) in open_text_input(filename)?.lines().enumerate() {
+        let l = l?;
+        if l.is_empty() {
+            continue;
+        }
+        let mut fields = l.split('\t');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(from), Some(to), None) => {
+                translation.insert(from.as_bytes().to_vec(), to.as_bytes().to_vec());
+            }
+            _ => bail!(
+                "Line {} of {} is not a from<TAB>to pair",
+                line_no + 1,
+                filename.display()
+            ),
+        }
+    }
+    debug!("Loaded {} barcode translations", translation.len());
+    Ok(translation)
+}
 
-    let tmp_bams: Vec<_> = results.iter().map(|r| r.as_ref().unwrap()).collect();
-    merge_bams(tmp_bams, &out_bam_file);
+/// Decimal elements of an integer array tag joined by commas, e.g. `1,2,3`.
+fn join_array<T: ToString, I: Iterator<Item = T>>(values: I) -> Vec<u8> {
+    values
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+        .into_bytes()
+}
 
-    Ok(PathBuf::from(&out_bam_file.as_ref()))
+/// Value of `bam_tag` as bytes to match against the whitelist.
+///
+/// String tags are used as they are. `B:C`/`B:c` (8-bit) array tags are taken
+/// as the raw bytes, so an array of ASCII codes reads as the barcode it spells;
+/// wider integer arrays become their comma-joined decimal elements, e.g.
+/// `B:S,1,2,3` reads as `1,2,3`. Other tag types are ignored.
+pub fn get_record_tag<'a>(rec: &'a Record, bam_tag: &str) -> Option<Vec<u8>> {
+    let tag = rec.aux(bam_tag.as_bytes());
+    match tag {
+        Ok(t) => match t {
+            Aux::String(t) => Some(t.as_bytes().to_vec()),
+            Aux::ArrayU8(a) => Some(a.iter().collect()),
+            Aux::ArrayI8(a) => Some(a.iter().map(|v| v as u8).collect()),
+            Aux::ArrayU16(a) => Some(join_array(a.iter())),
+            Aux::ArrayI16(a) => Some(join_array(a.iter())),
+            Aux::ArrayU32(a) => Some(join_array(a.iter())),
+            Aux::ArrayI32(a) => Some(join_array(a.iter())),
+            _ => None,
+        },
+        Err(t) => None,
+    }
+}
+
+/// Field `field` of a `delimiter`-separated tag value, e.g. `AACCGG` from `sampleA:AACCGG:extra`.
+pub fn extract_tag_field(tag: &[u8], delimiter: char, field: usize) -> Option<Vec<u8>> {
+    std::str::from_utf8(tag)
+        .ok()?
+        .split(delimiter)
+        .nth(field)
+        .map(|f| f.as_bytes().to_vec())
+}
+
+pub fn passes_tlen_filter(rec: &Record, opts: &SubsampleOptions) -> bool {
+    if opts.min_tlen.is_none() && opts.max_tlen.is_none() {
+        return true;
+    }
+    // insert size is only meaningful for properly-paired reads
+    if !rec.is_proper_pair() {
+        return opts.tlen_keep_unpaired;
+    }
+    let tlen = rec.insert_size().abs();
+    opts.min_tlen.map_or(true, |min| tlen >= min) && opts.max_tlen.map_or(true, |max| tlen <= max)
+}
+
+pub fn passes_strand_filter(rec: &Record, opts: &SubsampleOptions) -> bool {
+    let strand = match opts.strand {
+        Some(strand) => strand,
+        None => return true,
+    };
+    // read 2 of a pair carries read 1's orientation in its mate flag
+    let is_reverse = if opts.strand_from_read1 && rec.is_paired() && rec.is_last_in_template() {
+        rec.is_mate_reverse()
+    } else {
+        rec.is_reverse()
+    };
+    match strand {
+        Strand::Forward => !is_reverse,
+        Strand::Reverse => is_reverse,
+    }
+}
+
+pub fn aligned_length(rec: &Record) -> usize {
+    rec.cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) => *n as usize,
+            _ => 0,
+        })
+        .sum()
+}
+
+pub fn passes_filters(rec: &Record, opts: &SubsampleOptions) -> bool {
+    if !passes_tlen_filter(rec, opts) {
+        return false;
+    }
+    if let Some(min_len) = opts.min_aligned_length {
+        if aligned_length(rec) < min_len {
+            return false;
+        }
+    }
+    if !passes_strand_filter(rec, opts) {
+        return false;
+    }
+    if opts.remove_duplicates && rec.is_duplicate() {
+        return false;
+    }
+    if !passes_mate_filter(rec, opts) {
+        return false;
+    }
+    if let Some(fraction) = opts.subsample_fraction {
+        if !keep_by_name_hash(rec.qname(), opts.subsample_seed, fraction) {
+            return false;
+        }
+    }
+    if opts.indel_only && !has_indel(rec, opts.min_indel_length.unwrap_or(1)) {
+        return false;
+    }
+    if opts.min_gc.is_some() || opts.max_gc.is_some() {
+        if let Some(gc) = gc_content(rec) {
+            if opts.min_gc.map_or(false, |min| gc < min)
+                || opts.max_gc.map_or(false, |max| gc > max)
+            {
+                return false;
+            }
+        }
+    }
+    if opts.discordant_only && !is_discordant(rec, opts.discordant_min_insert) {
+        return false;
+    }
+    // an unaligned BAM holds each read once
+    if opts.strip_alignment && (rec.is_secondary() || rec.is_supplementary()) {
+        return false;
+    }
+    true
+}
+
+/// Whether both mates are mapped but to different contigs or, when
+/// `min_insert` is given, at least that far apart on the same contig.
+pub fn is_discordant(rec: &Record, min_insert: Option<i64>) -> bool {
+    if !rec.is_paired() || rec.is_unmapped() || rec.is_mate_unmapped() {
+        return false;
+    }
+    rec.tid() != rec.mtid() || min_insert.map_or(false, |min| rec.insert_size().abs() >= min)
+}
+
+/// Whether the CIGAR has an insertion or deletion of at least `min_length` bases.
+pub fn has_indel(rec: &Record, min_length: u32) -> bool {
+    rec.cigar().iter().any(|op| match op {
+        Cigar::Ins(n) | Cigar::Del(n) => *n >= min_length,
+        _ => false,
+    })
+}
+
+/// GC fraction of the read's called bases; `N`s are left out of the
+/// denominator, so a read of only `N`s has none.
+pub fn gc_content(rec: &Record) -> Option<f64> {
+    let mut gc = 0;
+    let mut called = 0;
+    for base in rec.seq().as_bytes() {
+        match base.to_ascii_uppercase() {
+            b'G' | b'C' => {
+                gc += 1;
+                called += 1;
+            }
+            b'N' => {}
+            _ => called += 1,
+        }
+    }
+    if called == 0 {
+        None
+    } else {
+        Some(gc as f64 / called as f64)
+    }
+}
+
+/// The `samtools view -s` keep decision: the qname's X31 hash, mixed with the
+/// seed by Wang's integer hash, must fall below the fraction. Mates share a
+/// name, so they are kept or dropped together.
+pub fn keep_by_name_hash(qname: &[u8], seed: u32, fraction: f64) -> bool {
+    let mut h: u32 = 0;
+    for &c in qname {
+        h = (h << 5).wrapping_sub(h).wrapping_add(c as u32);
+    }
+    let mut k = h ^ seed;
+    k = k.wrapping_add(!(k << 15));
+    k ^= k >> 10;
+    k = k.wrapping_add(k << 3);
+    k ^= k >> 6;
+    k = k.wrapping_add(!(k << 11));
+    k ^= k >> 16;
+    ((k & 0xffffff) as f64 / 0x1000000 as f64) < fraction
+}
+
+pub fn passes_mate_filter(rec: &Record, opts: &SubsampleOptions) -> bool {
+    match opts.mate {
+        None => true,
+        Some(_) if !rec.is_paired() => opts.mate_keep_unpaired,
+        Some(Mate::Read1) => rec.is_first_in_template(),
+        Some(Mate::Read2) => rec.is_last_in_template(),
+    }
+}
+
+pub fn trim_softclips(rec: &mut Record) {
+    let ops: Vec<Cigar> = rec.cigar().iter().cloned().collect();
+
+    // soft clips sit inside any hard clips at either end of the cigar
+    let leading = ops
+        .iter()
+        .take_while(|op| matches!(op, Cigar::HardClip(_) | Cigar::SoftClip(_)))
+        .filter_map(|op| match op {
+            Cigar::SoftClip(n) => Some(*n as usize),
+            _ => None,
+        })
+        .sum::<usize>();
+    let trailing = ops
+        .iter()
+        .rev()
+        .take_while(|op| matches!(op, Cigar::HardClip(_) | Cigar::SoftClip(_)))
+        .filter_map(|op| match op {
+            Cigar::SoftClip(n) => Some(*n as usize),
+            _ => None,
+        })
+        .sum::<usize>();
+
+    if leading == 0 && trailing == 0 {
+        return;
+    }
+
+    let seq = rec.seq().as_bytes();
+    // a read that is all clip has nothing left to keep, so it stays as it is
+    if leading + trailing >= seq.len() {
+        return;
+    }
+    let qual = rec.qual().to_vec();
+    let qname = rec.qname().to_vec();
+    let end = seq.len() - trailing;
+    let cigar = CigarString(
+        ops.into_iter()
+            .filter(|op| !matches!(op, Cigar::SoftClip(_)))
+            .collect(),
+    );
+
+    // soft clips do not consume the reference, so the position is unchanged
+    rec.set(
+        &qname,
+        Some(&cigar),
+        &seq[leading..end],
+        &qual[leading..end],
+    );
+}
+
+/// Turns `rec` into an unmapped read as `samtools reset` does, keeping the
+/// original read sequence, qualities and aux tags.
+/// Aux tags that describe an alignment, and mean nothing once it is stripped.
+const ALIGNMENT_TAGS: [&[u8]; 10] = [
+    b"NM", b"MD", b"AS", b"XS", b"XA", b"SA", b"MC", b"MQ", b"NH", b"HI",
+];
+
+/// Turns `rec` into an unaligned read, as `samtools reset` does: the original
+/// sequence and qualities with every other tag, and only the pairing, QC fail
+/// and duplicate flags. Secondary and supplementary reads are dropped by
+/// `passes_filters` before they get here.
+pub fn strip_alignment(rec: &mut Record) -> Result<(), Error> {
+    let qname = rec.qname().to_vec();
+    let mut seq = rec.seq().as_bytes();
+    let mut qual = rec.qual().to_vec();
+    // reverse-strand reads are stored reverse complemented
+    if rec.is_reverse() {
+        seq = revcomp(&seq);
+        qual.reverse();
+    }
+    rec.set(&qname, None, &seq, &qual);
+    rec.set_tid(-1);
+    rec.set_pos(-1);
+    rec.set_mtid(-1);
+    rec.set_mpos(-1);
+    rec.set_mapq(0);
+    rec.set_insert_size(0);
+
+    // keep paired, read 1/2, QC fail and duplicate; mark the read (and any mate) unmapped
+    let mut flags = rec.flags() & (0x1 | 0x40 | 0x80 | 0x200 | 0x400) | 0x4;
+    if rec.is_paired() {
+        flags |= 0x8;
+    }
+    rec.set_flags(flags);
+    for tag in ALIGNMENT_TAGS.iter() {
+        while rec.aux(tag).is_ok() {
+            rec.remove_aux(tag)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn mask_low_quality(rec: &mut Record, min_qual: u8) {
+    let qual = rec.qual().to_vec();
+    if !qual.iter().any(|&q| q < min_qual) {
+        return;
+    }
+    let seq: Vec<u8> = rec
+        .seq()
+        .as_bytes()
+        .iter()
+        .zip(&qual)
+        .map(|(&base, &q)| if q < min_qual { b'N' } else { base })
+        .collect();
+    let qname = rec.qname().to_vec();
+    let cigar = rec.cigar().take();
+    rec.set(&qname, Some(&cigar), &seq, &qual);
+}
+
+/// Removes every aux tag not named in `keep`.
+pub fn retain_tags(rec: &mut Record, keep: &[String]) -> Result<(), Error> {
+    let drop: Vec<Vec<u8>> = rec
+        .aux_iter()
+        .filter_map(|aux| aux.ok())
+        .map(|(tag, _)| tag.to_vec())
+        .filter(|tag| !keep.iter().any(|k| k.as_bytes() == tag.as_slice()))
+        .collect();
+    for tag in drop {
+        rec.remove_aux(&tag)?;
+    }
+    Ok(())
+}
+
+/// Removes the aux tags named in `strip`, leaving all others.
+pub fn strip_tags(rec: &mut Record, strip: &[String]) -> Result<(), Error> {
+    for tag in strip {
+        if rec.aux(tag.as_bytes()).is_ok() {
+            rec.remove_aux(tag.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn load_header_template(
+    template: &Path,
+    bam_header: &bam::HeaderView,
+) -> Result<bam::Header, Error> {
+    let text = fs::read(template)?;
+    let template_hdr = bam::HeaderView::from_bytes(&text);
+
+    // records keep their tids, so the references must line up one-to-one
+    if template_hdr.target_count() != bam_header.target_count() {
+        bail!(
+            "Header template {} has {} @SQ lines but the input BAM has {}",
+            template.display(),
+            template_hdr.target_count(),
+            bam_header.target_count()
+        );
+    }
+    for tid in 0..bam_header.target_count() {
+        if template_hdr.target_len(tid) != bam_header.target_len(tid) {
+            bail!(
+                "Header template {} @SQ {} ({}) has length {:?} but input reference {} has length {:?}",
+                template.display(),
+                tid,
+                String::from_utf8_lossy(template_hdr.tid2name(tid)),
+                template_hdr.target_len(tid),
+                String::from_utf8_lossy(bam_header.tid2name(tid)),
+                bam_header.target_len(tid)
+            );
+        }
+    }
+    Ok(bam::Header::from_template(&template_hdr))
+}
+
+pub fn with_sort_order(header: &bam::Header, sort_order: &str) -> bam::Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let mut lines: Vec<String> = Vec::new();
+    let mut has_hd = false;
+
+    for line in text.lines() {
+        if line.starts_with("@HD") {
+            has_hd = true;
+            let mut fields: Vec<String> = line
+                .split('\t')
+                .filter(|f| !f.starts_with("SO:"))
+                .map(|f| f.to_string())
+                .collect();
+            fields.push(format!("SO:{}", sort_order));
+            lines.push(fields.join("\t"));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    if !has_hd {
+        lines.insert(0, format!("@HD\tVN:1.6\tSO:{}", sort_order));
+    }
+
+    let text = lines.join("\n") + "\n";
+    bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()))
+}
+
+/// Replaces the `SS` sub-sort field of the `@HD` line, which `with_sort_order`
+/// has already added.
+fn with_sub_sort_order(header: &bam::Header, sub_sort: &str) -> bam::Header {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            if !line.starts_with("@HD") {
+                return line.to_string();
+            }
+            let mut fields: Vec<&str> =
+                line.split('\t').filter(|f| !f.starts_with("SS:")).collect();
+            let ss = format!("SS:{}", sub_sort);
+            fields.push(&ss);
+            fields.join("\t")
+        })
+        .collect();
+    let text = lines.join("\n") + "\n";
+    bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()))
+}
+
+pub fn load_writer(
+    bam: &bam::Reader,
+    out_bam_path: &Path,
+    header_template: Option<&Path>,
+    sort_order: Option<&str>,
+) -> Result<bam::Writer, Error> {
+    use rust_htslib::bam::Read; // collides with fs::Read
+    let hdr = match header_template {
+        Some(template) => load_header_template(template, bam.header())?,
+        None => rust_htslib::bam::Header::from_template(bam.header()),
+    };
+    let hdr = match sort_order {
+        Some(sort_order) => with_sort_order(&hdr, sort_order),
+        None => hdr,
+    };
+    let out_handle = bam::Writer::from_path(out_bam_path, &hdr, bam::Format::Bam)?;
+    Ok(out_handle)
+}
+
+pub fn bgzf_noffsets<P: AsRef<Path>>(
+    bam_path: &P,
+    num_chunks: &u64,
+) -> Result<Vec<(Option<i64>, Option<i64>)>, Error> {
+    use std::io::Read;
+
+    fn vec_diff(input: &Vec<u64>) -> Vec<u64> {
+        let vals = input.iter();
+        let next_vals = input.iter().skip(1);
+
+        vals.zip(next_vals).map(|(cur, next)| next - cur).collect()
+    }
+
+    // if we only have one thread, this is easy
+    if *num_chunks == 1 as u64 {
+        let final_offsets = vec![(None, None)];
+        return Ok(final_offsets);
+    }
+
+    let bam_bytes = fs::metadata(bam_path)?.len();
+    let mut initial_offsets = Vec::new();
+    let step_size = bam_bytes / num_chunks;
+    for n in 1..*num_chunks {
+        initial_offsets.push((step_size * n) as u64);
+    }
+
+    let num_bytes = if initial_offsets.len() > 1 {
+        let diff = vec_diff(&initial_offsets);
+        let m = diff.iter().max().unwrap();
+        cmp::min(1 << 16, *m)
+    } else {
+        1 << 16
+    };
+
+    // linear search to the right of each possible offset until
+    // a valid virtual offset is found
+    let mut adjusted_offsets = Vec::new();
+    let mut fp = fs::File::open(bam_path)?;
+    for offset in initial_offsets {
+        fp.seek(io::SeekFrom::Start(offset))?;
+        let mut buffer = [0; 2 << 16];
+        fp.read(&mut buffer)?;
+        for i in 0..num_bytes {
+            if is_valid_bgzf_block(&buffer[i as usize..]) {
+                adjusted_offsets.push(offset + i);
+                break;
+            }
+        }
+    }
+    // a boundary inside the header blocks would start a chunk in the header,
+    // and windows that found the same block give the same boundary
+    let header_block = (bam::Reader::from_path(bam_path)?.tell() >> 16) as u64;
+    let mut boundaries: Vec<i64> = adjusted_offsets
+        .into_iter()
+        .filter(|offset| *offset > header_block)
+        .map(|offset| (offset as i64) << 16)
+        .collect();
+    boundaries.dedup();
+
+    // handle special case where we found no usable offset
+    if boundaries.is_empty() {
+        warn!(
+            "{} is too small or too dense to split into {} chunks; using 1 chunk",
+            bam_path.as_ref().display(),
+            num_chunks
+        );
+        return Ok(vec![(None, None)]);
+    }
+
+    // bit-shifted start/stop intervals
+    let mut final_offsets = vec![(None, Some(boundaries[0]))];
+    for pair in boundaries.windows(2) {
+        final_offsets.push((Some(pair[0]), Some(pair[1])));
+    }
+    final_offsets.push((Some(boundaries[boundaries.len() - 1]), None));
+    if (final_offsets.len() as u64) < *num_chunks {
+        warn!(
+            "Split {} into {} chunks of the {} requested",
+            bam_path.as_ref().display(),
+            final_offsets.len(),
+            num_chunks
+        );
+    }
+    Ok(final_offsets)
+}
+
+pub fn is_valid_bgzf_block(block: &[u8]) -> bool {
+    // look for the bgzip magic characters \x1f\x8b\x08\x04
+    // TODO: is this sufficient?
+    if block.len() < 18 {
+        return false;
+    }
+    if (block[0] != 31) | (block[1] != 139) | (block[2] != 8) | (block[3] != 4) {
+        return false;
+    }
+    true
+}
+
+pub fn chunk_key(
+    bam_file: &Path,
+    virtual_start: Option<i64>,
+    virtual_stop: Option<i64>,
+    barcodes_hash: u64,
+    options_hash: u64,
+) -> String {
+    // DefaultHasher is only stable within a build, which is enough to resume
+    let mut hasher = DefaultHasher::new();
+    bam_file.hash(&mut hasher);
+    virtual_start.hash(&mut hasher);
+    virtual_stop.hash(&mut hasher);
+    barcodes_hash.hash(&mut hasher);
+    options_hash.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+pub fn hash_barcodes(cell_barcodes: &HashSet<Vec<u8>>) -> u64 {
+    let mut sorted: Vec<_> = cell_barcodes.iter().collect();
+    sorted.sort();
+    let mut hasher = DefaultHasher::new();
+    sorted.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Part files written by a chunk for one split, route or shard key.
+#[derive(Serialize, Deserialize)]
+struct ManifestOutput {
+    key: String,
+    parts: Vec<PathBuf>,
+    count: u64,
+}
+
+/// JSON marker of a completed chunk: its counts and the part files of every
+/// extra output, so a resumed run can reuse them instead of rescanning.
+#[derive(Serialize, Deserialize)]
+struct ChunkManifest {
+    reads_scanned: u64,
+    reads_written: u64,
+    tid_counts: Vec<u64>,
+    split: Vec<ManifestOutput>,
+    routes: Vec<ManifestOutput>,
+}
+
+fn manifest_outputs(
+    parts: &HashMap<Vec<u8>, Vec<PathBuf>>,
+    counts: &HashMap<Vec<u8>, u64>,
+) -> Vec<ManifestOutput> {
+    parts
+        .iter()
+        .map(|(key, parts)| ManifestOutput {
+            key: String::from_utf8_lossy(key).into_owned(),
+            parts: parts.clone(),
+            count: counts.get(key).copied().unwrap_or(0),
+        })
+        .collect()
+}
+
+/// Part files and counts per key, or `None` if a part is gone (e.g. it was
+/// deleted by hand), in which case the chunk has to run again.
+fn outputs_from_manifest(
+    outputs: Vec<ManifestOutput>,
+) -> Option<(HashMap<Vec<u8>, Vec<PathBuf>>, HashMap<Vec<u8>, u64>)> {
+    let mut parts = HashMap::new();
+    let mut counts = HashMap::new();
+    for output in outputs {
+        if output.parts.iter().any(|p| !p.exists()) {
+            return None;
+        }
+        let key = output.key.into_bytes();
+        counts.insert(key.clone(), output.count);
+        parts.insert(key, output.parts);
+    }
+    Some((parts, counts))
+}
+
+fn write_chunk_marker(marker: &Path, result: &SliceResult) -> Result<(), Error> {
+    let manifest = ChunkManifest {
+        reads_scanned: result.reads_scanned,
+        reads_written: result.reads_written,
+        tid_counts: result.tid_counts.clone(),
+        split: manifest_outputs(&result.split_parts, &result.split_counts),
+        routes: manifest_outputs(&result.route_parts, &result.route_counts),
+    };
+    // a marker only exists once it is complete
+    let tmp_marker = marker.with_extension("done.tmp");
+    fs::write(&tmp_marker, serde_json::to_vec_pretty(&manifest)?)?;
+    fs::rename(&tmp_marker, marker)?;
+    Ok(())
+}
+
+fn load_chunk_marker(
+    marker: &Path,
+    out_bam_file: &Path,
+    fastq_path: &Option<PathBuf>,
+) -> Option<SliceResult> {
+    if !out_bam_file.exists() || fastq_path.as_ref().map_or(false, |p| !p.exists()) {
+        return None;
+    }
+    let manifest: ChunkManifest = serde_json::from_slice(&fs::read(marker).ok()?).ok()?;
+    let (split_parts, split_counts) = outputs_from_manifest(manifest.split)?;
+    let (route_parts, route_counts) = outputs_from_manifest(manifest.routes)?;
+    Some(SliceResult {
+        path: out_bam_file.to_path_buf(),
+        reads_scanned: manifest.reads_scanned,
+        reads_written: manifest.reads_written,
+        tid_counts: manifest.tid_counts,
+        split_parts,
+        split_counts,
+        fastq_path: fastq_path.clone(),
+        elapsed_seconds: 0.0,
+        started_seconds: 0.0,
+        saturation: None,
+        unmatched: None,
+        write_errors: 0,
+        coverage: None,
+        flagstat: None,
+        route_parts,
+        route_counts,
+    })
+}
+
+/// What `RecordPipeline::scan` decided about a record.
+enum Scan {
+    /// Counted as scanned; go on to select it.
+    Keep,
+    /// Not counted, e.g. an unmapped read under `mapped_only`.
+    Skip,
+    /// `head` is reached, or the unplaced reads under `mapped_only` begin.
+    Stop,
+}
+
+/// The per-record work shared by the chunked and streaming paths: `head` and
+/// `mapped_only`, selecting and rewriting, and the counts and side outputs of
+/// every record written. Writing the main output is left to the caller.
+struct RecordPipeline<'a> {
+    selector: &'a RecordSelector<'a>,
+    routes: &'a [Regex],
+    chunk: usize,
+    progress: &'a AtomicU64,
+    scanned_in_input: u64,
+    reads_scanned: u64,
+    reads_written: u64,
+    write_errors: u64,
+    duplicate_tags: u64,
+    tid_counts: Vec<u64>,
+    coverage: Option<Coverage>,
+    flagstat: Option<Flagstat>,
+    saturation: Option<Saturation>,
+    unmatched: Option<UnmatchedBarcodes>,
+    split_writers: Option<WriterCache>,
+    route_writers: Option<WriterCache>,
+    target_names: Vec<Vec<u8>>,
+    fastq_out: Option<MaybeGzWriter>,
+    interleaver: Option<MateInterleaver>,
+    sort_check: Option<SortCheck>,
+}
+
+impl<'a> RecordPipeline<'a> {
+    /// A pipeline for records under `header`. Split, route and FASTQ outputs
+    /// are opened by the caller, which knows where its parts go.
+    fn new(
+        selector: &'a RecordSelector<'a>,
+        routes: &'a [Regex],
+        chunk: usize,
+        progress: &'a AtomicU64,
+        header: &bam::HeaderView,
+    ) -> Self {
+        let opts = selector.opts;
+        let target_names = if opts.split_by_chrom {
+            header.target_names().iter().map(|n| n.to_vec()).collect()
+        } else {
+            Vec::new()
+        };
+        RecordPipeline {
+            selector,
+            routes,
+            chunk,
+            progress,
+            scanned_in_input: 0,
+            reads_scanned: 0,
+            reads_written: 0,
+            write_errors: 0,
+            duplicate_tags: 0,
+            tid_counts: vec![0; header.target_count() as usize + 1],
+            coverage: opts.coverage_bed.as_ref().map(|_| Coverage::default()),
+            flagstat: opts.flagstat_output.as_ref().map(|_| Flagstat::default()),
+            saturation: opts
+                .saturation_output
+                .as_ref()
+                .map(|_| Saturation::default()),
+            unmatched: opts
+                .unmatched_histogram
+                .as_ref()
+                .map(|_| UnmatchedBarcodes::default()),
+            split_writers: None,
+            route_writers: None,
+            target_names,
+            fastq_out: None,
+            interleaver: if opts.interleave {
+                Some(MateInterleaver::default())
+            } else {
+                None
+            },
+            sort_check: if opts.assert_sorted {
+                Some(SortCheck::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Starts counting `head` afresh, as it applies per input.
+    fn start_input(&mut self) {
+        self.scanned_in_input = 0;
+    }
+
+    /// Applies `head` and `mapped_only`, counting the record as scanned unless
+    /// it is skipped.
+    fn scan(&mut self, rec: &Record, stop_at_unplaced: bool) -> Scan {
+        let opts = self.selector.opts;
+        if opts.head.map_or(false, |n| self.scanned_in_input >= n) {
+            return Scan::Stop;
+        }
+        if opts.mapped_only && rec.is_unmapped() {
+            return if stop_at_unplaced && rec.tid() < 0 {
+                Scan::Stop
+            } else {
+                Scan::Skip
+            };
+        }
+        self.scanned_in_input += 1;
+        self.reads_scanned += 1;
+        if self.reads_scanned % PROGRESS_BATCH == 0 {
+            self.progress.fetch_add(PROGRESS_BATCH, Ordering::Relaxed);
+        }
+        Scan::Keep
+    }
+
+    /// Selects and rewrites a scanned record, returning its tag, or `None` when
+    /// it is dropped.
+    fn select(&mut self, rec: &mut Record) -> Result<Option<Option<Vec<u8>>>, Error> {
+        let selector = self.selector;
+        self.duplicate_tags += selector.check_tag(rec)? as u64;
+        let tag = match selector.select_counting(rec, self.unmatched.as_mut())? {
+            Some(tag) => tag,
+            None => return Ok(None),
+        };
+        if let (Some(sat), Some(umi_tag), Some(tag)) =
+            (self.saturation.as_mut(), &selector.opts.umi_tag, &tag)
+        {
+            if let Some(umi) = get_record_tag(rec, umi_tag) {
+                let key = observation_key(tag, &umi, rec.tid(), rec.pos());
+                sat.add(rec.qname(), key, selector.opts.seed);
+            }
+        }
+        selector.rewrite(rec, &tag, self.chunk)?;
+        Ok(Some(tag))
+    }
+
+    /// Counts a record the caller wrote to the main output, and writes it to
+    /// the side outputs.
+    fn written(&mut self, rec: &Record, tag: &Option<Vec<u8>>) -> Result<(), Error> {
+        let opts = self.selector.opts;
+        if let Some(sort_check) = self.sort_check.as_mut() {
+            sort_check.check(rec, format!("chunk {}", self.chunk))?;
+        }
+        self.reads_written += 1;
+        let tid_idx = if rec.tid() < 0 {
+            self.tid_counts.len() - 1
+        } else {
+            rec.tid() as usize
+        };
+        self.tid_counts[tid_idx] += 1;
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.add_record(rec);
+        }
+        if let Some(flagstat) = self.flagstat.as_mut() {
+            flagstat.add_record(rec);
+        }
+
+        if let Some(cache) = self.split_writers.as_mut() {
+            if opts.split_by_chrom {
+                let key: &[u8] = if rec.tid() < 0 {
+                    b"unmapped"
+                } else {
+                    &self.target_names[rec.tid() as usize]
+                };
+                cache.write(key, rec)?;
+            } else if let Some(tag) = tag {
+                cache.write(tag, rec)?;
+            }
+        }
+        if let Some(cache) = self.route_writers.as_mut() {
+            if !self.routes.is_empty() {
+                if let Some(key) = route_key(self.routes, tag, opts) {
+                    cache.write(&key, rec)?;
+                }
+            }
+            if let Some(num_shards) = opts.num_shards {
+                cache.write(&shard_key(rec.qname(), num_shards), rec)?;
+            }
+        }
+        match (self.fastq_out.as_mut(), self.interleaver.as_mut()) {
+            (Some(fq), Some(interleaver)) => interleaver.write_record(fq, rec)?,
+            (Some(fq), None) => fq.write_record(rec)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Under `fail_fast`, fails once enough reads were scanned without a match.
+    fn check_fail_fast(&self) -> Result<(), Error> {
+        if self.selector.opts.fail_fast
+            && self.reads_written == 0
+            && self.reads_scanned >= FAIL_FAST_MIN_READS
+        {
+            bail!(
+                "0/{} reads matched - check tag/whitelist",
+                self.reads_scanned
+            );
+        }
+        Ok(())
+    }
+
+    /// Closes the side outputs and reports the counts, for a main output at `path`.
+    fn finish(
+        self,
+        path: &Path,
+        fastq_path: Option<PathBuf>,
+        chunk_start: Instant,
+        started_seconds: f64,
+    ) -> Result<SliceResult, Error> {
+        self.progress
+            .fetch_add(self.reads_scanned % PROGRESS_BATCH, Ordering::Relaxed);
+        if let Some(mut fq) = self.fastq_out {
+            if let Some(interleaver) = self.interleaver {
+                let singletons = interleaver.finish(&mut fq)?;
+                if singletons > 0 {
+                    warn!(
+                        "Chunk {} wrote {} reads without their mate to the interleaved FASTQ",
+                        self.chunk, singletons
+                    );
+                }
+            }
+            fq.finish()?;
+        }
+        let (split_parts, split_counts) =
+            self.split_writers.map(|c| c.finish()).unwrap_or_default();
+        let (route_parts, route_counts) =
+            self.route_writers.map(|c| c.finish()).unwrap_or_default();
+        warn_duplicate_tags(self.duplicate_tags, self.selector.bam_tag);
+        Ok(SliceResult {
+            path: path.to_path_buf(),
+            reads_scanned: self.reads_scanned,
+            reads_written: self.reads_written,
+            tid_counts: self.tid_counts,
+            split_parts,
+            split_counts,
+            fastq_path,
+            elapsed_seconds: chunk_start.elapsed().as_secs_f64(),
+            started_seconds,
+            saturation: self.saturation,
+            unmatched: self.unmatched,
+            write_errors: self.write_errors,
+            coverage: self.coverage,
+            flagstat: self.flagstat,
+            route_parts,
+            route_counts,
+        })
+    }
+}
+
+pub fn read_bam_slice(args: &SliceArgs) -> Result<SliceResult, Error> {
+    read_bam_slice_to(args, None)
+}
+
+/// `read_bam_slice`, sending the matched records to `sink` in batches instead
+/// of writing a chunk BAM when one is given. Dropping `sink` on return tells
+/// the writer the chunk is finished, whether or not it succeeded.
+fn read_bam_slice_to(
+    args: &SliceArgs,
+    sink: Option<SyncSender<Vec<Record>>>,
+) -> Result<SliceResult, Error> {
+    let chunk_name = match &args.chunk_key {
+        Some(key) => key.clone(),
+        None => args.i.to_string(),
+    };
+    let out_bam_file = args.tmp_dir.join(format!("{}.bam", chunk_name));
+    let marker_file = args
+        .chunk_key
+        .as_ref()
+        .map(|key| args.tmp_dir.join(format!("{}.done", key)));
+    let fastq_path = args
+        .opts
+        .fastq_output
+        .as_ref()
+        .map(|_| args.tmp_dir.join(format!("{}.fastq", chunk_name)));
+
+    // saturation, coverage, flagstat and unmatched counts are not recorded in the marker, so those chunks always rerun;
+    // neither are per-barcode kept counts, which a reused chunk would leave at zero
+    let resumable = args.opts.saturation_output.is_none()
+        && args.opts.per_barcode_targets.is_none()
+        && args.opts.coverage_bed.is_none()
+        && args.opts.flagstat_output.is_none()
+        && args.opts.unmatched_histogram.is_none();
+    if let (Some(marker), true) = (&marker_file, resumable) {
+        if let Some(result) = load_chunk_marker(marker, &out_bam_file, &fastq_path) {
+            info!("Chunk {} is already complete, skipping", args.i);
+            args.progress
+                .fetch_add(result.reads_scanned, Ordering::Relaxed);
+            return Ok(result);
+        }
+    }
+
+    let chunk_start = Instant::now();
+    let started_seconds = args.run_start.elapsed().as_secs_f64();
+    let mut bam = bam::Reader::from_path(args.bam_file)?;
+    if let Some(options) = &args.opts.htslib_options {
+        apply_htslib_options(&bam, options)?;
+    }
+
+    // htslib thread pools are not Send, so each chunk gets its own
+    let _decompress_pool = if args.opts.decompress_threads > 0 {
+        let pool = tpool::ThreadPool::new(args.opts.decompress_threads as u32)?;
+        bam.set_thread_pool(&pool)?;
+        Some(pool)
+    } else {
+        None
+    };
+
+    let mut out_bam = match sink {
+        Some(_) => None,
+        None => Some(load_writer(&bam, &out_bam_file, None, None)?),
+    };
+    let mut batch = Vec::new();
+    let mut aborted = false;
+
+    let selector = args.selector();
+    let mut pipeline =
+        RecordPipeline::new(&selector, args.routes, args.i, args.progress, bam.header());
+    if args.opts.split_output_dir.is_some() {
+        pipeline.split_writers = Some(WriterCache::new(
+            &args.tmp_dir.join(format!("split_{}", chunk_name)),
+            bam::Header::from_template(bam.header()),
+            args.max_open_files,
+        )?);
+    }
+    if !args.routes.is_empty() || args.opts.num_shards.is_some() {
+        pipeline.route_writers = Some(WriterCache::new(
+            &args.tmp_dir.join(format!("routes_{}", chunk_name)),
+            bam::Header::from_template(bam.header()),
+            args.max_open_files,
+        )?);
+    }
+    if let (Some(path), Some(final_path)) = (&fastq_path, &args.opts.fastq_output) {
+        pipeline.fastq_out = Some(MaybeGzWriter::from_path(
+            path,
+            is_gzip_path(final_path),
+            args.opts
+                .fastq_compression
+                .unwrap_or(DEFAULT_FASTQ_COMPRESSION),
+        )?);
+    }
+
+    let stop_at_unplaced = args.opts.mapped_only && is_coordinate_sorted(bam.header());
+    for r in bam.iter_chunk(args.virtual_start, args.virtual_stop) {
+        let mut rec = r?;
+        match pipeline.scan(&rec, stop_at_unplaced) {
+            Scan::Keep => {}
+            Scan::Skip => continue,
+            Scan::Stop => break,
+        }
+        if pipeline.reads_scanned % PROGRESS_BATCH == 0 && args.abort.load(Ordering::Relaxed) {
+            aborted = true;
+            break;
+        }
+        let tag = match pipeline.select(&mut rec)? {
+            Some(tag) => tag,
+            None => continue,
+        };
+        match (out_bam.as_mut(), &sink) {
+            (Some(out_bam), _) => {
+                if let Err(e) = out_bam.write(&rec) {
+                    handle_write_error(&rec, e, args.write_errors, args.opts)?;
+                    pipeline.write_errors += 1;
+                    continue;
+                }
+            }
+            (None, Some(sink)) => {
+                batch.push(rec.clone());
+                if batch.len() == ORDERED_BATCH_RECORDS {
+                    send_batch(sink, &mut batch)?;
+                }
+            }
+            (None, None) => unreachable!("a chunk writes to a file or a sink"),
+        }
+        pipeline.written(&rec, &tag)?;
+    }
+    if let Some(sink) = &sink {
+        send_batch(sink, &mut batch)?;
+    }
+
+    // only the first chunk to finish with enough reads scanned decides whether
+    // to abort the others, so a small chunk cannot switch the check off
+    if args.opts.fail_fast
+        && pipeline.reads_scanned >= FAIL_FAST_MIN_READS
+        && !args.first_done.swap(true, Ordering::Relaxed)
+    {
+        if pipeline.reads_written == 0 {
+            args.abort.store(true, Ordering::Relaxed);
+            bail!(
+                "0/{} reads matched in chunk {} - check tag/whitelist",
+                pipeline.reads_scanned,
+                args.i
+            );
+        }
+    }
+
+    // flush the chunk before it can be marked complete
+    let wrote_file = out_bam.is_some();
+    drop(out_bam);
+    if wrote_file && !aborted {
+        verify_chunk_output(&out_bam_file, pipeline.reads_written, args.i)?;
+    }
+    let result = pipeline.finish(&out_bam_file, fastq_path, chunk_start, started_seconds)?;
+    info!(
+        "Chunk {} ran from {:.1}s to {:.1}s: {} reads scanned, {} written",
+        args.i,
+        result.started_seconds,
+        result.started_seconds + result.elapsed_seconds,
+        result.reads_scanned,
+        result.reads_written
+    );
+    if let (Some(marker), false) = (&marker_file, aborted) {
+        write_chunk_marker(marker, &result)?;
+    }
+    Ok(result)
+}
+
+fn send_batch(sink: &SyncSender<Vec<Record>>, batch: &mut Vec<Record>) -> Result<(), Error> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    if sink.send(std::mem::take(batch)).is_err() {
+        bail!("The output writer stopped before the chunk was written");
+    }
+    Ok(())
+}
+
+/// Writes the batches of every chunk in chunk order, returning the records
+/// skipped per chunk under the write error policy.
+fn write_ordered(
+    receivers: Vec<Receiver<Vec<Record>>>,
+    mut writer: bam::Writer,
+    write_errors: &AtomicU64,
+    opts: &SubsampleOptions,
+) -> Result<Vec<u64>, Error> {
+    let mut skipped = vec![0; receivers.len()];
+    let mut sort_check = SortCheck::default();
+    for (i, rx) in receivers.into_iter().enumerate() {
+        // ends once chunk i drops its sender
+        for batch in rx {
+            for rec in &batch {
+                if opts.assert_sorted {
+                    sort_check.check(rec, format!("chunk {}", i))?;
+                }
+                if let Err(e) = writer.write(rec) {
+                    handle_write_error(rec, e, write_errors, opts)?;
+                    skipped[i] += 1;
+                }
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+/// Runs the chunks on `threads` threads, sending their records to a single
+/// writer thread that writes them to `writer` in chunk order, so no chunk
+/// BAMs or merge are needed.
+///
+/// Chunks start in index order and each may only get a few batches ahead of
+/// the writer, so the earliest unfinished chunk is always running and memory
+/// stays bounded; chunks further ahead wait for the writer to reach them.
+pub fn run_chunks_ordered(
+    chunks: &[SliceArgs],
+    threads: usize,
+    writer: bam::Writer,
+    write_errors: &AtomicU64,
+    opts: &SubsampleOptions,
+) -> Result<Vec<SliceResult>, Error> {
+    let (senders, receivers): (Vec<_>, Vec<_>) = chunks
+        .iter()
+        .map(|_| mpsc::sync_channel(ORDERED_CHANNEL_BATCHES))
+        .unzip();
+    let senders: Vec<_> = senders.into_iter().map(|s| Mutex::new(Some(s))).collect();
+    let next_chunk = AtomicUsize::new(0);
+
+    thread::scope(|s| {
+        let writer_thread = s.spawn(move || write_ordered(receivers, writer, write_errors, opts));
+        let workers: Vec<_> = (0..threads.max(1).min(chunks.len()))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next_chunk.fetch_add(1, Ordering::Relaxed);
+                        if i >= chunks.len() {
+                            break;
+                        }
+                        let sink = senders[i].lock().unwrap().take();
+                        results.push((i, read_bam_slice_to(&chunks[i], sink)));
+                    }
+                    results
+                })
+            })
+            .collect();
+
+        let mut results: Vec<_> = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        // a failed writer makes the chunks fail too, so report its error first
+        let skipped = writer_thread.join().unwrap()?;
+        results.sort_by_key(|(i, _)| *i);
+        let mut slices = results
+            .into_iter()
+            .map(|(_, r)| r)
+            .collect::<Result<Vec<_>, _>>()?;
+        for (slice, n) in slices.iter_mut().zip(skipped) {
+            slice.write_errors += n;
+            slice.reads_written -= n;
+        }
+        Ok(slices)
+    })
+}
+
+/// Applies the write error policy to a record that failed to write: abort by
+/// default, otherwise skip it until `max_write_errors` is used up across the run.
+fn handle_write_error(
+    rec: &Record,
+    e: impl std::fmt::Display,
+    write_errors: &AtomicU64,
+    opts: &SubsampleOptions,
+) -> Result<(), Error> {
+    let qname = String::from_utf8_lossy(rec.qname());
+    match opts.max_write_errors {
+        Some(max) if write_errors.fetch_add(1, Ordering::Relaxed) < max => {
+            warn!(
+                "Skipping record {} which could not be written: {}",
+                qname, e
+            );
+            Ok(())
+        }
+        Some(max) => bail!(
+            "Cannot write record {} ({}); more than {} records failed to write",
+            qname,
+            e,
+            max
+        ),
+        None => bail!("Cannot write record {}: {}", qname, e),
+    }
+}
+
+/// Whether a run would write every input record unchanged, so the input can
+/// simply be copied. Outputs that need per-record counts rule this out.
+fn is_plain_copy(opts: &SubsampleOptions, to_replace: &Option<String>) -> bool {
+    opts.rewrite_only
+        && to_replace.is_none()
+        && opts.per_barcode_targets.is_none()
+        && opts.min_tlen.is_none()
+        && opts.max_tlen.is_none()
+        && opts.header_template.is_none()
+        && !opts.trim_softclips
+        && !opts.validate_output
+        && opts.min_aligned_length.is_none()
+        && opts.idxstats_output.is_none()
+        && opts.split_output_dir.is_none()
+        && opts.strand.is_none()
+        && opts.fastq_output.is_none()
+        && opts.mask_low_qual.is_none()
+        && opts.multiqc_output.is_none()
+        && opts.bed_file.is_none()
+        && !opts.remove_duplicates
+        && !opts.sort_by_tag
+        && !opts.sort_by_name
+        && opts.saturation_output.is_none()
+        && opts.set_sort_order.is_none()
+        && opts.positions_file.is_none()
+        && opts.replacement_template.is_none()
+        && !opts.prune_header
+        && !opts.strip_alignment
+        && opts.reorder_to.is_none()
+        && opts.min_gc.is_none()
+        && opts.max_gc.is_none()
+        && opts.flagstat_output.is_none()
+        && opts.tag_chunk.is_none()
+        && !opts.indel_only
+        && opts.max_output_bytes.is_none()
+        && opts.htslib_options.is_none()
+        && !opts.uppercase_tag
+        && opts.unmatched_histogram.is_none()
+        && opts.extra_tag_whitelists.is_none()
+        && !opts.mapped_only
+        && !opts.discordant_only
+        && !opts.fixmate
+        && opts.head.is_none()
+        && opts.keep_tags.is_none()
+        && opts.strip_tags.is_none()
+        && opts.collapse_molecules.is_none()
+        && opts.translation_map.is_none()
+        && opts.mate.is_none()
+        && opts.coverage_bed.is_none()
+        && opts.regex_routes.is_empty()
+        && opts.subsample_fraction.is_none()
+        && opts.num_shards.is_none()
+        && !opts.untagged_only
+        && !opts.strict_tags
+        && !opts.assert_sorted
+}
+
+/// Whether `path` is a URL such as `https://...`, `s3://bucket/key` or
+/// `file:///data/x.bam` for htslib to open rather than a local path.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .and_then(|s| s.find("://").map(|i| &s[..i]))
+        .map_or(false, |scheme| {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        })
+}
+
+fn input_url(path: &Path) -> Result<Url, Error> {
+    Ok(Url::parse(&path.to_string_lossy())?)
+}
+
+/// Opens an input BAM: `-` for stdin, a URL through htslib's remote file
+/// support, or a local path.
+///
+/// Remote reads need htslib built with libcurl. `s3://` credentials come from
+/// the usual `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY`/`AWS_SESSION_TOKEN`
+/// variables or the `AWS_PROFILE` section of `~/.aws/credentials`, and
+/// `https://` bearer tokens from the file named by `HTS_AUTH_LOCATION`.
+pub fn open_input(path: &Path) -> Result<bam::Reader, Error> {
+    Ok(if path == Path::new("-") {
+        bam::Reader::from_stdin()?
+    } else if is_url(path) {
+        bam::Reader::from_url(&input_url(path)?)?
+    } else {
+        bam::Reader::from_path(path)?
+    })
+}
+
+/// Whether any input can only be read front to back (stdin, a URL, SAM or
+/// CRAM), which rules out byte-offset chunking.
+fn needs_streaming<P: AsRef<Path>>(bam_files: &[P]) -> bool {
+    bam_files.iter().any(|p| {
+        let p = p.as_ref();
+        p == Path::new("-")
+            || is_url(p)
+            || p.extension()
+                .map_or(false, |ext| ext == "sam" || ext == "cram")
+    })
+}
+
+/// Whether every requested output can be produced without per-chunk temp files.
+fn can_stream(opts: &SubsampleOptions) -> bool {
+    opts.regex_routes.is_empty()
+        && opts.num_shards.is_none()
+        && opts.fastq_output.is_none()
+        && opts.saturation_output.is_none()
+        && opts.resume_dir.is_none()
+}
+
+/// Reads every input front to back straight into `out_bam_file`, without
+/// chunk temp files or a merge. `-` reads from stdin.
+///
+/// Split outputs are written in the same pass, to one part per group in
+/// `tmp_dir` (more when over `max_open_files`), so the input is only read once.
+pub fn subsample_bam_streaming<P: AsRef<Path>>(
+    bam_files: &[P],
+    out_bam_file: &Path,
+    tmp_dir: &Path,
+    max_open_files: usize,
+    selector: &RecordSelector,
+    progress: &AtomicU64,
+    write_errors: &AtomicU64,
+    shared_pool: Option<&tpool::ThreadPool>,
+) -> Result<SliceResult, Error> {
+    let opts = selector.opts;
+    let chunk_start = Instant::now();
+    let mut out_bam = None;
+    let mut pipeline = None;
+
+    for bam_file in bam_files {
+        let mut bam = open_input(bam_file.as_ref())?;
+        if let Some(options) = &opts.htslib_options {
+            apply_htslib_options(&bam, options)?;
+        }
+        let _decompress_pool = match shared_pool {
+            Some(pool) => {
+                bam.set_thread_pool(pool)?;
+                None
+            }
+            None if opts.decompress_threads > 0 => {
+                let pool = tpool::ThreadPool::new(opts.decompress_threads as u32)?;
+                bam.set_thread_pool(&pool)?;
+                Some(pool)
+            }
+            None => None,
+        };
+        if out_bam.is_none() {
+            let mut writer = load_writer(
+                &bam,
+                out_bam_file,
+                opts.header_template.as_deref(),
+                Some(opts.set_sort_order.as_deref().unwrap_or("unknown")),
+            )?;
+            if let Some(pool) = shared_pool {
+                writer.set_thread_pool(pool)?;
+            }
+            out_bam = Some(writer);
+            let mut first = RecordPipeline::new(selector, &[], 0, progress, bam.header());
+            if opts.split_output_dir.is_some() {
+                first.split_writers = Some(WriterCache::new(
+                    &tmp_dir.join("split_stream"),
+                    bam::Header::from_template(bam.header()),
+                    max_open_files,
+                )?);
+            }
+            pipeline = Some(first);
+        }
+        let out = out_bam.as_mut().unwrap();
+        let pipe = pipeline.as_mut().unwrap();
+
+        // head applies per input, as it does per chunk
+        pipe.start_input();
+        let stop_at_unplaced = opts.mapped_only && is_coordinate_sorted(bam.header());
+        if stream_records(
+            &mut bam,
+            out,
+            pipe,
+            stop_at_unplaced,
+            Some(out_bam_file),
+            write_errors,
+        )? {
+            break;
+        }
+    }
+    let pipeline = match pipeline {
+        Some(pipeline) => pipeline,
+        None => bail!("No input BAM files were given"),
+    };
+
+    drop(out_bam);
+    pipeline.finish(out_bam_file, None, chunk_start, 0.0)
+}
+
+pub fn write_split_outputs(
+    slices: &[SliceResult],
+    split_dir: &Path,
+    header: &bam::Header,
+    keep_parts: bool,
+) -> Result<HashMap<Vec<u8>, u64>, Error> {
+    fs::create_dir_all(split_dir)?;
+
+    // chunks are in file order, so their parts concatenate in file order too
+    let mut parts: HashMap<&Vec<u8>, Vec<PathBuf>> = HashMap::new();
+    let mut counts = HashMap::new();
+    for slice in slices {
+        for (key, paths) in &slice.split_parts {
+            parts
+                .entry(key)
+                .or_insert_with(Vec::new)
+                .extend(paths.iter().cloned());
+        }
+        for (key, n) in &slice.split_counts {
+            *counts.entry(key.clone()).or_insert(0) += n;
+        }
+    }
+    for (key, paths) in &parts {
+        let out_path = split_dir.join(format!("{}.bam", sanitize_key(key)));
+        concat_parts(paths, header, &out_path, keep_parts)?;
+    }
+    info!(
+        "Wrote {} split BAMs to {}",
+        parts.len(),
+        split_dir.display()
+    );
+    Ok(counts)
+}
+
+/// Key of the route a tag goes to: the index of the first matching regex,
+/// else `default` when there is a default output.
+fn route_key(routes: &[Regex], tag: &Option<Vec<u8>>, opts: &SubsampleOptions) -> Option<Vec<u8>> {
+    let matched = tag
+        .as_ref()
+        .and_then(|t| routes.iter().position(|re| re.is_match(t)));
+    match (matched, &opts.regex_default_output) {
+        (Some(i), _) => Some(i.to_string().into_bytes()),
+        (None, Some(_)) => Some(b"default".to_vec()),
+        (None, None) => None,
+    }
+}
+
+/// Joins the per-chunk parts stored under each key into that key's output,
+/// returning the records written per output.
+fn join_keyed_outputs(
+    slices: &[SliceResult],
+    outputs: &[(Vec<u8>, PathBuf)],
+    header: &bam::Header,
+    keep_parts: bool,
+) -> Result<Vec<u64>, Error> {
+    let mut counts = Vec::with_capacity(outputs.len());
+    for (key, out_path) in outputs {
+        let parts: Vec<PathBuf> = slices
+            .iter()
+            .filter_map(|s| s.route_parts.get(key))
+            .flatten()
+            .cloned()
+            .collect();
+        // an output nothing went to still gets a (header-only) BAM
+        concat_parts(&parts, header, out_path, keep_parts)?;
+        counts.push(slices.iter().filter_map(|s| s.route_counts.get(key)).sum());
+    }
+    Ok(counts)
+}
+
+/// Joins the per-chunk route parts into each route's output, returning the
+/// records written per output.
+pub fn write_route_outputs(
+    slices: &[SliceResult],
+    opts: &SubsampleOptions,
+    header: &bam::Header,
+) -> Result<HashMap<PathBuf, u64>, Error> {
+    let mut outputs: Vec<(Vec<u8>, PathBuf)> = opts
+        .regex_routes
+        .iter()
+        .enumerate()
+        .map(|(i, (_, path))| (i.to_string().into_bytes(), path.clone()))
+        .collect();
+    if let Some(path) = &opts.regex_default_output {
+        outputs.push((b"default".to_vec(), path.clone()));
+    }
+    let counts = join_keyed_outputs(slices, &outputs, header, opts.resume_dir.is_some())?;
+    Ok(outputs
+        .into_iter()
+        .map(|(_, path)| path)
+        .zip(counts)
+        .collect())
+}
+
+fn shard_key(qname: &[u8], num_shards: usize) -> Vec<u8> {
+    format!("shard_{}", stable_hash(qname, 0) % num_shards as u64).into_bytes()
+}
+
+/// `<dir>/<stem>.shard_<k>.bam` next to the main output.
+pub fn shard_path(out_bam_file: &Path, shard: usize) -> PathBuf {
+    let stem = out_bam_file
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    out_bam_file.with_file_name(format!("{}.shard_{}.bam", stem, shard))
+}
+
+/// Joins the per-chunk shard parts into `num_shards` BAMs next to the output,
+/// returning the records per shard.
+pub fn write_shard_outputs(
+    slices: &[SliceResult],
+    out_bam_file: &Path,
+    num_shards: usize,
+    header: &bam::Header,
+    keep_parts: bool,
+) -> Result<Vec<u64>, Error> {
+    let outputs: Vec<(Vec<u8>, PathBuf)> = (0..num_shards)
+        .map(|k| {
+            (
+                format!("shard_{}", k).into_bytes(),
+                shard_path(out_bam_file, k),
+            )
+        })
+        .collect();
+    join_keyed_outputs(slices, &outputs, header, keep_parts)
+}
+
+/// The coordinate of the last record written, for `assert_sorted`.
+#[derive(Default)]
+struct SortCheck {
+    last: (u32, i64),
+}
+
+impl SortCheck {
+    /// Errors if `rec`, from `source`, goes backwards in coordinate order.
+    fn check(&mut self, rec: &Record, source: impl std::fmt::Display) -> Result<(), Error> {
+        // unmapped reads (tid -1) sort after every contig
+        let key = (rec.tid() as u32, rec.pos());
+        if key < self.last {
+            bail!(
+                "Record {} in {} is out of coordinate order",
+                String::from_utf8_lossy(rec.qname()),
+                source
+            );
+        }
+        self.last = key;
+        Ok(())
+    }
+}
+
+pub fn merge_bams<P: AsRef<Path>>(
+    tmp_bams: Vec<&PathBuf>,
+    header_source: &Path,
+    out_bam_file: P,
+    header_template: Option<&Path>,
+    sort_order: Option<&str>,
+    assert_sorted: bool,
+) -> Result<(), Error> {
+    use rust_htslib::bam::Read; // collides with fs::Read
+
+    // take the header from the input, so it does not matter which chunks are empty
+    let bam = bam::Reader::from_path(header_source)?;
+    let mut out_bam = load_writer(&bam, out_bam_file.as_ref(), header_template, sort_order)?;
+    let mut sort_check = SortCheck::default();
+    for b in tmp_bams.iter() {
+        let mut rdr = bam::Reader::from_path(b)?;
+        for _rec in rdr.records() {
+            let rec = _rec?;
+            if assert_sorted {
+                sort_check.check(&rec, b.display())?;
+            }
+            out_bam.write(&rec)?;
+        }
+    }
+    Ok(())
+}
+
+fn has_duplicate_tag(rec: &Record, bam_tag: &str) -> bool {
+    rec.aux_iter()
+        .filter_map(|a| a.ok())
+        .filter(|(tag, _)| *tag == bam_tag.as_bytes())
+        .nth(1)
+        .is_some()
+}
+
+fn warn_duplicate_tags(n: u64, bam_tag: &str) {
+    if n > 0 {
+        warn!(
+            "{} reads had more than one {} tag; the first was used",
+            n, bam_tag
+        );
+    }
+}
+
+/// Removes every copy of `tag`, so a rewritten tag is not shadowed by a
+/// leftover duplicate.
+fn remove_tag(rec: &mut bam::Record, tag: &[u8]) -> Result<(), rust_htslib::tpool::Error> {
+    rec.remove_aux(tag)?;
+    while rec.aux(tag).is_ok() {
+        rec.remove_aux(tag)?;
+    }
+    Ok(())
+}
+
+fn substitute_text_in_tag(
+    rec: &mut bam::Record,
+    bam_tag: &str,
+    to_replace: &str,
+    replacement: &str,
+) -> Result<(), Error> {
+    let bam_tag_bytes = bam_tag.as_bytes();
+    let bc = match get_record_tag(&rec, &bam_tag) {
+        Some(bc) => bc,
+        None => bail!(
+            "Read {} has no {} tag to replace text in",
+            String::from_utf8_lossy(rec.qname()),
+            bam_tag
+        ),
+    };
+    remove_tag(rec, &bam_tag_bytes)?;
+    // array tags are written back as string tags
+    let new_tag = String::from_utf8_lossy(&bc).replace(to_replace, replacement);
+    rec.push_aux(&bam_tag_bytes, Aux::String(&new_tag))?;
+    Ok(())
+}
+
+/// Sets `bam_tag` to `template` with `{barcode}` replaced by the current tag
+/// value and `{chunk}` by the index of the chunk the record was read in.
+fn expand_tag_template(
+    rec: &mut bam::Record,
+    bam_tag: &str,
+    template: &str,
+    chunk: usize,
+) -> Result<(), rust_htslib::tpool::Error> {
+    let bam_tag_bytes = bam_tag.as_bytes();
+    let bc = get_record_tag(&rec, &bam_tag);
+
+    match bc {
+        Some(b) => {
+            let new_tag = template
+                .replace("{barcode}", &String::from_utf8_lossy(&b))
+                .replace("{chunk}", &chunk.to_string());
+            remove_tag(rec, &bam_tag_bytes)?;
+            rec.push_aux(&bam_tag_bytes, Aux::String(&new_tag))?;
+            Ok(())
+        }
+        None => Err(rust_htslib::tpool::Error::BamAuxTagNotFound),
+    }
+}
+
+/// Rewrites `bam_tag` in uppercase if it holds any lowercase letters.
+fn uppercase_tag(rec: &mut bam::Record, bam_tag: &str) -> Result<(), Error> {
+    let bam_tag_bytes = bam_tag.as_bytes();
+    match get_record_tag(&rec, &bam_tag) {
+        Some(b) if b.iter().any(|c| c.is_ascii_lowercase()) => {
+            let new_tag = String::from_utf8_lossy(&b).to_ascii_uppercase();
+            remove_tag(rec, &bam_tag_bytes)?;
+            rec.push_aux(&bam_tag_bytes, Aux::String(&new_tag))?;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn write_idxstats<P: AsRef<Path>>(
+    path: P,
+    header: &bam::HeaderView,
+    tid_counts: &[u64],
+) -> Result<(), Error> {
+    let mut out = sidecar_writer(path.as_ref())?;
+    for tid in 0..header.target_count() {
+        writeln!(
+            out,
+            "{}\t{}\t{}",
+            String::from_utf8_lossy(header.tid2name(tid)),
+            header.target_len(tid).unwrap_or(0),
+            tid_counts[tid as usize]
+        )?;
+    }
+    writeln!(out, "*\t0\t{}", tid_counts[tid_counts.len() - 1])?;
+    out.finish()?;
+    Ok(())
+}
+
+pub fn write_multiqc<P: AsRef<Path>>(path: P, stats: &SubsampleStats) -> Result<(), Error> {
+    let sample = stats
+        .output_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "sample".to_string());
+    let retention = if stats.reads_scanned > 0 {
+        100.0 * stats.reads_written as f64 / stats.reads_scanned as f64
+    } else {
+        0.0
+    };
+
+    let mut out = sidecar_writer(path.as_ref())?;
+    writeln!(out, "# id: 'rust_bam_tools_subsample'")?;
+    writeln!(out, "# section_name: 'BAM subsampling'")?;
+    writeln!(
+        out,
+        "# description: 'Reads retained by rust_bam_tools subsample_bam'"
+    )?;
+    writeln!(out, "# format: 'tsv'")?;
+    writeln!(out, "# plot_type: 'table'")?;
+
+    let mut columns = vec![
+        "Sample".to_string(),
+        "reads_scanned".to_string(),
+        "reads_written".to_string(),
+        "retention_pct".to_string(),
+        "elapsed_seconds".to_string(),
+    ];
+    let mut values = vec![
+        sample,
+        stats.reads_scanned.to_string(),
+        stats.reads_written.to_string(),
+        format!("{:.2}", retention),
+        format!("{:.2}", stats.elapsed_seconds),
+    ];
+    for (i, seconds) in stats.chunk_seconds.iter().enumerate() {
+        columns.push(format!("chunk_{}_seconds", i));
+        values.push(format!("{:.2}", seconds));
+    }
+    writeln!(out, "{}", columns.join("\t"))?;
+    writeln!(out, "{}", values.join("\t"))?;
+    out.finish()?;
+    Ok(())
+}
+
+/// Removes `@SQ` lines that no written record (or its mate) refers to.
+///
+/// tids index the `@SQ` lines, so every record is rewritten with remapped
+/// tids: this costs one extra read of the output to find the used
+/// references and one full rewrite through `tmp_dir`.
+pub fn prune_header(bam_path: &Path, tmp_dir: &Path) -> Result<usize, Error> {
+    let (header_text, n_targets) = {
+        let bam = bam::Reader::from_path(bam_path)?;
+        (
+            String::from_utf8_lossy(bam.header().as_bytes()).into_owned(),
+            bam.header().target_count() as usize,
+        )
+    };
+
+    let mut used = vec![false; n_targets];
+    let mut bam = bam::Reader::from_path(bam_path)?;
+    for r in bam.records() {
+        let rec = r?;
+        for tid in [rec.tid(), rec.mtid()].iter() {
+            if *tid >= 0 {
+                used[*tid as usize] = true;
+            }
+        }
+    }
+    let n_removed = used.iter().filter(|u| !**u).count();
+    if n_removed == 0 {
+        return Ok(0);
+    }
+
+    let mut new_tids = vec![-1; n_targets];
+    let mut next_tid = 0;
+    let mut sq_index = 0;
+    let mut lines = Vec::new();
+    for line in header_text.lines() {
+        if line.starts_with("@SQ") {
+            if used[sq_index] {
+                new_tids[sq_index] = next_tid;
+                next_tid += 1;
+                lines.push(line);
+            }
+            sq_index += 1;
+        } else {
+            lines.push(line);
+        }
+    }
+    let text = lines.join("\n") + "\n";
+    let header = bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()));
+
+    let tmp_path = tmp_dir.join("pruned.bam");
+    {
+        let mut out = bam::Writer::from_path(&tmp_path, &header, bam::Format::Bam)?;
+        let mut bam = bam::Reader::from_path(bam_path)?;
+        for r in bam.records() {
+            let mut rec = r?;
+            if rec.tid() >= 0 {
+                rec.set_tid(new_tids[rec.tid() as usize]);
+            }
+            if rec.mtid() >= 0 {
+                rec.set_mtid(new_tids[rec.mtid() as usize]);
+            }
+            out.write(&rec)?;
+        }
+    }
+    fs::rename(&tmp_path, bam_path).or_else(|_| fs::copy(&tmp_path, bam_path).map(|_| ()))?;
+    info!(
+        "Pruned {} unused @SQ lines from the output header",
+        n_removed
+    );
+    Ok(n_removed)
+}
+
+/// Contig names and lengths, in order, of a `.fai` or the `@SQ` lines of a SAM header.
+pub fn load_reference_order(path: &Path) -> Result<Vec<(String, u64)>, Error> {
+    let is_fai = path.extension().map_or(false, |ext| ext == "fai");
+    let reader = BufReader::new(fs::File::open(path)?);
+    let mut contigs = Vec::new();
+    for (line_no, l) in reader.lines().enumerate() {
+        let l = l?;
+        let contig = if is_fai {
+            let mut fields = l.split('\t');
+            match (fields.next(), fields.next().map(|len| len.parse::<u64>())) {
+                (Some(name), Some(Ok(len))) => Some((name.to_string(), len)),
+                _ => None,
+            }
+        } else if l.starts_with("@SQ") {
+            let field = |key: &str| l.split('\t').find_map(|f| f.strip_prefix(key));
+            match (field("SN:"), field("LN:").map(|len| len.parse::<u64>())) {
+                (Some(name), Some(Ok(len))) => Some((name.to_string(), len)),
+                _ => None,
+            }
+        } else {
+            continue;
+        };
+        match contig {
+            Some(contig) => contigs.push(contig),
+            None => bail!(
+                "Line {} of {} is not a valid {} line",
+                line_no + 1,
+                path.display(),
+                if is_fai { ".fai" } else { "@SQ" }
+            ),
+        }
+    }
+    if contigs.is_empty() {
+        bail!("No contigs found in {}", path.display());
+    }
+    Ok(contigs)
+}
+
+/// Rewrites `bam_path` with its `@SQ` lines in the order of `reference` (see
+/// `load_reference_order`), remapping every record's tid and mate tid.
+///
+/// Contigs only in the reference are added, so outputs reordered to the same
+/// reference share one header; a contig missing from the reference, or with
+/// a different length, is an error.
+pub fn reorder_references(bam_path: &Path, tmp_dir: &Path, reference: &Path) -> Result<(), Error> {
+    let order = load_reference_order(reference)?;
+    let position: HashMap<&str, usize> = order
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (name.as_str(), i))
+        .collect();
+
+    let mut bam = bam::Reader::from_path(bam_path)?;
+    let header_text = String::from_utf8_lossy(bam.header().as_bytes()).into_owned();
+    let mut new_tids = Vec::new();
+    let mut sq_lines: Vec<Option<String>> = vec![None; order.len()];
+    let mut lines = Vec::new();
+    for line in header_text.lines() {
+        if !line.starts_with("@SQ") {
+            lines.push(line.to_string());
+            continue;
+        }
+        let tid = new_tids.len() as u32;
+        let name = String::from_utf8_lossy(bam.header().tid2name(tid)).into_owned();
+        let i = match position.get(name.as_str()) {
+            Some(i) => *i,
+            None => bail!(
+                "Contig {} of {} is not in {}",
+                name,
+                bam_path.display(),
+                reference.display()
+            ),
+        };
+        if bam.header().target_len(tid) != Some(order[i].1) {
+            bail!(
+                "Contig {} has length {:?} in the output but {} in {}",
+                name,
+                bam.header().target_len(tid),
+                order[i].1,
+                reference.display()
+            );
+        }
+        new_tids.push(i as i32);
+        sq_lines[i] = Some(line.to_string());
+    }
+    // @SQ lines go after @HD, and before the @RG/@PG lines
+    let hd_lines = lines.iter().take_while(|l| l.starts_with("@HD")).count();
+    let sq_text = sq_lines
+        .into_iter()
+        .zip(&order)
+        .map(|(line, (name, len))| line.unwrap_or_else(|| format!("@SQ\tSN:{}\tLN:{}", name, len)));
+    let lines: Vec<String> = lines[..hd_lines]
+        .iter()
+        .cloned()
+        .chain(sq_text)
+        .chain(lines[hd_lines..].iter().cloned())
+        .collect();
+    let text = lines.join("\n") + "\n";
+    let header = bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()));
+
+    let tmp_path = tmp_dir.join("reordered.bam");
+    {
+        let mut out = bam::Writer::from_path(&tmp_path, &header, bam::Format::Bam)?;
+        for r in bam.records() {
+            let mut rec = r?;
+            if rec.tid() >= 0 {
+                rec.set_tid(new_tids[rec.tid() as usize]);
+            }
+            if rec.mtid() >= 0 {
+                rec.set_mtid(new_tids[rec.mtid() as usize]);
+            }
+            out.write(&rec)?;
+        }
+    }
+    fs::rename(&tmp_path, bam_path).or_else(|_| fs::copy(&tmp_path, bam_path).map(|_| ()))?;
+    info!("Reordered the output contigs to {}", reference.display());
+    Ok(())
+}
+
+pub fn validate_bam<P: AsRef<Path>>(bam_file: P, expected_records: u64) -> Result<(), Error> {
+    let mut bam = bam::Reader::from_path(bam_file.as_ref())?;
+    let mut n_records = 0;
+    for r in bam.records() {
+        r?;
+        n_records += 1;
+    }
+    if n_records != expected_records {
+        bail!(
+            "{} contains {} records but {} were written; the output may be truncated",
+            bam_file.as_ref().display(),
+            n_records,
+            expected_records
+        );
+    }
+    info!(
+        "Verified {} records in {}",
+        n_records,
+        bam_file.as_ref().display()
+    );
+    Ok(())
+}
+
+/// `SO` value of the `@HD` line, if the header has one.
+pub fn header_sort_order(header: &bam::HeaderView) -> Option<String> {
+    String::from_utf8_lossy(header.as_bytes())
+        .lines()
+        .find(|l| l.starts_with("@HD"))?
+        .split('\t')
+        .find_map(|f| f.strip_prefix("SO:"))
+        .map(|so| so.to_string())
+}
+
+/// Whether the header says `SO:coordinate`, so every read after the first
+/// unplaced one (tid -1) is unplaced too.
+fn is_coordinate_sorted(header: &bam::HeaderView) -> bool {
+    header_sort_order(header).as_deref() == Some("coordinate")
+}
+
+/// Checks `bam_file` is coordinate-sorted and indexed before the BED and
+/// positions filters treat it as an aligned, position-ordered file.
+pub fn check_region_input(bam_file: &Path) -> Result<(), Error> {
+    if bam_file == Path::new("-") {
+        bail!("Region filters need an indexed BAM file, not stdin");
+    }
+    let bam = open_input(bam_file)?;
+    match header_sort_order(bam.header()).as_deref() {
+        Some("coordinate") => {}
+        Some(so) => bail!(
+            "{} is sorted by {}, but region filters need a coordinate-sorted BAM",
+            bam_file.display(),
+            so
+        ),
+        None => warn!(
+            "{} has no @HD SO, assuming it is coordinate-sorted",
+            bam_file.display()
+        ),
+    }
+    // a remote BAM's index is fetched from next to it
+    let indexed = if is_url(bam_file) {
+        bam::IndexedReader::from_url(&input_url(bam_file)?).is_ok()
+    } else {
+        bam::IndexedReader::from_path(bam_file).is_ok()
+    };
+    if !indexed {
+        bail!(
+            "{} has no index; run samtools index before using region filters",
+            bam_file.display()
+        );
+    }
+    Ok(())
+}
+
+/// Parses `key=value` htslib format options into an `hts_opt` list, which the
+/// caller must free with `hts_opt_free`.
+fn build_htslib_options(options: &[(String, String)]) -> Result<*mut htslib::hts_opt, Error> {
+    let mut list: *mut htslib::hts_opt = std::ptr::null_mut();
+    for (key, value) in options {
+        let arg = CString::new(format!("{}={}", key, value))?;
+        // htslib rejects keys it does not know
+        if unsafe { htslib::hts_opt_add(&mut list, arg.as_ptr()) } != 0 {
+            unsafe { htslib::hts_opt_free(list) };
+            bail!("Unknown or invalid htslib option {}={}", key, value);
+        }
+    }
+    Ok(list)
+}
+
+/// Checks every htslib option is one htslib knows, before any file is opened.
+pub fn check_htslib_options(options: &[(String, String)]) -> Result<(), Error> {
+    let list = build_htslib_options(options)?;
+    unsafe { htslib::hts_opt_free(list) };
+    Ok(())
+}
+
+/// Applies htslib format options, as given to `samtools view --input-fmt-option`
+/// (e.g. `required_fields=0x1ff` or `decode_md=0` for CRAM), to an open reader.
+pub fn apply_htslib_options<R: bam::Read>(
+    reader: &R,
+    options: &[(String, String)],
+) -> Result<(), Error> {
+    let list = build_htslib_options(options)?;
+    let ret = unsafe { htslib::hts_opt_apply(reader.htsfile(), list) };
+    unsafe { htslib::hts_opt_free(list) };
+    if ret != 0 {
+        bail!("htslib could not apply the options {:?}", options);
+    }
+    Ok(())
+}
+
+fn check_bgzf_eof(bam_file: &Path) -> Result<(), Error> {
+    use std::io::Read;
+
+    let mut fp = fs::File::open(bam_file)?;
+    let len = fp.metadata()?.len();
+    let mut tail = [0; 28];
+    if len < tail.len() as u64 {
+        bail!("{} is too short to be a BAM file", bam_file.display());
+    }
+    fp.seek(io::SeekFrom::End(-(tail.len() as i64)))?;
+    fp.read_exact(&mut tail)?;
+    if tail != BGZF_EOF {
+        bail!(
+            "{} is missing its BGZF EOF marker; the file may be truncated",
+            bam_file.display()
+        );
+    }
+    Ok(())
+}
+
+/// Checks a chunk BAM is complete and holds the `expected` records written to
+/// it, so a corrupt temp file fails its chunk rather than the merge.
+fn verify_chunk_output(bam_file: &Path, expected: u64, chunk: usize) -> Result<(), Error> {
+    if let Err(e) = check_bgzf_eof(bam_file) {
+        bail!("Chunk {} output is corrupt: {}", chunk, e);
+    }
+    let mut bam = bam::Reader::from_path(bam_file)?;
+    let mut n_records = 0;
+    for r in bam.records() {
+        if let Err(e) = r {
+            bail!(
+                "Chunk {} output {} is corrupt after {} records: {}",
+                chunk,
+                bam_file.display(),
+                n_records,
+                e
+            );
+        }
+        n_records += 1;
+    }
+    if n_records != expected {
+        bail!(
+            "Chunk {} output {} holds {} records but {} were written",
+            chunk,
+            bam_file.display(),
+            n_records,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Checks the BGZF EOF marker of `bam_file` and that its MD5 matches `expected_md5`.
+pub fn verify_input(bam_file: &Path, expected_md5: &str) -> Result<(), Error> {
+    check_bgzf_eof(bam_file)?;
+
+    let fp = fs::File::open(bam_file)?;
+    let mut reader = BufReader::new(fp);
+    let mut context = md5::Context::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let n = buf.len();
+        context.consume(buf);
+        reader.consume(n);
+    }
+    let digest = format!("{:x}", context.finalize());
+    if !digest.eq_ignore_ascii_case(expected_md5.trim()) {
+        bail!(
+            "MD5 of {} is {} but {} was expected",
+            bam_file.display(),
+            digest,
+            expected_md5.trim()
+        );
+    }
+    info!("Verified MD5 of {}", bam_file.display());
+    Ok(())
+}
+
+pub fn check_header_compatibility<P: AsRef<Path>>(bam_files: &[P]) -> Result<(), Error> {
+    let first = open_input(bam_files[0].as_ref())?;
+    let first_hdr = first.header();
+
+    for bam_file in &bam_files[1..] {
+        let bam = open_input(bam_file.as_ref())?;
+        let hdr = bam.header();
+        if hdr.target_names() != first_hdr.target_names()
+            || (0..hdr.target_count()).any(|tid| hdr.target_len(tid) != first_hdr.target_len(tid))
+        {
+            bail!(
+                "{} has different references to {}; inputs must share the same @SQ lines",
+                bam_file.as_ref().display(),
+                bam_files[0].as_ref().display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The throwaway directory chunks are written to, named with `temp_prefix`
+/// when one is given; `None` when `resume_dir` keeps them between runs.
+fn run_temp_dir(opts: &SubsampleOptions) -> Result<Option<tempfile::TempDir>, Error> {
+    if opts.resume_dir.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(match &opts.temp_prefix {
+        Some(prefix) => tempfile::Builder::new().prefix(prefix).tempdir()?,
+        None => tempdir()?,
+    }))
+}
+
+pub fn subsample_bam<P: AsRef<Path>>(
+    bam_files: &[P],
+    barcodes_file: Option<P>,
+    bam_tag: String,
+    to_replace: Option<String>,
+    replacement: Option<String>,
+    out_bam_file: P,
+    cores: usize,
+    opts: &SubsampleOptions,
+) -> Result<SubsampleStats, Error> {
+    subsample_bam_with_pool(
+        bam_files,
+        barcodes_file,
+        bam_tag,
+        to_replace,
+        replacement,
+        out_bam_file,
+        cores,
+        opts,
+        None,
+    )
+}
+
+/// `subsample_bam` reusing an existing htslib thread pool.
+///
+/// htslib pools cannot move between threads, so the pool is only used when the
+/// run streams on the calling thread; chunked runs warn and use their own pools.
+pub fn subsample_bam_with_pool<P: AsRef<Path>>(
+    bam_files: &[P],
+    barcodes_file: Option<P>,
+    bam_tag: String,
+    to_replace: Option<String>,
+    replacement: Option<String>,
+    out_bam_file: P,
+    cores: usize,
+    opts: &SubsampleOptions,
+    shared_pool: Option<&tpool::ThreadPool>,
+) -> Result<SubsampleStats, Error> {
+    let _ = SimpleLogger::init(LevelFilter::Info, Config::default());
+    let start_time = Instant::now();
+
+    if bam_files.is_empty() {
+        bail!("No input BAM files given");
+    }
+    // htslib only reports a missing directory as a generic open failure
+    if let Some(out_dir) = out_bam_file.as_ref().parent() {
+        if !out_dir.as_os_str().is_empty() && !out_dir.is_dir() {
+            if opts.create_output_dirs {
+                fs::create_dir_all(out_dir)?;
+            } else {
+                bail!("Output directory {} does not exist", out_dir.display());
+            }
+        }
+    }
+    if opts.saturation_output.is_some() && opts.umi_tag.is_none() {
+        bail!("A UMI tag is required for the saturation curve");
+    }
+    if let Some(fraction) = opts.subsample_fraction {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            bail!("subsample_fraction must be in (0, 1], got {}", fraction);
+        }
+    }
+    for gc in opts.min_gc.iter().chain(opts.max_gc.iter()) {
+        if !(0.0..=1.0).contains(gc) {
+            bail!("min_gc and max_gc must be in [0, 1], got {}", gc);
+        }
+    }
+    if let (Some(min), Some(max)) = (opts.min_gc, opts.max_gc) {
+        if min > max {
+            bail!("min_gc ({}) is above max_gc ({})", min, max);
+        }
+    }
+    if let Some(options) = &opts.htslib_options {
+        check_htslib_options(options)?;
+    }
+    if opts.discordant_min_insert.is_some() && !opts.discordant_only {
+        bail!("discordant_min_insert needs discordant_only");
+    }
+    if opts.min_indel_length.is_some() && !opts.indel_only {
+        bail!("min_indel_length needs indel_only");
+    }
+    if opts.keep_tags.is_some() && opts.strip_tags.is_some() {
+        bail!("keep_tags and strip_tags cannot be used together");
+    }
+    if opts.denylist && (opts.rewrite_only || opts.untagged_only) {
+        bail!("denylist needs a barcode file and cannot be combined with rewrite_only or untagged_only");
+    }
+    if opts.unmatched_histogram.is_some()
+        && (barcodes_file.is_none() || opts.rewrite_only || opts.untagged_only || opts.denylist)
+    {
+        bail!("unmatched_histogram needs a barcode file and cannot be combined with rewrite_only, untagged_only or denylist");
+    }
+    if opts.extra_tag_whitelists.is_some()
+        && (barcodes_file.is_none()
+            || opts.rewrite_only
+            || opts.untagged_only
+            || opts.denylist
+            || opts.per_barcode_targets.is_some())
+    {
+        bail!("extra_tag_whitelists needs a barcode file and cannot be combined with rewrite_only, untagged_only, denylist or per_barcode_targets");
+    }
+    if opts.collapse_molecules.is_some()
+        && (opts.split_output_dir.is_some()
+            || !opts.regex_routes.is_empty()
+            || opts.num_shards.is_some()
+            || opts.fastq_output.is_some())
+    {
+        bail!("collapse_molecules only applies to the main output, so it cannot be combined with split_output_dir, regex_routes, num_shards or fastq_output");
+    }
+    if opts.match_any_tag && opts.extra_tag_whitelists.is_none() {
+        bail!("match_any_tag needs extra_tag_whitelists");
+    }
+    if opts.sort_by_name && opts.sort_by_tag {
+        bail!("sort_by_name and sort_by_tag cannot be used together");
+    }
+    if opts.sort_by_name && opts.set_sort_order.is_some() {
+        bail!("sort_by_name sets the sort order to queryname; drop set_sort_order");
+    }
+    if opts.denylist && opts.per_barcode_targets.is_some() {
+        bail!("denylist cannot be combined with per_barcode_targets");
+    }
+    if opts.num_shards == Some(0) {
+        bail!("num_shards must be at least 1");
+    }
+    if opts.interleave && opts.fastq_output.is_none() {
+        bail!("interleave only applies to FASTQ output");
+    }
+    if let Some(level) = opts.fastq_compression {
+        check_compression_level("fastq_compression", level)?;
+    }
+    let streaming_input = needs_streaming(bam_files);
+    if streaming_input && !can_stream(opts) {
+        bail!("SAM, CRAM, URL and stdin inputs cannot be used with route, shard, FASTQ, saturation or resume outputs");
+    }
+    // stdin can only be read once, so nothing may look at it before the scan
+    let from_stdin = bam_files.iter().any(|p| p.as_ref() == Path::new("-"));
+    if from_stdin
+        && (bam_files.len() > 1
+            || opts.header_template.is_some()
+            || opts.bed_file.is_some()
+            || opts.positions_file.is_some()
+            || opts.expected_input_md5.is_some()
+            || opts.idxstats_output.is_some())
+    {
+        bail!("Reading from stdin needs a single input and no header_template, bed_file, positions_file, expected_input_md5 or idxstats_output");
+    }
+
+    // reads from every input end up under a single header
+    if bam_files.len() > 1 {
+        check_header_compatibility(bam_files)?;
+    }
+
+    if let Some(expected_md5) = &opts.expected_input_md5 {
+        if bam_files.len() > 1 {
+            bail!("expected_input_md5 can only be checked for a single input BAM");
+        }
+        if is_url(bam_files[0].as_ref()) {
+            bail!("expected_input_md5 can only be checked for a local file, not a URL");
+        }
+        verify_input(bam_files[0].as_ref(), expected_md5)?;
+    }
+
+    // "the first N reads" only means something for a single sequential scan
+    let cores = match opts.head {
+        Some(n) if cores > 1 => {
+            info!("Head mode ({} reads) runs on a single core", n);
+            1
+        }
+        _ => cores,
+    };
+    // as does stopping once the output is big enough
+    let cores = match opts.max_output_bytes {
+        Some(_) if !can_stream(opts) => {
+            bail!("max_output_bytes cannot be used with route, shard, FASTQ, saturation or resume outputs")
+        }
+        Some(_) if cores > 1 => {
+            info!("max_output_bytes runs on a single core");
+            1
+        }
+        _ => cores,
+    };
+    let streaming = streaming_input || (cores == 1 && can_stream(opts));
+    let ordered = opts.ordered_writer && !streaming;
+    if ordered && opts.resume_dir.is_some() {
+        bail!("ordered_writer writes no chunk BAMs, so it cannot be combined with resume_dir");
+    }
+
+    // the output BAM is built here and only renamed into place once complete
+    let partial = PartialOutput::new(out_bam_file.as_ref());
+
+    // nothing to filter or rewrite: skip decompressing and recompressing every
+    // record. The index gives the read counts a filtering run would report, so
+    // without one the records are decoded as usual.
+    let plain_copy_reads =
+        if bam_files.len() == 1 && !streaming_input && is_plain_copy(opts, &to_replace) {
+            indexed_read_count(bam_files[0].as_ref())
+        } else {
+            None
+        };
+    if let Some(n_reads) = plain_copy_reads {
+        fs::copy(bam_files[0].as_ref(), &partial.path)?;
+        partial.commit(out_bam_file.as_ref())?;
+        info!(
+            "No filters or transforms requested, copied {} unchanged ({} reads)",
+            bam_files[0].as_ref().display(),
+            n_reads
+        );
+        return Ok(SubsampleStats {
+            output_path: out_bam_file.as_ref().to_path_buf(),
+            reads_scanned: n_reads,
+            reads_written: n_reads,
+            elapsed_seconds: start_time.elapsed().as_secs_f64(),
+            chunks: 0,
+            chunk_seconds: Vec::new(),
+            chunk_timings: Vec::new(),
+            barcode_counts: None,
+            split_counts: None,
+            molecules: None,
+            skipped_records: 0,
+            shard_counts: None,
+        });
+    }
+
+    // fail before scanning rather than at merge time
+    if let Some(template) = &opts.header_template {
+        let bam = open_input(bam_files[0].as_ref())?;
+        load_header_template(template, bam.header())?;
+    }
+
+    let translation = match &opts.translation_map {
+        Some(path) => Some(load_translation_map(path)?),
+        None => None,
+    };
+
+    let targets = match &opts.per_barcode_targets {
+        Some(path) => Some(load_barcode_targets(path, opts)?),
+        None => None,
+    };
+
+    let mut barcodes_hash = 0;
+    let matcher = match (&barcodes_file, opts.rewrite_only) {
+        (_, true) => None,
+        _ if opts.untagged_only => None,
+        // the targets file doubles as the whitelist
+        (None, false) if targets.is_some() => None,
+        (Some(barcodes_file), false) => {
+            let mut cell_barcodes = load_barcodes(barcodes_file, opts)?;
+            if let Some(n) = opts.sample_n_barcodes {
+                if n >= cell_barcodes.len() {
+                    warn!(
+                        "Asked to sample {} barcodes but the whitelist only has {}; keeping all",
+                        n,
+                        cell_barcodes.len()
+                    );
+                } else {
+                    let sampled = sample_barcodes(cell_barcodes, n, opts.seed);
+                    // keep the choice reproducible outside of this run
+                    let sidecar = PathBuf::from(format!(
+                        "{}.sampled_barcodes.txt",
+                        out_bam_file.as_ref().display()
+                    ));
+                    let mut out = sidecar_writer(&sidecar)?;
+                    for bc in &sampled {
+                        out.write_all(bc)?;
+                        out.write_all(b"\n")?;
+                    }
+                    out.finish()?;
+                    info!(
+                        "Sampled {} barcodes (seed {}), listed in {}",
+                        n,
+                        opts.seed,
+                        sidecar.display()
+                    );
+                    cell_barcodes = sampled.into_iter().collect();
+                }
+            }
+            barcodes_hash = hash_barcodes(&cell_barcodes);
+            Some(build_matcher(cell_barcodes, opts))
+        }
+        (None, false) => bail!("A barcode file is required unless rewrite_only is set"),
+    };
+
+    let mut tag_filters = Vec::new();
+    for (tag, path) in opts.extra_tag_whitelists.iter().flatten() {
+        let barcodes = load_barcodes(path, opts)?;
+        barcodes_hash = barcodes_hash.rotate_left(1) ^ hash_barcodes(&barcodes);
+        tag_filters.push(TagFilter {
+            tag: tag.clone(),
+            matcher: build_matcher(barcodes, opts),
+        });
+    }
+
+    if opts.regex_default_output.is_some() && opts.regex_routes.is_empty() {
+        bail!("regex_default_output needs at least one regex route");
+    }
+    let routes = opts
+        .regex_routes
+        .iter()
+        .map(|(pattern, _)| Regex::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let source_label = match (&opts.annotate_source_tag, &barcodes_file) {
+        (Some(_), Some(barcodes_file)) if !opts.rewrite_only && !opts.denylist => barcodes_file
+            .as_ref()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned()),
+        _ => None,
+    };
+
+    if opts.bed_file.is_some() || opts.positions_file.is_some() {
+        for bam_file in bam_files {
+            check_region_input(bam_file.as_ref())?;
+        }
+    }
+
+    let regions = match &opts.bed_file {
+        Some(bed_file) => {
+            let bam = open_input(bam_files[0].as_ref())?;
+            Some(load_bed(bed_file, bam.header())?)
+        }
+        None => None,
+    };
+
+    let positions = match &opts.positions_file {
+        Some(positions_file) => {
+            let bam = open_input(bam_files[0].as_ref())?;
+            Some(load_positions(positions_file, bam.header())?)
+        }
+        None => None,
+    };
+
+    let tmp_guard = run_temp_dir(opts)?;
+    let tmp_path = match &opts.resume_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.clone()
+        }
+        None => tmp_guard.as_ref().unwrap().path().to_path_buf(),
+    };
+    let options_hash = {
+        let mut hasher = DefaultHasher::new();
+        format!("{:?}{}{:?}{:?}", opts, bam_tag, to_replace, replacement).hash(&mut hasher);
+        hasher.finish()
+    };
+    let max_open_files = match opts.max_open_files {
+        0 => DEFAULT_MAX_OPEN_FILES,
+        n => n,
+    };
+    // chunks run concurrently, so share the handle budget between them
+    let max_open_files = cmp::max(1, max_open_files / cores.max(1));
+    let memory_bytes = opts.max_memory_mb.map(|mb| mb * 1024 * 1024);
+    let max_open_files = match memory_bytes {
+        Some(bytes) => {
+            let writers = cmp::max(1, bytes / cores.max(1) / WRITER_BUFFER_BYTES);
+            if writers < max_open_files {
+                debug!("Memory budget allows {} open writers per chunk", writers);
+            }
+            cmp::min(max_open_files, writers)
+        }
+        None => max_open_files,
+    };
+    let first_done = AtomicBool::new(false);
+    let abort = AtomicBool::new(false);
+    let progress = AtomicU64::new(0);
+    let write_errors = AtomicU64::new(0);
+
+    let merged_path = if opts.sort_by_tag || opts.sort_by_name {
+        tmp_path.join("merged.bam")
+    } else {
+        partial.path.clone()
+    };
+
+    let mut chunks = Vec::new();
+    let chunks_per_input = match opts.head {
+        Some(_) => 1,
+        None => cores * cmp::max(1, opts.chunks_per_core),
+    };
+
+    for bam_file in bam_files.iter().filter(|_| !streaming) {
+        let virtual_offsets = bgzf_noffsets(bam_file, &(chunks_per_input as u64))?;
+        for (virtual_start, virtual_stop) in virtual_offsets {
+            let c = SliceArgs {
+                matcher: matcher.as_deref(),
+                tag_filters: &tag_filters,
+                regions: regions.as_ref(),
+                positions: positions.as_ref(),
+                targets: targets.as_ref(),
+                translation: translation.as_ref(),
+                source_label: source_label.as_deref(),
+                routes: &routes,
+                i: chunks.len(),
+                bam_file: bam_file.as_ref(),
+                tmp_dir: &tmp_path,
+                chunk_key: opts.resume_dir.as_ref().map(|_| {
+                    chunk_key(
+                        bam_file.as_ref(),
+                        virtual_start,
+                        virtual_stop,
+                        barcodes_hash,
+                        options_hash,
+                    )
+                }),
+                max_open_files,
+                first_done: &first_done,
+                abort: &abort,
+                progress: &progress,
+                write_errors: &write_errors,
+                run_start: start_time,
+                bam_tag: bam_tag.clone(),
+                virtual_start,
+                virtual_stop,
+                to_replace: to_replace.clone(),
+                replacement: replacement.clone(),
+                opts,
+            };
+            chunks.push(c);
+        }
+    }
+    let pool = if streaming {
+        info!("Streaming {} input(s) on a single thread", bam_files.len());
+        None
+    } else {
+        if shared_pool.is_some() {
+            warn!("The shared thread pool is only used for single-threaded runs");
+        }
+        info!(
+            "Processing {} chunks on {} threads",
+            chunks.len(),
+            cmp::min(cores, chunks.len())
+        );
+        if ordered {
+            None
+        } else {
+            build_thread_pool(cores)
+        }
+    };
+    let ordered_writer = if ordered {
+        let bam = open_input(bam_files[0].as_ref())?;
+        Some(load_writer(
+            &bam,
+            &merged_path,
+            opts.header_template.as_deref(),
+            Some(opts.set_sort_order.as_deref().unwrap_or("unknown")),
+        )?)
+    } else {
+        None
+    };
+    let selector = RecordSelector {
+        matcher: matcher.as_deref(),
+        tag_filters: &tag_filters,
+        regions: regions.as_ref(),
+        positions: positions.as_ref(),
+        targets: targets.as_ref(),
+        translation: translation.as_ref(),
+        source_label: source_label.as_deref(),
+        bam_tag: &bam_tag,
+        to_replace: to_replace.as_deref(),
+        replacement: replacement.as_deref(),
+        opts,
+    };
+    let done = AtomicBool::new(false);
+    let results = thread::scope(|s| {
+        if let Some(interval) = opts.progress_interval {
+            // head stops chunks early, so the file size says little about the work
+            let total = if opts.head.is_none() {
+                estimate_total_reads(bam_files)
+            } else {
+                None
+            };
+            let (progress, done) = (&progress, &done);
+            s.spawn(move || log_progress(progress, done, interval, total));
+        }
+        let results = if streaming {
+            vec![subsample_bam_streaming(
+                bam_files,
+                &merged_path,
+                &tmp_path,
+                max_open_files,
+                &selector,
+                &progress,
+                &write_errors,
+                shared_pool,
+            )]
+        } else if let Some(writer) = ordered_writer {
+            match run_chunks_ordered(&chunks, cores, writer, &write_errors, opts) {
+                Ok(slices) => slices.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            }
+        } else {
+            run_chunks(pool.as_ref(), &chunks, |chunk| read_bam_slice(chunk))
+        };
+        done.store(true, Ordering::Relaxed);
+        results
+    });
+
+    let mut slices = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+    debug_assert_eq!(
+        progress.load(Ordering::Relaxed),
+        slices.iter().map(|s| s.reads_scanned).sum::<u64>()
+    );
+    if !streaming && !ordered {
+        // chunks that matched nothing only hold a header
+        let tmp_bams: Vec<_> = slices
+            .iter()
+            .filter(|s| s.reads_written > 0)
+            .map(|s| &s.path)
+            .collect();
+        merge_bams(
+            tmp_bams,
+            bam_files[0].as_ref(),
+            &merged_path,
+            opts.header_template.as_deref(),
+            Some(opts.set_sort_order.as_deref().unwrap_or("unknown")),
+            opts.assert_sorted,
+        )?;
+    }
+
+    if opts.sort_by_tag || opts.sort_by_name {
+        let header = {
+            let bam = bam::Reader::from_path(&merged_path)?;
+            bam::Header::from_template(bam.header())
+        };
+        let buffer_bytes = memory_bytes.unwrap_or(usize::MAX);
+        if opts.sort_by_tag {
+            external_sort(
+                &merged_path,
+                &partial.path,
+                &tmp_path,
+                &header,
+                DEFAULT_SORT_BUFFER_RECORDS,
+                buffer_bytes,
+                tag_sort_key(&bam_tag),
+            )?;
+        } else {
+            external_sort(
+                &merged_path,
+                &partial.path,
+                &tmp_path,
+                &with_sub_sort_order(
+                    &with_sort_order(&header, "queryname"),
+                    "queryname:lexicographical",
+                ),
+                DEFAULT_SORT_BUFFER_RECORDS,
+                buffer_bytes,
+                name_sort_key,
+            )?;
+        }
+        fs::remove_file(&merged_path)?;
+    }
+
+    let molecules = match &opts.collapse_molecules {
+        Some((cell_tag, umi_tag)) => Some(collapse_molecules(
+            &partial.path,
+            &tmp_path,
+            cell_tag,
+            umi_tag,
+        )?),
+        None => None,
+    };
+
+    if opts.fixmate {
+        fix_mates(&partial.path, &tmp_path)?;
+    }
+
+    // tids still follow the input header here, before any reordering or pruning
+    let output_header = bam::Reader::from_path(&partial.path)?.header().clone();
+    let mut flagstat = Flagstat::default();
+    let mut coverage = Coverage::default();
+    let mut tid_counts = vec![0; output_header.target_count() as usize + 1];
+    let wants_counts = opts.flagstat_output.is_some()
+        || opts.coverage_bed.is_some()
+        || opts.idxstats_output.is_some();
+    if wants_counts && (molecules.is_some() || opts.fixmate) {
+        // collapsing dropped reads the chunks counted, and fixmate changes mate flags
+        let mut bam = bam::Reader::from_path(&partial.path)?;
+        for r in bam.records() {
+            let rec = r?;
+            flagstat.add_record(&rec);
+            if opts.coverage_bed.is_some() {
+                coverage.add_record(&rec);
+            }
+            let tid_idx = if rec.tid() < 0 {
+                tid_counts.len() - 1
+            } else {
+                rec.tid() as usize
+            };
+            tid_counts[tid_idx] += 1;
+        }
+    } else {
+        for slice in &mut slices {
+            if let Some(f) = &slice.flagstat {
+                flagstat.merge(f);
+            }
+            if let Some(c) = slice.coverage.take() {
+                coverage.merge(c);
+            }
+            for (total, n) in tid_counts.iter_mut().zip(&slice.tid_counts) {
+                *total += n;
+            }
+        }
+    }
+    if let Some(flagstat_path) = &opts.flagstat_output {
+        flagstat.write(flagstat_path)?;
+    }
+    if let Some(coverage_path) = &opts.coverage_bed {
+        coverage.write(coverage_path, &output_header)?;
+    }
+    if let Some(idxstats_path) = &opts.idxstats_output {
+        write_idxstats(idxstats_path, &output_header, &tid_counts)?;
+    }
+
+    if let Some(reference) = &opts.reorder_to {
+        reorder_references(&partial.path, &tmp_path, reference)?;
+    }
+
+    // no record refers to a reference any more, so every @SQ line goes
+    if opts.prune_header || opts.strip_alignment {
+        prune_header(&partial.path, &tmp_path)?;
+    }
+
+    let mut stats = SubsampleStats {
+        output_path: PathBuf::from(&out_bam_file.as_ref()),
+        reads_scanned: slices.iter().map(|s| s.reads_scanned).sum(),
+        reads_written: molecules.unwrap_or_else(|| slices.iter().map(|s| s.reads_written).sum()),
+        elapsed_seconds: start_time.elapsed().as_secs_f64(),
+        chunks: slices.len(),
+        chunk_seconds: slices.iter().map(|s| s.elapsed_seconds).collect(),
+        chunk_timings: slices
+            .iter()
+            .map(|s| ChunkTiming {
+                start_seconds: s.started_seconds,
+                end_seconds: s.started_seconds + s.elapsed_seconds,
+                reads_scanned: s.reads_scanned,
+                reads_written: s.reads_written,
+            })
+            .collect(),
+        barcode_counts: targets.as_ref().map(|targets| {
+            targets
+                .iter()
+                .map(|(bc, t)| (bc.clone(), t.kept.load(Ordering::Relaxed)))
+                .collect()
+        }),
+        split_counts: None,
+        molecules,
+        skipped_records: slices.iter().map(|s| s.write_errors).sum(),
+        shard_counts: None,
+    };
+
+    if let Some(fastq_output) = &opts.fastq_output {
+        let parts: Vec<_> = slices.iter().filter_map(|s| s.fastq_path.clone()).collect();
+        concat_fastq(&parts, fastq_output)?;
+    }
+
+    if let Some(saturation_path) = &opts.saturation_output {
+        let mut saturation = Saturation::default();
+        for slice in &mut slices {
+            if let Some(sat) = slice.saturation.take() {
+                saturation.merge(sat);
+            }
+        }
+        saturation.write(saturation_path)?;
+    }
+
+    if let Some(unmatched_path) = &opts.unmatched_histogram {
+        let mut unmatched = UnmatchedBarcodes::default();
+        for slice in &mut slices {
+            if let Some(u) = slice.unmatched.take() {
+                unmatched.merge(u);
+            }
+        }
+        unmatched.write(unmatched_path)?;
+    }
+
+    if let Some(split_dir) = &opts.split_output_dir {
+        // parts carry the input header, and a streamed stdin input cannot be reopened
+        let first_part = slices
+            .iter()
+            .flat_map(|s| s.split_parts.values().flatten())
+            .next();
+        let header = match first_part {
+            Some(part) => bam::Header::from_template(bam::Reader::from_path(part)?.header()),
+            None => bam::Header::new(),
+        };
+        stats.split_counts = Some(write_split_outputs(
+            &slices,
+            split_dir,
+            &header,
+            opts.resume_dir.is_some(),
+        )?);
+    }
+
+    if !routes.is_empty() {
+        let bam = open_input(bam_files[0].as_ref())?;
+        let header = bam::Header::from_template(bam.header());
+        for (path, n) in write_route_outputs(&slices, opts, &header)? {
+            info!("Routed {} reads to {}", n, path.display());
+        }
+    }
+
+    if let Some(num_shards) = opts.num_shards {
+        let bam = open_input(bam_files[0].as_ref())?;
+        let header = bam::Header::from_template(bam.header());
+        stats.shard_counts = Some(write_shard_outputs(
+            &slices,
+            out_bam_file.as_ref(),
+            num_shards,
+            &header,
+            opts.resume_dir.is_some(),
+        )?);
+    }
+
+    if opts.validate_output {
+        validate_bam(&partial.path, stats.reads_written)?;
+    }
+    if let Some(multiqc_path) = &opts.multiqc_output {
+        write_multiqc(multiqc_path, &stats)?;
+    }
+    // only once every sidecar is written, so a failure leaves no final output
+    partial.commit(out_bam_file.as_ref())?;
+    info!(
+        "Wrote {} of {} reads in {:.1}s",
+        stats.reads_written, stats.reads_scanned, stats.elapsed_seconds
+    );
+
+    Ok(stats)
+}
+
+/// Reads sampled from the start of an unindexed BAM to estimate its read density.
+const DENSITY_SAMPLE_READS: u64 = 100_000;
+
+/// Progress samples the rolling throughput is measured over.
+const THROUGHPUT_WINDOW: usize = 10;
+
+/// Number of reads in a local BAM according to its index, unplaced unmapped
+/// reads included. `None` when there is no index to ask.
+fn indexed_read_count(bam_file: &Path) -> Option<u64> {
+    let mut bam = bam::IndexedReader::from_path(bam_file).ok()?;
+    let stats = bam.index_stats().ok()?;
+    Some(
+        stats
+            .iter()
+            .map(|(_, _, mapped, unmapped)| mapped + unmapped)
+            .sum(),
+    )
+}
+
+/// Approximate number of reads in `bam_files`, from the index when there is
+/// one and otherwise from the file size and the compressed bytes taken by the
+/// first reads. `None` for stdin or when no reads could be sampled.
+pub fn estimate_total_reads<P: AsRef<Path>>(bam_files: &[P]) -> Option<u64> {
+    let mut total = 0;
+    for bam_file in bam_files {
+        let bam_file = bam_file.as_ref();
+        if bam_file == Path::new("-") {
+            return None;
+        }
+        if let Some(n_reads) = indexed_read_count(bam_file) {
+            total += n_reads;
+            continue;
+        }
+        let file_bytes = fs::metadata(bam_file).ok()?.len();
+        let mut bam = bam::Reader::from_path(bam_file).ok()?;
+        let header_offset = (bam.tell() >> 16) as u64;
+        let mut sampled = 0;
+        let mut rec = Record::new();
+        while sampled < DENSITY_SAMPLE_READS {
+            match bam.read(&mut rec) {
+                Some(Ok(())) => sampled += 1,
+                _ => break,
+            }
+        }
+        if sampled < DENSITY_SAMPLE_READS {
+            // the whole file was read
+            total += sampled;
+            continue;
+        }
+        let sampled_bytes = ((bam.tell() >> 16) as u64).saturating_sub(header_offset);
+        if sampled == 0 || sampled_bytes == 0 {
+            return None;
+        }
+        total += (file_bytes - header_offset) * sampled / sampled_bytes;
+    }
+    Some(total)
+}
+
+/// Reads per second over the last `THROUGHPUT_WINDOW` progress samples.
+#[derive(Default)]
+pub struct Throughput {
+    samples: VecDeque<(f64, u64)>,
+}
+
+impl Throughput {
+    pub fn add(&mut self, seconds: f64, scanned: u64) {
+        if self.samples.len() == THROUGHPUT_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((seconds, scanned));
+    }
+
+    /// Rolling rate, or `None` until two samples are a moment apart.
+    pub fn rate(&self) -> Option<f64> {
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let seconds = last.0 - first.0;
+        if seconds <= 0.0 {
+            return None;
+        }
+        Some(last.1.saturating_sub(first.1) as f64 / seconds)
+    }
+
+    /// Seconds left to scan `total` reads. Takes the slower of the rolling and
+    /// overall rates, so a fast start or a burst does not shorten the estimate.
+    pub fn eta_seconds(&self, total: u64) -> Option<f64> {
+        let last = self.samples.back()?;
+        let overall = last.1 as f64 / last.0;
+        let rate = self.rate()?.min(overall);
+        if rate <= 0.0 {
+            return None;
+        }
+        Some(total.saturating_sub(last.1) as f64 / rate)
+    }
+}
+
+/// Output BAM written under `<name>.tmp` next to its final path, removed on
+/// drop unless `commit` renamed it into place, so a failed run never leaves
+/// a truncated file at the final path.
+struct PartialOutput {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl PartialOutput {
+    fn new(final_path: &Path) -> Self {
+        let name = final_path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        PartialOutput {
+            path: final_path.with_file_name(format!("{}.tmp", name)),
+            committed: false,
+        }
+    }
+
+    fn commit(mut self, final_path: &Path) -> Result<(), Error> {
+        fs::rename(&self.path, final_path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Logs the reads scanned so far every `interval` seconds until `done` is set,
+/// with an approximate ETA when the total number of reads is known.
+fn log_progress(progress: &AtomicU64, done: &AtomicBool, interval: u64, total: Option<u64>) {
+    let start = Instant::now();
+    let interval = Duration::from_secs(interval.max(1));
+    let mut next_log = interval;
+    let mut throughput = Throughput::default();
+    while !done.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(100));
+        let elapsed = start.elapsed();
+        if elapsed >= next_log {
+            let scanned = progress.load(Ordering::Relaxed);
+            throughput.add(elapsed.as_secs_f64(), scanned);
+            let rate = throughput
+                .rate()
+                .unwrap_or_else(|| scanned as f64 / elapsed.as_secs_f64());
+            match total.and_then(|t| throughput.eta_seconds(t).map(|eta| (t, eta))) {
+                Some((total, eta)) => info!(
+                    "Scanned {} of ~{} reads ({:.0} reads/s, approx. {:.0}s left)",
+                    scanned, total, rate, eta
+                ),
+                None => info!("Scanned {} reads ({:.0} reads/s)", scanned, rate),
+            }
+            next_log += interval;
+        }
+    }
+}
+
+pub fn build_thread_pool(cores: usize) -> Option<rayon::ThreadPool> {
+    match rayon::ThreadPoolBuilder::new().num_threads(cores).build() {
+        Ok(pool) => Some(pool),
+        Err(e) => {
+            warn!(
+                "Could not start a pool of {} threads ({}), processing chunks on the current thread",
+                cores, e
+            );
+            None
+        }
+    }
+}
+
+pub fn run_chunks<T, R, F>(pool: Option<&rayon::ThreadPool>, chunks: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    match pool {
+        Some(pool) => pool.install(|| chunks.par_iter().map(|c| f(c)).collect()),
+        None => chunks.iter().map(|c| f(c)).collect(),
+    }
+}
+
+pub fn count_bam_slice<P: AsRef<Path>>(
+    bam_file: P,
+    cell_barcodes: &HashSet<Vec<u8>>,
+    bam_tag: &str,
+    virtual_start: Option<i64>,
+    virtual_stop: Option<i64>,
+) -> Result<u64, rust_htslib::tpool::Error> {
+    let mut bam = bam::Reader::from_path(bam_file.as_ref())?;
+    let mut n_matching = 0;
+
+    for r in bam.iter_chunk(virtual_start, virtual_stop) {
+        let rec = r?;
+        if let Some(tag) = get_record_tag(&rec, bam_tag) {
+            if cell_barcodes.contains(&tag) {
+                n_matching += 1;
+            }
+        }
+    }
+    Ok(n_matching)
+}
+
+pub fn count_matching<P: AsRef<Path>>(
+    bam_file: P,
+    barcodes_file: P,
+    bam_tag: String,
+    cores: usize,
+) -> Result<u64, Error> {
+    let _ = SimpleLogger::init(LevelFilter::Info, Config::default());
+
+    let cell_barcodes = load_barcodes(&barcodes_file, &SubsampleOptions::default())?;
+    let virtual_offsets = bgzf_noffsets(&bam_file, &(cores as u64))?;
+
+    let bam_path = bam_file.as_ref();
+    let pool = build_thread_pool(cores);
+    let counts = run_chunks(
+        pool.as_ref(),
+        &virtual_offsets,
+        |(virtual_start, virtual_stop)| {
+            count_bam_slice(
+                bam_path,
+                &cell_barcodes,
+                &bam_tag,
+                *virtual_start,
+                *virtual_stop,
+            )
+        },
+    );
+
+    let counts: Result<Vec<_>, _> = counts.into_iter().collect();
+    let n_matching = counts?.iter().sum();
+    info!("{} reads carry a whitelisted {} tag", n_matching, bam_tag);
+    Ok(n_matching)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_HEADER: &[u8] = b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n";
+
+    fn test_header() -> bam::Header {
+        bam::Header::from_template(&bam::HeaderView::from_bytes(TEST_HEADER))
+    }
+
+    fn test_record(name: &str, pos: i64, barcode: &str) -> Record {
+        let mut rec = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(4)]);
+        rec.set(name.as_bytes(), Some(&cigar), b"ACGT", &[30; 4]);
+        rec.set_tid(0);
+        rec.set_pos(pos);
+        rec.set_mtid(-1);
+        rec.set_mpos(-1);
+        rec.push_aux(b"CB", Aux::String(barcode)).unwrap();
+        rec
+    }
+
+    fn write_test_bam(path: &Path, n_reads: usize) {
+        let mut out = bam::Writer::from_path(path, &test_header(), bam::Format::Bam).unwrap();
+        for i in 0..n_reads {
+            let barcode = if i % 2 == 0 { "AAAA" } else { "CCCC" };
+            out.write(&test_record(&format!("read{}", i), i as i64, barcode))
+                .unwrap();
+        }
+    }
+
+    fn count_chunk_records(path: &Path, offsets: &[(Option<i64>, Option<i64>)]) -> usize {
+        offsets
+            .iter()
+            .map(|(start, stop)| {
+                let mut bam = bam::Reader::from_path(path).unwrap();
+                bam.iter_chunk(*start, *stop).count()
+            })
+            .sum()
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test")
+            .join(name)
+    }
+
+    fn read_all(path: &Path) -> Vec<Record> {
+        let mut bam = bam::Reader::from_path(path).unwrap();
+        bam.records().map(|r| r.unwrap()).collect()
+    }
+
+    fn write_bam(path: &Path, header: &[u8], records: &[Record]) {
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(header));
+        let mut out = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+        for rec in records {
+            out.write(rec).unwrap();
+        }
+    }
+
+    fn write_barcodes(path: &Path, barcodes: &[&str]) -> PathBuf {
+        fs::write(path, barcodes.join("\n") + "\n").unwrap();
+        path.to_path_buf()
+    }
+
+    const TWO_CONTIG_HEADER: &[u8] =
+        b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n";
+
+    /// Reads on chr1 and chr2 plus an unplaced unmapped one.
+    fn two_contig_records() -> Vec<Record> {
+        let mut records = Vec::new();
+        for (i, (tid, barcode)) in [
+            (0, "AAAA"),
+            (0, "CCCC"),
+            (0, "AAAA"),
+            (1, "AAAA"),
+            (1, "AAAA"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let mut rec = test_record(&format!("read{}", i), 10 * i as i64, barcode);
+            rec.set_tid(*tid);
+            records.push(rec);
+        }
+        let mut unmapped = test_record("unmapped", -1, "AAAA");
+        unmapped.set_tid(-1);
+        unmapped.set_flags(0x4);
+        records.push(unmapped);
+        records
+    }
+
+    /// A read tagged CB, UB, NM, AS and RG, in that order.
+    fn five_tag_record() -> Record {
+        let mut rec = test_record("tagged", 100, "AACC");
+        rec.push_aux(b"UB", Aux::String("GGTT")).unwrap();
+        rec.push_aux(b"NM", Aux::U8(1)).unwrap();
+        rec.push_aux(b"AS", Aux::I32(-3)).unwrap();
+        rec.push_aux(b"RG", Aux::String("lane1")).unwrap();
+        rec
+    }
+
+    fn tag_names(rec: &Record) -> Vec<String> {
+        rec.aux_iter()
+            .map(|aux| String::from_utf8_lossy(aux.unwrap().0).into_owned())
+            .collect()
+    }
+
+    /// A single-core run with tag `CB` and no substitution.
+    fn run_subsample(
+        input: &Path,
+        barcodes: Option<PathBuf>,
+        out: &Path,
+        opts: &SubsampleOptions,
+    ) -> Result<SubsampleStats, Error> {
+        subsample_bam(
+            &[input.to_path_buf()],
+            barcodes,
+            "CB".to_string(),
+            None,
+            None,
+            out.to_path_buf(),
+            1,
+            opts,
+        )
+    }
+
+    #[test]
+    fn streaming_matches_chunked_output() {
+        let dir = tempdir().unwrap();
+        let streamed = dir.path().join("streamed.bam");
+        let chunked = dir.path().join("chunked.bam");
+        let opts = SubsampleOptions::default();
+        let run = |out: &PathBuf, cores: usize| {
+            subsample_bam(
+                &[fixture("eFL1_test.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                cores,
+                &opts,
+            )
+            .unwrap()
+        };
+        let streamed_stats = run(&streamed, 1);
+        let chunked_stats = run(&chunked, 4);
+        assert_eq!(streamed_stats.reads_scanned, chunked_stats.reads_scanned);
+        assert_eq!(streamed_stats.reads_written, chunked_stats.reads_written);
+        assert!(chunked_stats.chunks > 1);
+        let (streamed, chunked) = (read_all(&streamed), read_all(&chunked));
+        assert_eq!(streamed.len() as u64, streamed_stats.reads_written);
+        assert!(streamed.len() > 0);
+        assert!(streamed == chunked);
+    }
+
+    /// Records held in memory, to drive the core loop without any files.
+    struct MemoryReader {
+        header: bam::HeaderView,
+        records: std::vec::IntoIter<Record>,
+    }
+
+    impl RecordSource for MemoryReader {
+        fn read_record(&mut self, rec: &mut Record) -> Option<Result<(), Error>> {
+            *rec = self.records.next()?;
+            Some(Ok(()))
+        }
+
+        fn header_view(&self) -> &bam::HeaderView {
+            &self.header
+        }
+    }
+
+    fn memory_reader(n_reads: usize) -> MemoryReader {
+        let records: Vec<Record> = (0..n_reads)
+            .map(|i| {
+                let barcode = if i % 2 == 0 { "AAAA" } else { "CCCC" };
+                test_record(&format!("read{}", i), i as i64, barcode)
+            })
+            .collect();
+        MemoryReader {
+            header: bam::HeaderView::from_bytes(TEST_HEADER),
+            records: records.into_iter(),
+        }
+    }
+
+    fn test_selector<'a>(
+        matcher: &'a dyn BarcodeMatcher,
+        opts: &'a SubsampleOptions,
+    ) -> RecordSelector<'a> {
+        RecordSelector {
+            matcher: Some(matcher),
+            tag_filters: &[],
+            regions: None,
+            positions: None,
+            targets: None,
+            translation: None,
+            source_label: None,
+            bam_tag: "CB",
+            to_replace: None,
+            replacement: None,
+            opts,
+        }
+    }
+
+    #[test]
+    fn subsample_from_reader_in_memory() {
+        let matcher = ExactMatcher::new(vec![b"AAAA".to_vec()].into_iter().collect());
+        let opts = SubsampleOptions::default();
+        let mut written: Vec<Record> = Vec::new();
+        let counts = subsample_from_reader(
+            &mut memory_reader(6),
+            &mut written,
+            &test_selector(&matcher, &opts),
+        )
+        .unwrap();
+        assert_eq!(counts, (6, 3));
+        let names: Vec<&[u8]> = written.iter().map(|r| r.qname()).collect();
+        assert_eq!(names, vec![&b"read0"[..], b"read2", b"read4"]);
+    }
+
+    #[test]
+    fn subsample_from_reader_stops_at_head() {
+        let matcher = ExactMatcher::new(vec![b"CCCC".to_vec()].into_iter().collect());
+        let opts = SubsampleOptions {
+            head: Some(4),
+            ..Default::default()
+        };
+        let mut written: Vec<Record> = Vec::new();
+        let counts = subsample_from_reader(
+            &mut memory_reader(10),
+            &mut written,
+            &test_selector(&matcher, &opts),
+        )
+        .unwrap();
+        assert_eq!(counts, (4, 2));
+        assert_eq!(written.len(), 2);
+    }
+
+    fn clipped_record(cigar: Vec<Cigar>, len: usize) -> Record {
+        let seq: Vec<u8> = (0..len).map(|i| b"ACGT"[i % 4]).collect();
+        let qual: Vec<u8> = (0..len).map(|i| (i % 40) as u8).collect();
+        let mut rec = Record::new();
+        rec.set(b"clipped", Some(&CigarString(cigar)), &seq, &qual);
+        rec.set_tid(0);
+        rec.set_pos(100);
+        rec
+    }
+
+    #[test]
+    fn trim_softclips_removes_leading_clip() {
+        let mut rec = clipped_record(vec![Cigar::SoftClip(10), Cigar::Match(90)], 100);
+        let seq = rec.seq().as_bytes();
+        let qual = rec.qual().to_vec();
+        trim_softclips(&mut rec);
+        assert_eq!(rec.cigar().to_string(), "90M");
+        assert_eq!(rec.pos(), 100);
+        assert_eq!(rec.seq().len(), 90);
+        assert_eq!(rec.seq().as_bytes(), &seq[10..]);
+        assert_eq!(rec.qual(), &qual[10..]);
+    }
+
+    #[test]
+    fn trim_softclips_keeps_hard_clips() {
+        let mut rec = clipped_record(
+            vec![
+                Cigar::HardClip(5),
+                Cigar::SoftClip(3),
+                Cigar::Match(40),
+                Cigar::SoftClip(7),
+            ],
+            50,
+        );
+        let seq = rec.seq().as_bytes();
+        trim_softclips(&mut rec);
+        assert_eq!(rec.cigar().to_string(), "5H40M");
+        assert_eq!(rec.seq().as_bytes(), &seq[3..43]);
+    }
+
+    #[test]
+    fn trim_softclips_leaves_all_clip_reads() {
+        let mut rec = clipped_record(vec![Cigar::SoftClip(10)], 10);
+        trim_softclips(&mut rec);
+        assert_eq!(rec.cigar().to_string(), "10S");
+        assert_eq!(rec.seq().len(), 10);
+    }
+
+    #[test]
+    fn resume_reruns_only_the_chunk_without_a_marker() {
+        let dir = tempdir().unwrap();
+        let resume_dir = dir.path().join("resume");
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            resume_dir: Some(resume_dir.clone()),
+            split_output_dir: Some(dir.path().join("split")),
+            ..Default::default()
+        };
+        let run = || {
+            subsample_bam(
+                &[fixture("eFL1_test.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                4,
+                &opts,
+            )
+            .unwrap()
+        };
+        let markers = || {
+            let mut markers: Vec<(PathBuf, std::time::SystemTime)> = fs::read_dir(&resume_dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.extension().map_or(false, |ext| ext == "done"))
+                .map(|p| {
+                    let modified = fs::metadata(&p).unwrap().modified().unwrap();
+                    (p, modified)
+                })
+                .collect();
+            markers.sort();
+            markers
+        };
+
+        let first = run();
+        let first_records = read_all(&out);
+        let before = markers();
+        assert_eq!(before.len(), 4);
+
+        // with every marker in place nothing is rescanned
+        run();
+        assert_eq!(markers(), before);
+
+        fs::remove_file(&before[1].0).unwrap();
+        let resumed = run();
+        let after = markers();
+        assert_eq!(after.len(), 4);
+        for (i, ((path, modified), (path_after, modified_after))) in
+            before.iter().zip(&after).enumerate()
+        {
+            assert_eq!(path, path_after);
+            assert_eq!(modified == modified_after, i != 1);
+        }
+        assert_eq!(resumed.reads_written, first.reads_written);
+        assert_eq!(resumed.split_counts, first.split_counts);
+        assert!(read_all(&out) == first_records);
+    }
+
+    #[test]
+    fn tiny_file_is_one_chunk() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("tiny.bam");
+        write_test_bam(&path, 10);
+        // more chunks than bytes leaves no block boundary to split at
+        assert!(fs::metadata(&path).unwrap().len() < 4096);
+        let offsets = bgzf_noffsets(&path, &4096).unwrap();
+        assert_eq!(offsets, vec![(None, None)]);
+        assert_eq!(count_chunk_records(&path, &offsets), 10);
+    }
+
+    #[test]
+    fn tiny_file_chunks_cover_every_read_once() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("tiny.bam");
+        write_test_bam(&path, 10);
+        for num_chunks in 1..=32 {
+            let offsets = bgzf_noffsets(&path, &num_chunks).unwrap();
+            assert!(offsets.len() as u64 <= num_chunks);
+            assert_eq!(count_chunk_records(&path, &offsets), 10);
+        }
+    }
+
+    #[test]
+    fn large_file_gets_every_requested_chunk() {
+        let path = fixture("eFL1_test.bam");
+        let total = count_chunk_records(&path, &[(None, None)]);
+        for num_chunks in 2..=8 {
+            let offsets = bgzf_noffsets(&path, &num_chunks).unwrap();
+            assert_eq!(offsets.len() as u64, num_chunks);
+            assert_eq!(count_chunk_records(&path, &offsets), total);
+        }
+    }
+
+    #[test]
+    fn stable_hash_is_fixed() {
+        assert_eq!(stable_hash(b"", 0), 0xf52a_15e9_a9b5_e89b);
+        assert_eq!(stable_hash(b"ACGT", 0), 0x9348_eb03_8131_8bfe);
+        assert_eq!(stable_hash(b"ACGT", 42), 0x134d_c076_2a68_1e97);
+    }
+
+    #[test]
+    fn sample_barcodes_nests_by_seed() {
+        let barcodes: HashSet<Vec<u8>> = (0..100)
+            .map(|i| format!("BC{:03}", i).into_bytes())
+            .collect();
+        let ten = sample_barcodes(barcodes.clone(), 10, 7);
+        assert_eq!(ten.len(), 10);
+        assert_eq!(sample_barcodes(barcodes.clone(), 10, 7), ten);
+        let five = sample_barcodes(barcodes.clone(), 5, 7);
+        assert!(five.iter().all(|bc| ten.contains(bc)));
+        assert_ne!(sample_barcodes(barcodes, 10, 8), ten);
+    }
+
+    #[test]
+    fn empty_barcode_file_is_an_error() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("barcodes.tsv");
+        fs::write(&path, "").unwrap();
+        let err = load_barcodes(&path, &SubsampleOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("Loaded 0 barcodes"));
+    }
+
+    #[test]
+    fn blank_barcode_lines_are_skipped() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("barcodes.tsv");
+        fs::write(&path, "AAAA\n\nCCCC\n  \n").unwrap();
+        let opts = SubsampleOptions {
+            validate_dna: true,
+            ..Default::default()
+        };
+        let barcodes = load_barcodes(&path, &opts).unwrap();
+        assert_eq!(barcodes.len(), 2);
+        assert!(!barcodes.contains(&Vec::new()));
+    }
+
+    #[test]
+    fn plain_copy_reports_read_counts() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        fs::copy(fixture("eFL1_test.bam"), &input).unwrap();
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            ..Default::default()
+        };
+        let run = |out: &PathBuf| {
+            subsample_bam(
+                &[input.clone()],
+                None,
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                2,
+                &opts,
+            )
+            .unwrap()
+        };
+        let n_reads = read_all(&input).len() as u64;
+
+        // no index: the records are decoded and written as usual
+        let decoded = dir.path().join("decoded.bam");
+        let stats = run(&decoded);
+        assert!(stats.chunks > 0);
+        assert_eq!(stats.reads_written, n_reads);
+        assert_eq!(read_all(&decoded).len() as u64, n_reads);
+
+        bam::index::build(&input, None, bam::index::Type::Bai, 1).unwrap();
+        let copied = dir.path().join("copied.bam");
+        let stats = run(&copied);
+        assert_eq!(stats.chunks, 0);
+        assert_eq!(stats.reads_scanned, n_reads);
+        assert_eq!(stats.reads_written, n_reads);
+        assert_eq!(fs::read(&copied).unwrap(), fs::read(&input).unwrap());
+    }
+
+    #[test]
+    fn assert_sorted_rejects_unsorted_input_on_every_path() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("unsorted.bam");
+        {
+            let mut out = bam::Writer::from_path(&input, &test_header(), bam::Format::Bam).unwrap();
+            for (i, pos) in [100, 300, 200].iter().enumerate() {
+                out.write(&test_record(&format!("read{}", i), *pos, "AAAA"))
+                    .unwrap();
+            }
+        }
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            assert_sorted: true,
+            ..Default::default()
+        };
+        let ordered_opts = SubsampleOptions {
+            ordered_writer: true,
+            ..opts.clone()
+        };
+        // streamed, merged from chunk BAMs and written in order
+        for (cores, opts) in &[(1, &opts), (2, &opts), (2, &ordered_opts)] {
+            let err = subsample_bam(
+                &[input.clone()],
+                None,
+                "CB".to_string(),
+                None,
+                None,
+                dir.path().join("out.bam"),
+                *cores,
+                opts,
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains("out of coordinate order"));
+            assert!(!dir.path().join("out.bam").exists());
+        }
+    }
+
+    #[test]
+    fn missing_input_is_an_error() {
+        let dir = tempdir().unwrap();
+        for cores in 1..=2 {
+            let result = subsample_bam(
+                &[dir.path().join("missing.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                dir.path().join("out.bam"),
+                cores,
+                &SubsampleOptions::default(),
+            );
+            assert!(result.is_err());
+        }
+    }
+
+    fn fragment(name: &str, tlen: i64) -> Record {
+        let mut rec = test_record(name, 100, "AAAA");
+        // paired and properly paired
+        rec.set_flags(0x3);
+        rec.set_insert_size(tlen);
+        rec
+    }
+
+    #[test]
+    fn tlen_filter_keeps_fragments_in_the_window() {
+        let matcher = ExactMatcher::new(vec![b"AAAA".to_vec()].into_iter().collect());
+        let opts = SubsampleOptions {
+            min_tlen: Some(100),
+            max_tlen: Some(300),
+            ..Default::default()
+        };
+        let selector = test_selector(&matcher, &opts);
+        let kept = |tlen| {
+            selector
+                .select(&mut fragment("frag", tlen))
+                .unwrap()
+                .is_some()
+        };
+        assert!(!kept(50));
+        assert!(kept(200));
+        // read 2 of a pair has the negative insert size
+        assert!(kept(-200));
+        assert!(!kept(400));
+
+        let mut singleton = test_record("single", 100, "AAAA");
+        assert!(selector.select(&mut singleton).unwrap().is_none());
+        let opts = SubsampleOptions {
+            tlen_keep_unpaired: true,
+            ..opts
+        };
+        let selector = test_selector(&matcher, &opts);
+        assert!(selector.select(&mut singleton).unwrap().is_some());
+    }
+
+    #[test]
+    fn header_template_renames_contigs() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("ensembl.bam");
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(
+            b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:1\tLN:1000\n@SQ\tSN:2\tLN:500\n",
+        ));
+        {
+            let mut out = bam::Writer::from_path(&input, &header, bam::Format::Bam).unwrap();
+            for (i, tid) in [0, 0, 1].iter().enumerate() {
+                let mut rec = test_record(&format!("read{}", i), 10 * i as i64, "AAAA");
+                rec.set_tid(*tid);
+                out.write(&rec).unwrap();
+            }
+        }
+        let template = dir.path().join("ucsc.sam");
+        fs::write(
+            &template,
+            "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n",
+        )
+        .unwrap();
+        let run = |template: &Path| {
+            let opts = SubsampleOptions {
+                rewrite_only: true,
+                header_template: Some(template.to_path_buf()),
+                ..Default::default()
+            };
+            subsample_bam(
+                &[input.clone()],
+                None,
+                "CB".to_string(),
+                None,
+                None,
+                dir.path().join("out.bam"),
+                2,
+                &opts,
+            )
+        };
+        run(&template).unwrap();
+
+        let bam = bam::Reader::from_path(dir.path().join("out.bam")).unwrap();
+        assert_eq!(bam.header().target_names(), vec![&b"chr1"[..], b"chr2"]);
+        let text = String::from_utf8_lossy(bam.header().as_bytes()).into_owned();
+        assert!(text.contains("@SQ\tSN:chr1\tLN:1000"));
+        let tids: Vec<i32> = read_all(&dir.path().join("out.bam"))
+            .iter()
+            .map(|r| r.tid())
+            .collect();
+        assert_eq!(tids, vec![0, 0, 1]);
+
+        // a different length means the template is for another assembly
+        fs::write(&template, "@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:600\n").unwrap();
+        let err = run(&template).unwrap_err();
+        assert!(err.to_string().contains("has length"));
+    }
+
+    #[test]
+    fn reorder_references_reverses_two_contigs() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("two_contigs.bam");
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(
+            b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chrA\tLN:1000\n@SQ\tSN:chrB\tLN:500\n",
+        ));
+        {
+            let mut out = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+            let mut on_a = test_record("on_a", 10, "AAAA");
+            on_a.set_mtid(1);
+            on_a.set_mpos(20);
+            let mut on_b = test_record("on_b", 20, "AAAA");
+            on_b.set_tid(1);
+            on_b.set_mtid(0);
+            on_b.set_mpos(10);
+            out.write(&on_a).unwrap();
+            out.write(&on_b).unwrap();
+        }
+        let fai = dir.path().join("reversed.fa.fai");
+        fs::write(&fai, "chrB\t500\t6\t60\t61\nchrA\t1000\t520\t60\t61\n").unwrap();
+
+        reorder_references(&path, dir.path(), &fai).unwrap();
+
+        let bam = bam::Reader::from_path(&path).unwrap();
+        assert_eq!(bam.header().target_names(), vec![&b"chrB"[..], b"chrA"]);
+        let recs = read_all(&path);
+        // records keep their order, only their tids change
+        let names: Vec<&[u8]> = recs.iter().map(|r| r.qname()).collect();
+        assert_eq!(names, vec![&b"on_a"[..], b"on_b"]);
+        assert_eq!((recs[0].tid(), recs[0].mtid()), (1, 0));
+        assert_eq!((recs[1].tid(), recs[1].mtid()), (0, 1));
+
+        fs::write(&fai, "chrB\t500\t6\t60\t61\n").unwrap();
+        let err = reorder_references(&path, dir.path(), &fai).unwrap_err();
+        assert!(err.to_string().contains("is not in"));
+    }
+
+    #[test]
+    fn ordered_writer_matches_merged_chunks() {
+        let dir = tempdir().unwrap();
+        let run = |name: &str, ordered_writer: bool| {
+            let out = dir.path().join(name);
+            let opts = SubsampleOptions {
+                ordered_writer,
+                ..Default::default()
+            };
+            let stats = subsample_bam(
+                &[fixture("eFL1_test.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                4,
+                &opts,
+            )
+            .unwrap();
+            (stats, read_all(&out))
+        };
+        let (merged_stats, merged) = run("merged.bam", false);
+        let (ordered_stats, ordered) = run("ordered.bam", true);
+        assert!(ordered_stats.chunks > 1);
+        assert_eq!(ordered_stats.reads_written, merged_stats.reads_written);
+        assert!(!merged.is_empty());
+        assert!(ordered == merged);
+    }
+
+    #[test]
+    fn failed_merge_leaves_no_output() {
+        let dir = tempdir().unwrap();
+        let resume_dir = dir.path().join("resume");
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            resume_dir: Some(resume_dir.clone()),
+            ..Default::default()
+        };
+        let run = || {
+            subsample_bam(
+                &[fixture("eFL1_test.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                4,
+                &opts,
+            )
+        };
+        run().unwrap();
+        fs::remove_file(&out).unwrap();
+
+        // a completed chunk whose BAM is no longer readable fails the merge
+        let largest_chunk = fs::read_dir(&resume_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().map_or(false, |ext| ext == "bam"))
+            .max_by_key(|p| fs::metadata(p).unwrap().len())
+            .unwrap();
+        fs::write(&largest_chunk, b"not a BAM").unwrap();
+        assert!(run().is_err());
+        assert!(!out.exists());
+        assert!(!dir.path().join("out.bam.tmp").exists());
+    }
+
+    #[test]
+    fn corrupt_chunk_output_names_the_chunk() {
+        let dir = tempdir().unwrap();
+        let chunk = dir.path().join("3.bam");
+        write_test_bam(&chunk, 3);
+        verify_chunk_output(&chunk, 3, 3).unwrap();
+
+        let err = verify_chunk_output(&chunk, 5, 3).unwrap_err().to_string();
+        assert!(err.starts_with("Chunk 3 output"));
+        assert!(err.contains("holds 3 records but 5 were written"));
+
+        // dropping the EOF block is what a full disk leaves behind
+        let bytes = fs::read(&chunk).unwrap();
+        fs::write(&chunk, &bytes[..bytes.len() - BGZF_EOF.len()]).unwrap();
+        let err = verify_chunk_output(&chunk, 3, 3).unwrap_err();
+        assert!(err.to_string().starts_with("Chunk 3 output is corrupt"));
+    }
+
+    #[test]
+    fn failed_chunk_writes_no_marker() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("unsorted.bam");
+        {
+            let mut out = bam::Writer::from_path(&input, &test_header(), bam::Format::Bam).unwrap();
+            for (i, pos) in [300, 100].iter().enumerate() {
+                out.write(&test_record(&format!("read{}", i), *pos, "AAAA"))
+                    .unwrap();
+            }
+        }
+        let resume_dir = dir.path().join("resume");
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            assert_sorted: true,
+            resume_dir: Some(resume_dir.clone()),
+            ..Default::default()
+        };
+        let result = subsample_bam(
+            &[input],
+            None,
+            "CB".to_string(),
+            None,
+            None,
+            dir.path().join("out.bam"),
+            2,
+            &opts,
+        );
+        assert!(result.is_err());
+        assert!(!dir.path().join("out.bam").exists());
+        assert!(!dir.path().join("out.bam.tmp").exists());
+        let markers = fs::read_dir(&resume_dir)
+            .unwrap()
+            .filter(|e| {
+                let path = e.as_ref().unwrap().path();
+                path.extension().map_or(false, |ext| ext == "done")
+            })
+            .count();
+        assert_eq!(markers, 0);
+    }
+
+    #[test]
+    fn verify_input_checks_the_md5() {
+        let path = fixture("eFL1_test.bam");
+        let digest = format!("{:x}", md5::compute(fs::read(&path).unwrap()));
+        verify_input(&path, &digest).unwrap();
+        // as pasted from an md5sum line
+        verify_input(&path, &format!(" {}\n", digest.to_uppercase())).unwrap();
+
+        let err = verify_input(&path, "0123456789abcdef0123456789abcdef").unwrap_err();
+        assert!(err.to_string().contains("was expected"));
+    }
+
+    #[test]
+    fn strip_alignment_resets_reads() {
+        let mut rec = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(4)]);
+        rec.set(b"read", Some(&cigar), b"AACG", &[10, 20, 30, 40]);
+        rec.set_tid(0);
+        rec.set_pos(100);
+        rec.set_mtid(0);
+        rec.set_mpos(200);
+        rec.set_insert_size(104);
+        // paired, proper, reverse, mate reverse, read 1, duplicate
+        rec.set_flags(0x1 | 0x2 | 0x10 | 0x20 | 0x40 | 0x400);
+        rec.push_aux(b"NM", Aux::U8(1)).unwrap();
+        rec.push_aux(b"MD", Aux::String("3A0")).unwrap();
+        rec.push_aux(b"CB", Aux::String("AAAA")).unwrap();
+
+        strip_alignment(&mut rec).unwrap();
+        assert_eq!(rec.flags(), 0x1 | 0x4 | 0x8 | 0x40 | 0x400);
+        assert_eq!(
+            (rec.tid(), rec.pos(), rec.mtid(), rec.mpos()),
+            (-1, -1, -1, -1)
+        );
+        assert_eq!(rec.insert_size(), 0);
+        assert_eq!(rec.cigar_len(), 0);
+        // back on the strand it was sequenced from
+        assert_eq!(rec.seq().as_bytes(), b"CGTT".to_vec());
+        assert_eq!(rec.qual(), &[40, 30, 20, 10]);
+        assert!(rec.aux(b"NM").is_err());
+        assert!(rec.aux(b"MD").is_err());
+        assert_eq!(get_record_tag(&rec, "CB"), Some(b"AAAA".to_vec()));
+
+        let opts = SubsampleOptions {
+            strip_alignment: true,
+            ..Default::default()
+        };
+        let mut secondary = test_record("secondary", 100, "AAAA");
+        secondary.set_flags(0x100);
+        assert!(!passes_filters(&secondary, &opts));
+        let mut supplementary = test_record("supplementary", 100, "AAAA");
+        supplementary.set_flags(0x800);
+        assert!(!passes_filters(&supplementary, &opts));
+        assert!(passes_filters(&test_record("primary", 100, "AAAA"), &opts));
+    }
+
+    #[test]
+    fn translation_map_reads_gzip_and_names_malformed_lines() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("map.tsv.gz");
+        let write_gz = |text: &str| {
+            let mut gz = GzEncoder::new(fs::File::create(&path).unwrap(), Compression::default());
+            gz.write_all(text.as_bytes()).unwrap();
+            gz.finish().unwrap();
+        };
+        write_gz("AAAA\tCCCC\nGGGG\tTTTT\nACGT\tTGCA\n");
+        let map = load_translation_map(&path).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&b"GGGG".to_vec()], b"TTTT".to_vec());
+
+        write_gz("AAAA\tCCCC\nGGGG\tTTTT\tNNNN\n");
+        let err = load_translation_map(&path).unwrap_err();
+        assert!(err.to_string().starts_with("Line 2 of"));
+
+        // zstd magic, from either loader
+        fs::write(&path, [0x28, 0xb5, 0x2f, 0xfd, 0, 0]).unwrap();
+        let err = load_translation_map(&path).unwrap_err();
+        assert!(err.to_string().contains("zstd"));
+        let err = load_barcodes(&path, &SubsampleOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("zstd"));
+    }
+
+    #[test]
+    fn barcode_targets_are_normalised_like_the_whitelist() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("targets.tsv");
+        fs::write(&path, "aacc-1\t2\n").unwrap();
+        let opts = SubsampleOptions {
+            case_insensitive: true,
+            barcode_suffix_delimiter: Some(b'-'),
+            ..Default::default()
+        };
+        let targets = load_barcode_targets(&path, &opts).unwrap();
+        assert!(targets.contains_key(&b"AACC"[..]));
+
+        let matcher = build_matcher(vec![b"AACC".to_vec()].into_iter().collect(), &opts);
+        let selector = RecordSelector {
+            targets: Some(&targets),
+            ..test_selector(matcher.as_ref(), &opts)
+        };
+        let kept = (0..3)
+            .filter(|i| {
+                let mut rec = test_record(&format!("read{}", i), 10 * i, "AACC-1");
+                selector.select(&mut rec).unwrap().is_some()
+            })
+            .count();
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn sampled_barcodes_are_listed_next_to_the_output() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("sampled.bam");
+        let opts = SubsampleOptions {
+            sample_n_barcodes: Some(10),
+            seed: 7,
+            ..Default::default()
+        };
+        let run = || {
+            subsample_bam(
+                &[fixture("eFL1_test.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                1,
+                &opts,
+            )
+            .unwrap();
+            fs::read_to_string(dir.path().join("sampled.bam.sampled_barcodes.txt")).unwrap()
+        };
+        let listed = run();
+        let whitelist = fs::read_to_string(fixture("eFL1_barcodes_revert.tsv")).unwrap();
+        let whitelist: HashSet<_> = whitelist.lines().collect();
+        let sampled: HashSet<_> = listed.lines().collect();
+        assert_eq!(sampled.len(), 10);
+        assert!(sampled.is_subset(&whitelist));
+        assert_eq!(run(), listed);
+    }
+
+    #[test]
+    fn htslib_options_set_cram_required_fields() {
+        let dir = tempdir().unwrap();
+        let fasta = dir.path().join("ref.fa");
+        let mut seq = String::from(">chr1\n");
+        for _ in 0..100_000 / 50 {
+            seq.push_str(&"ACGT".repeat(25));
+            seq.push('\n');
+        }
+        fs::write(&fasta, seq).unwrap();
+        let input = dir.path().join("input.cram");
+        {
+            let mut out =
+                bam::Writer::from_path(&input, &test_header(), bam::Format::Cram).unwrap();
+            out.set_reference(&fasta).unwrap();
+            for i in 0..10 {
+                let barcode = if i % 2 == 0 { "AAAA" } else { "CCCC" };
+                out.write(&test_record(&format!("read{}", i), 100 * i, barcode))
+                    .unwrap();
+            }
+        }
+
+        let run = |options: Vec<(&str, String)>| {
+            let opts = SubsampleOptions {
+                rewrite_only: true,
+                htslib_options: Some(
+                    options
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v))
+                        .collect(),
+                ),
+                ..Default::default()
+            };
+            subsample_bam(
+                &[input.clone()],
+                None,
+                "CB".to_string(),
+                None,
+                None,
+                dir.path().join("out.bam"),
+                1,
+                &opts,
+            )
+        };
+        // everything but QUAL
+        let stats = run(vec![
+            ("reference", fasta.display().to_string()),
+            ("required_fields", "0xbff".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(stats.reads_written, 10);
+        let written = read_all(&dir.path().join("out.bam"));
+        assert_eq!(written.len(), 10);
+        assert_eq!(written[3].qname(), b"read3");
+        assert_eq!(written[3].aux(b"CB").unwrap(), Aux::String("CCCC"));
+
+        let err = run(vec![("required_feilds", "0xbff".to_string())]).unwrap_err();
+        assert!(err.to_string().contains("required_feilds"));
+    }
+
+    #[test]
+    fn name_hash_downsampling_is_reproducible() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("pairs.bam");
+        {
+            let mut out = bam::Writer::from_path(&input, &test_header(), bam::Format::Bam).unwrap();
+            // both mates of each template, 10 bases apart
+            for i in 0..400 {
+                out.write(&test_record(&format!("pair{}", i / 2), 10 * i, "AAAA"))
+                    .unwrap();
+            }
+        }
+        let run = |name: &str, seed: u32, cores: usize| {
+            let out = dir.path().join(name);
+            let opts = SubsampleOptions {
+                rewrite_only: true,
+                subsample_fraction: Some(0.3),
+                subsample_seed: seed,
+                ..Default::default()
+            };
+            subsample_bam(
+                &[input.clone()],
+                None,
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                cores,
+                &opts,
+            )
+            .unwrap();
+            read_all(&out)
+                .iter()
+                .map(|r| r.qname().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let kept = run("first.bam", 7, 1);
+        assert_eq!(run("second.bam", 7, 1), kept);
+        assert_eq!(run("chunked.bam", 7, 4), kept);
+        assert!(kept.len() > 60 && kept.len() < 180);
+        // mates hash alike, so templates are kept whole
+        for pair in kept.chunks(2) {
+            assert_eq!(pair[0], pair[1]);
+        }
+        assert_ne!(run("reseeded.bam", 8, 1), kept);
+    }
+
+    #[test]
+    fn shards_hold_disjoint_reads_summing_to_the_output() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("matched.bam");
+        let opts = SubsampleOptions {
+            num_shards: Some(3),
+            ..Default::default()
+        };
+        let stats = subsample_bam(
+            &[fixture("eFL1_test.bam")],
+            Some(fixture("eFL1_barcodes_revert.tsv")),
+            "CB".to_string(),
+            None,
+            None,
+            out.clone(),
+            2,
+            &opts,
+        )
+        .unwrap();
+        let key = |r: &Record| (r.qname().to_vec(), r.flags(), r.tid(), r.pos());
+        let mut matched: Vec<_> = read_all(&out).iter().map(key).collect();
+        assert!(!matched.is_empty());
+
+        let counts = stats.shard_counts.unwrap();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts.iter().sum::<u64>(), stats.reads_written);
+        let input_header = bam::Reader::from_path(fixture("eFL1_test.bam")).unwrap();
+        let mut sharded = Vec::new();
+        let mut names_seen: HashMap<Vec<u8>, usize> = HashMap::new();
+        for (k, n) in counts.iter().enumerate() {
+            let path = shard_path(&out, k);
+            let shard = bam::Reader::from_path(&path).unwrap();
+            assert_eq!(
+                shard.header().target_names(),
+                input_header.header().target_names()
+            );
+            let records = read_all(&path);
+            assert_eq!(records.len() as u64, *n);
+            for rec in &records {
+                // a read name, and so both mates, lands in a single shard
+                let shard_of = *names_seen.entry(rec.qname().to_vec()).or_insert(k);
+                assert_eq!(shard_of, k);
+            }
+            sharded.extend(records.iter().map(key));
+        }
+        matched.sort();
+        sharded.sort();
+        assert_eq!(sharded, matched);
+    }
+
+    #[test]
+    fn interleaved_fastq_writes_mates_adjacent() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("pairs.bam");
+        {
+            let mut out = bam::Writer::from_path(&input, &test_header(), bam::Format::Bam).unwrap();
+            let mut records = Vec::new();
+            for i in 0..3 {
+                let mut read1 = test_record(&format!("pair{}", i), 10 * i, "AAAA");
+                read1.set_flags(0x41);
+                records.push(read1);
+                // read 2 has its own sequence to tell the mates apart
+                let mut read2 = test_record(&format!("pair{}", i), 60 + 10 * i, "AAAA");
+                read2.set(
+                    format!("pair{}", i).as_bytes(),
+                    Some(&CigarString(vec![Cigar::Match(4)])),
+                    b"TTTT",
+                    &[30; 4],
+                );
+                read2.set_flags(0x81);
+                records.push(read2);
+            }
+            // paired, but its mate is not in the file
+            let mut lonely = test_record("lonely", 25, "AAAA");
+            lonely.set_flags(0x41);
+            records.push(lonely);
+            records.sort_by_key(|r| r.pos());
+            for rec in &records {
+                out.write(rec).unwrap();
+            }
+        }
+        let fastq = dir.path().join("reads.fq");
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            fastq_output: Some(fastq.clone()),
+            interleave: true,
+            ..Default::default()
+        };
+        subsample_bam(
+            &[input.clone()],
+            None,
+            "CB".to_string(),
+            None,
+            None,
+            dir.path().join("out.bam"),
+            1,
+            &opts,
+        )
+        .unwrap();
+
+        let text = fs::read_to_string(&fastq).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        let entries: Vec<_> = lines.chunks(4).map(|e| (e[0], e[1])).collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("@pair0", "ACGT"),
+                ("@pair0", "TTTT"),
+                ("@pair1", "ACGT"),
+                ("@pair1", "TTTT"),
+                ("@pair2", "ACGT"),
+                ("@pair2", "TTTT"),
+                ("@lonely", "ACGT"),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_url_input_matches_the_local_path() {
+        let dir = tempdir().unwrap();
+        let url = Url::from_file_path(fixture("eFL1_test.bam")).unwrap();
+        let input = PathBuf::from(url.as_str());
+        assert!(is_url(&input));
+        let run = |input: PathBuf, name: &str| {
+            let out = dir.path().join(name);
+            let stats = subsample_bam(
+                &[input],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                2,
+                &SubsampleOptions::default(),
+            )
+            .unwrap();
+            (stats.reads_written, read_all(&out))
+        };
+        let (n_remote, remote) = run(input, "remote.bam");
+        let (n_local, local) = run(fixture("eFL1_test.bam"), "local.bam");
+        assert!(n_local > 0);
+        assert_eq!(n_remote, n_local);
+        assert_eq!(remote, local);
+    }
+
+    #[test]
+    fn validate_output_catches_truncated_files() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("validated.bam");
+        let opts = SubsampleOptions {
+            validate_output: true,
+            ..Default::default()
+        };
+        let stats = subsample_bam(
+            &[fixture("eFL1_test.bam")],
+            Some(fixture("eFL1_barcodes_revert.tsv")),
+            "CB".to_string(),
+            None,
+            None,
+            out.clone(),
+            2,
+            &opts,
+        )
+        .unwrap();
+        validate_bam(&out, stats.reads_written).unwrap();
+        let err = validate_bam(&out, stats.reads_written + 1).unwrap_err();
+        assert!(err.to_string().contains("may be truncated"));
+
+        let bytes = fs::read(&out).unwrap();
+        fs::write(&out, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(validate_bam(&out, stats.reads_written).is_err());
+    }
+
+    #[test]
+    fn barcode_column_picks_a_field_of_three() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cells.tsv");
+        fs::write(&path, "cell1\tAACC\t120\ncell2\tGGTT\t80\n").unwrap();
+        let opts = SubsampleOptions {
+            barcode_column: Some(1),
+            ..Default::default()
+        };
+        let barcodes = load_barcodes(&path, &opts).unwrap();
+        let expected: HashSet<Vec<u8>> = vec![b"AACC".to_vec(), b"GGTT".to_vec()]
+            .into_iter()
+            .collect();
+        assert_eq!(barcodes, expected);
+
+        let opts = SubsampleOptions {
+            barcode_column: Some(3),
+            ..Default::default()
+        };
+        let err = load_barcodes(&path, &opts).unwrap_err();
+        assert!(err.to_string().contains("Line 1"));
+        assert!(err.to_string().contains("has no column 3"));
+    }
+
+    #[test]
+    fn min_aligned_length_counts_bases_between_clips() {
+        let opts = SubsampleOptions {
+            min_aligned_length: Some(8),
+            ..Default::default()
+        };
+        let reads = [
+            (vec![Cigar::Match(10)], 10, 10, true),
+            (vec![Cigar::SoftClip(2), Cigar::Match(8)], 10, 8, true),
+            (vec![Cigar::SoftClip(5), Cigar::Match(5)], 10, 5, false),
+            (
+                vec![
+                    Cigar::Match(4),
+                    Cigar::Del(3),
+                    Cigar::Equal(2),
+                    Cigar::Diff(1),
+                ],
+                7,
+                7,
+                false,
+            ),
+            (vec![Cigar::HardClip(30), Cigar::Match(10)], 10, 10, true),
+        ];
+        for (cigar, len, aligned, kept) in reads.iter() {
+            let rec = clipped_record(cigar.clone(), *len);
+            assert_eq!(aligned_length(&rec), *aligned);
+            assert_eq!(passes_filters(&rec, &opts), *kept);
+        }
+    }
+
+    #[test]
+    fn idxstats_counts_written_reads_per_contig() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("two_contigs.bam");
+        write_bam(&input, TWO_CONTIG_HEADER, &two_contig_records());
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let idxstats = dir.path().join("out.idxstats");
+        let opts = SubsampleOptions {
+            idxstats_output: Some(idxstats.clone()),
+            ..Default::default()
+        };
+        run_subsample(&input, Some(barcodes), &dir.path().join("out.bam"), &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(&idxstats).unwrap(),
+            "chr1\t1000\t2\nchr2\t500\t2\n*\t0\t1\n"
+        );
+    }
+
+    #[test]
+    fn strand_filter_keeps_reads_of_one_orientation() {
+        let with_flags = |flags: u16| {
+            let mut rec = test_record("read", 100, "AAAA");
+            rec.set_flags(flags);
+            rec
+        };
+        let forward = with_flags(0x0);
+        let reverse = with_flags(0x10);
+        // read 2, reverse, of a pair whose read 1 is forward
+        let read2 = with_flags(0x1 | 0x10 | 0x80);
+        let opts = |strand: &str, strand_from_read1| SubsampleOptions {
+            strand: Some(strand.parse().unwrap()),
+            strand_from_read1,
+            ..Default::default()
+        };
+
+        assert!(passes_strand_filter(&forward, &opts("forward", false)));
+        assert!(!passes_strand_filter(&reverse, &opts("forward", false)));
+        assert!(passes_strand_filter(&reverse, &opts("-", false)));
+        assert!(!passes_strand_filter(&forward, &opts("reverse", false)));
+        assert!(passes_strand_filter(&read2, &opts("reverse", false)));
+        assert!(passes_strand_filter(&read2, &opts("forward", true)));
+        assert!(passes_strand_filter(&forward, &SubsampleOptions::default()));
+        assert!("sideways".parse::<Strand>().is_err());
+    }
+
+    #[test]
+    fn rewrite_only_keeps_every_read_and_rewrites_its_tag() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_test_bam(&input, 10);
+        let out = dir.path().join("rewritten.bam");
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            ..Default::default()
+        };
+        let stats = subsample_bam(
+            &[input.clone()],
+            None,
+            "CB".to_string(),
+            Some("AA".to_string()),
+            Some("-".to_string()),
+            out.clone(),
+            1,
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(stats.reads_scanned, 10);
+        assert_eq!(stats.reads_written, 10);
+        let tags: Vec<_> = read_all(&out)
+            .iter()
+            .map(|r| match r.aux(b"CB").unwrap() {
+                Aux::String(s) => s.to_string(),
+                other => panic!("CB is {:?}", other),
+            })
+            .collect();
+        let expected: Vec<_> = (0..10)
+            .map(|i| if i % 2 == 0 { "--" } else { "CCCC" })
+            .collect();
+        assert_eq!(tags, expected);
+    }
+
+    #[test]
+    fn fastq_compression_level_trades_size_for_speed() {
+        let dir = tempdir().unwrap();
+        let run = |level: u32| {
+            let fastq = dir.path().join(format!("level{}.fq.gz", level));
+            let opts = SubsampleOptions {
+                rewrite_only: true,
+                fastq_output: Some(fastq.clone()),
+                fastq_compression: Some(level),
+                ..Default::default()
+            };
+            run_subsample(
+                &fixture("eFL1_test.bam"),
+                None,
+                &dir.path().join(format!("level{}.bam", level)),
+                &opts,
+            )
+            .map(|_| fastq)
+        };
+        let decompress = |path: &Path| {
+            let mut text = String::new();
+            let mut gz = MultiGzDecoder::new(fs::File::open(path).unwrap());
+            io::Read::read_to_string(&mut gz, &mut text).unwrap();
+            text
+        };
+        let stored = run(0).unwrap();
+        let best = run(9).unwrap();
+        assert!(fs::metadata(&stored).unwrap().len() > fs::metadata(&best).unwrap().len());
+        let text = decompress(&stored);
+        assert!(text.starts_with('@'));
+        assert_eq!(text, decompress(&best));
+
+        let err = run(10).unwrap_err();
+        assert!(err.to_string().contains("fastq_compression"));
+    }
+
+    #[test]
+    fn complete_chunks_are_skipped_on_rerun() {
+        let key = chunk_key(Path::new("in.bam"), Some(0), Some(1 << 16), 1, 2);
+        assert_eq!(
+            key,
+            chunk_key(Path::new("in.bam"), Some(0), Some(1 << 16), 1, 2)
+        );
+        assert_ne!(
+            key,
+            chunk_key(Path::new("in.bam"), Some(0), Some(1 << 16), 3, 2)
+        );
+        assert_ne!(
+            key,
+            chunk_key(Path::new("in.bam"), Some(0), Some(1 << 16), 1, 4)
+        );
+        assert_ne!(
+            key,
+            chunk_key(Path::new("other.bam"), Some(0), Some(1 << 16), 1, 2)
+        );
+
+        let dir = tempdir().unwrap();
+        let resume_dir = dir.path().join("resume");
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            resume_dir: Some(resume_dir.clone()),
+            ..Default::default()
+        };
+        let run = || {
+            subsample_bam(
+                &[fixture("eFL1_test.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                2,
+                &opts,
+            )
+            .unwrap()
+        };
+        let chunk_files = || {
+            let mut files: Vec<_> = fs::read_dir(&resume_dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .map(|p| {
+                    let modified = fs::metadata(&p).unwrap().modified().unwrap();
+                    (p, modified)
+                })
+                .collect();
+            files.sort();
+            files
+        };
+
+        let first = run();
+        let first_records = read_all(&out);
+        let before = chunk_files();
+        assert!(before
+            .iter()
+            .any(|(p, _)| p.extension().map_or(false, |ext| ext == "done")));
+        // the chunk files keep their names and are not rewritten
+        let rerun = run();
+        assert_eq!(chunk_files(), before);
+        assert_eq!(rerun.reads_written, first.reads_written);
+        assert!(read_all(&out) == first_records);
+    }
+
+    #[test]
+    fn mask_low_quality_masks_only_the_low_stretch() {
+        let mut rec = test_record("read", 100, "AAAA");
+        let cigar = CigarString(vec![Cigar::SoftClip(2), Cigar::Match(6)]);
+        let qual = [30, 30, 5, 2, 19, 20, 40, 30];
+        rec.set(b"read", Some(&cigar), b"ACGTACGT", &qual);
+        mask_low_quality(&mut rec, 20);
+        assert_eq!(rec.seq().as_bytes(), b"ACNNNCGT");
+        assert_eq!(rec.qual(), &qual);
+        assert_eq!(rec.cigar().to_string(), "2S6M");
+        assert_eq!(rec.aux(b"CB").unwrap(), Aux::String("AAAA"));
+
+        let mut high = test_record("high", 100, "AAAA");
+        mask_low_quality(&mut high, 20);
+        assert_eq!(high.seq().as_bytes(), b"ACGT");
+    }
+
+    #[test]
+    fn case_insensitive_matching_needs_the_flag() {
+        let dir = tempdir().unwrap();
+        let kept = |whitelist: &str, tag: &str, case_insensitive| {
+            let opts = SubsampleOptions {
+                case_insensitive,
+                ..Default::default()
+            };
+            let path = write_barcodes(&dir.path().join("barcodes.txt"), &[whitelist]);
+            let matcher = build_matcher(load_barcodes(&path, &opts).unwrap(), &opts);
+            let selector = test_selector(matcher.as_ref(), &opts);
+            let mut rec = test_record("read", 100, tag);
+            selector.select(&mut rec).unwrap().is_some()
+        };
+        assert!(!kept("AACC", "aacc", false));
+        assert!(kept("AACC", "aacc", true));
+        assert!(!kept("aacc", "AACC", false));
+        assert!(kept("aacc", "AACC", true));
+        assert!(kept("AACC", "AACC", false));
+        assert!(!kept("AACC", "aacg", true));
+    }
+
+    #[test]
+    fn remove_duplicates_drops_flagged_reads() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("marked.bam");
+        let records: Vec<_> = (0..6)
+            .map(|i| {
+                let mut rec = test_record(&format!("read{}", i), 10 * i, "AAAA");
+                if i % 3 == 0 {
+                    rec.set_duplicate();
+                }
+                rec
+            })
+            .collect();
+        write_bam(&input, TEST_HEADER, &records);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let names = |remove_duplicates| {
+            let out = dir.path().join("out.bam");
+            let opts = SubsampleOptions {
+                remove_duplicates,
+                ..Default::default()
+            };
+            run_subsample(&input, Some(barcodes.clone()), &out, &opts).unwrap();
+            read_all(&out)
+                .iter()
+                .map(|r| String::from_utf8_lossy(r.qname()).into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(false).len(), 6);
+        assert_eq!(names(true), vec!["read1", "read2", "read4", "read5"]);
+    }
+
+    #[test]
+    fn split_by_chrom_writes_a_bam_per_contig_and_unmapped() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("two_contigs.bam");
+        write_bam(&input, TWO_CONTIG_HEADER, &two_contig_records());
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let split_dir = dir.path().join("by_chrom");
+        let opts = SubsampleOptions {
+            split_output_dir: Some(split_dir.clone()),
+            split_by_chrom: true,
+            // one open writer at a time, so every contig switch reopens one
+            max_open_files: 1,
+            ..Default::default()
+        };
+        let stats =
+            run_subsample(&input, Some(barcodes), &dir.path().join("out.bam"), &opts).unwrap();
+        let expected: HashMap<Vec<u8>, u64> = vec![
+            (b"chr1".to_vec(), 2),
+            (b"chr2".to_vec(), 2),
+            (b"unmapped".to_vec(), 1),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(stats.split_counts, Some(expected));
+        for (name, tid, n) in [("chr1", 0, 2), ("chr2", 1, 2), ("unmapped", -1, 1)].iter() {
+            let path = split_dir.join(format!("{}.bam", name));
+            let records = read_all(&path);
+            assert_eq!(records.len(), *n);
+            assert!(records.iter().all(|r| r.tid() == *tid));
+            let bam = bam::Reader::from_path(&path).unwrap();
+            assert_eq!(bam.header().target_count(), 2);
+        }
+    }
+
+    #[test]
+    fn output_sort_order_is_unknown_unless_set() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("sorted.bam");
+        write_test_bam(&input, 100);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let sort_order = |cores: usize, set_sort_order: Option<&str>| {
+            let out = dir.path().join("out.bam");
+            let opts = SubsampleOptions {
+                set_sort_order: set_sort_order.map(|so| so.to_string()),
+                ..Default::default()
+            };
+            subsample_bam(
+                &[input.clone()],
+                Some(barcodes.clone()),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                cores,
+                &opts,
+            )
+            .unwrap();
+            let bam = bam::Reader::from_path(&out).unwrap();
+            header_sort_order(bam.header())
+        };
+        // streamed and chunked runs alike
+        for cores in [1, 2].iter() {
+            assert_eq!(sort_order(*cores, None).as_deref(), Some("unknown"));
+            assert_eq!(
+                sort_order(*cores, Some("coordinate")).as_deref(),
+                Some("coordinate")
+            );
+        }
+    }
+
+    #[test]
+    fn gzipped_barcode_files_are_decoded_not_garbage() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let dir = tempdir().unwrap();
+        let plain = fixture("eFL1_barcodes_revert.tsv");
+        // sniffed by content, so a gzipped file without the .gz extension works too
+        let gzipped = dir.path().join("barcodes.tsv");
+        let mut gz = GzEncoder::new(fs::File::create(&gzipped).unwrap(), Compression::default());
+        gz.write_all(&fs::read(&plain).unwrap()).unwrap();
+        gz.finish().unwrap();
+        assert!(is_gzip_file(&gzipped).unwrap());
+        assert!(!is_gzip_file(&plain).unwrap());
+
+        let opts = SubsampleOptions::default();
+        assert_eq!(
+            load_barcodes(&gzipped, &opts).unwrap(),
+            load_barcodes(&plain, &opts).unwrap()
+        );
+        let written = |barcodes: PathBuf| {
+            run_subsample(
+                &fixture("eFL1_test.bam"),
+                Some(barcodes),
+                &dir.path().join("out.bam"),
+                &opts,
+            )
+            .unwrap()
+            .reads_written
+        };
+        let n_plain = written(plain);
+        assert!(n_plain > 0);
+        assert_eq!(written(gzipped), n_plain);
+    }
+
+    #[test]
+    fn prune_header_drops_contigs_without_reads() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("four_contigs.bam");
+        let mut records = Vec::new();
+        for (i, (tid, mtid, barcode)) in [(0, -1, "AAAA"), (1, -1, "CCCC"), (2, 3, "AAAA")]
+            .iter()
+            .enumerate()
+        {
+            let mut rec = test_record(&format!("read{}", i), 100, barcode);
+            rec.set_tid(*tid);
+            rec.set_mtid(*mtid);
+            records.push(rec);
+        }
+        write_bam(
+            &input,
+            b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chrA\tLN:1000\n@SQ\tSN:chrB\tLN:1000\n\
+              @SQ\tSN:chrC\tLN:1000\n@SQ\tSN:chrD\tLN:1000\n",
+            &records,
+        );
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let out = dir.path().join("pruned.bam");
+        let opts = SubsampleOptions {
+            prune_header: true,
+            ..Default::default()
+        };
+        run_subsample(&input, Some(barcodes), &out, &opts).unwrap();
+
+        // chrD only holds a mate, which keeps it
+        let bam = bam::Reader::from_path(&out).unwrap();
+        assert_eq!(
+            bam.header().target_names(),
+            vec![&b"chrA"[..], &b"chrC"[..], &b"chrD"[..]]
+        );
+        let written = read_all(&out);
+        assert_eq!(written.len(), 2);
+        assert_eq!((written[0].tid(), written[0].mtid()), (0, -1));
+        assert_eq!((written[1].tid(), written[1].mtid()), (1, 2));
+        assert_eq!(written[1].qname(), b"read2");
+    }
+
+    #[test]
+    fn mixed_barcode_lengths_are_reported() {
+        let dir = tempdir().unwrap();
+        let path = write_barcodes(&dir.path().join("mixed.txt"), &["AACCGG", "ACGT", "TTGGCC"]);
+        let barcodes = load_barcodes(&path, &SubsampleOptions::default()).unwrap();
+        assert_eq!(
+            mixed_length_summary(&barcodes).as_deref(),
+            Some("1 of length 4, 2 of length 6")
+        );
+        // a warning by default, an error when strict
+        check_barcode_lengths(&barcodes, false).unwrap();
+        let err = check_barcode_lengths(&barcodes, true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Barcodes have mixed lengths: 1 of length 4, 2 of length 6"
+        );
+
+        let same = write_barcodes(&dir.path().join("same.txt"), &["AACC", "ACGT"]);
+        let barcodes = load_barcodes(&same, &SubsampleOptions::default()).unwrap();
+        assert_eq!(mixed_length_summary(&barcodes), None);
+        check_barcode_lengths(&barcodes, true).unwrap();
+    }
+
+    #[test]
+    fn head_scans_exactly_n_reads() {
+        let dir = tempdir().unwrap();
+        let opts = SubsampleOptions {
+            head: Some(1000),
+            ..Default::default()
+        };
+        let out = dir.path().join("head.bam");
+        // more cores than head mode uses
+        let stats = subsample_bam(
+            &[fixture("eFL1_test.bam")],
+            Some(fixture("eFL1_barcodes_revert.tsv")),
+            "CB".to_string(),
+            None,
+            None,
+            out.clone(),
+            4,
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(stats.reads_scanned, 1000);
+
+        let whitelist = load_barcodes(
+            fixture("eFL1_barcodes_revert.tsv"),
+            &SubsampleOptions::default(),
+        )
+        .unwrap();
+        let expected: Vec<_> = read_all(&fixture("eFL1_test.bam"))
+            .into_iter()
+            .take(1000)
+            .filter(|r| get_record_tag(r, "CB").map_or(false, |t| whitelist.contains(&t)))
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(stats.reads_written, expected.len() as u64);
+        assert!(read_all(&out) == expected);
+    }
+
+    #[test]
+    fn progress_counter_ends_at_reads_scanned() {
+        let matcher = ExactMatcher::new(vec![b"AAAA".to_vec()].into_iter().collect());
+        let opts = SubsampleOptions::default();
+        let selector = test_selector(&matcher, &opts);
+        let progress = AtomicU64::new(0);
+        let write_errors = AtomicU64::new(0);
+        let header = bam::HeaderView::from_bytes(TEST_HEADER);
+
+        // two chunks sharing the counter, neither a whole number of batches
+        let mut reads_scanned = 0;
+        for n_reads in [25_003, 7].iter() {
+            let mut pipeline = RecordPipeline::new(&selector, &[], 0, &progress, &header);
+            let mut written: Vec<Record> = Vec::new();
+            let before = progress.load(Ordering::Relaxed);
+            stream_records(
+                &mut memory_reader(*n_reads),
+                &mut written,
+                &mut pipeline,
+                false,
+                None,
+                &write_errors,
+            )
+            .unwrap();
+            // only whole batches are added while scanning
+            let batches = (*n_reads as u64 / PROGRESS_BATCH) * PROGRESS_BATCH;
+            assert_eq!(progress.load(Ordering::Relaxed) - before, batches);
+            let result = pipeline
+                .finish(Path::new(""), None, Instant::now(), 0.0)
+                .unwrap();
+            reads_scanned += result.reads_scanned;
+        }
+        assert_eq!(reads_scanned, 25_010);
+        assert_eq!(progress.load(Ordering::Relaxed), reads_scanned);
+    }
+
+    #[test]
+    fn keep_tags_retains_only_the_requested_tags() {
+        let mut rec = five_tag_record();
+        assert_eq!(tag_names(&rec), vec!["CB", "UB", "NM", "AS", "RG"]);
+        retain_tags(&mut rec, &["NM".to_string(), "CB".to_string()]).unwrap();
+        assert_eq!(tag_names(&rec), vec!["CB", "NM"]);
+        assert_eq!(rec.aux(b"CB").unwrap(), Aux::String("AACC"));
+        assert_eq!(rec.aux(b"NM").unwrap(), Aux::U8(1));
+    }
+
+    #[test]
+    fn strip_tags_removes_only_the_named_tag() {
+        let mut rec = five_tag_record();
+        // a tag the read does not carry is no error
+        strip_tags(&mut rec, &["AS".to_string(), "XS".to_string()]).unwrap();
+        assert_eq!(tag_names(&rec), vec!["CB", "UB", "NM", "RG"]);
+        assert_eq!(rec.aux(b"UB").unwrap(), Aux::String("GGTT"));
+        assert_eq!(rec.aux(b"NM").unwrap(), Aux::U8(1));
+        assert_eq!(rec.aux(b"RG").unwrap(), Aux::String("lane1"));
+    }
+
+    #[test]
+    fn tag_field_matches_one_field_of_the_tag() {
+        assert_eq!(
+            extract_tag_field(b"sampleA:AACCGG:extra", ':', 1),
+            Some(b"AACCGG".to_vec())
+        );
+        assert_eq!(extract_tag_field(b"sampleA:AACCGG:extra", ':', 3), None);
+        assert_eq!(
+            extract_tag_field(b"AACCGG", ':', 0),
+            Some(b"AACCGG".to_vec())
+        );
+
+        let matcher = ExactMatcher::new(vec![b"AACCGG".to_vec()].into_iter().collect());
+        let opts = SubsampleOptions {
+            tag_field: Some((':', 1)),
+            ..Default::default()
+        };
+        let selector = test_selector(&matcher, &opts);
+        let kept = |tag: &str| {
+            selector
+                .select(&mut test_record("read", 100, tag))
+                .unwrap()
+                .is_some()
+        };
+        assert!(kept("sampleA:AACCGG:extra"));
+        assert!(kept("sampleB:AACCGG"));
+        assert!(!kept("AACCGG:sampleA:extra"));
+        assert!(!kept("AACCGG"));
+    }
+
+    #[test]
+    fn per_chunk_timings_are_reported() {
+        let dir = tempdir().unwrap();
+        let stats = subsample_bam(
+            &[fixture("eFL1_test.bam")],
+            Some(fixture("eFL1_barcodes_revert.tsv")),
+            "CB".to_string(),
+            None,
+            None,
+            dir.path().join("out.bam"),
+            4,
+            &SubsampleOptions::default(),
+        )
+        .unwrap();
+        assert!(stats.chunks > 1);
+        assert_eq!(stats.chunk_seconds.len(), stats.chunks);
+        assert_eq!(stats.chunk_timings.len(), stats.chunks);
+        for (timing, seconds) in stats.chunk_timings.iter().zip(&stats.chunk_seconds) {
+            assert!(timing.start_seconds >= 0.0);
+            assert!(timing.end_seconds >= timing.start_seconds);
+            assert!(timing.end_seconds <= stats.elapsed_seconds);
+            assert!((timing.end_seconds - timing.start_seconds - seconds).abs() < 1e-9);
+            assert!(timing.reads_scanned > 0);
+        }
+        let sum = |f: fn(&ChunkTiming) -> u64| stats.chunk_timings.iter().map(f).sum::<u64>();
+        assert_eq!(sum(|t| t.reads_scanned), stats.reads_scanned);
+        assert_eq!(sum(|t| t.reads_written), stats.reads_written);
+    }
+
+    #[test]
+    fn csv_barcodes_skip_the_header_row() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cells.csv");
+        fs::write(&path, "cell_type,barcode\nT cell, AACC\nB cell,GGTT\n").unwrap();
+        let opts = SubsampleOptions {
+            barcode_has_header: true,
+            barcode_column: Some(1),
+            ..Default::default()
+        };
+        // fields are split on commas and trimmed, so "T cell" stays one field
+        let expected: HashSet<Vec<u8>> = vec![b"AACC".to_vec(), b"GGTT".to_vec()]
+            .into_iter()
+            .collect();
+        assert_eq!(load_barcodes(&path, &opts).unwrap(), expected);
+
+        // without skipping it, the header is read as a barcode
+        let opts = SubsampleOptions {
+            barcode_has_header: false,
+            ..opts
+        };
+        assert!(load_barcodes(&path, &opts)
+            .unwrap()
+            .contains(&b"barcode"[..]));
+    }
+
+    #[test]
+    fn mate_option_writes_only_the_requested_mate() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("pairs.bam");
+        let mut records = Vec::new();
+        for (i, flags) in [0x41, 0x81, 0x41, 0x81, 0x0].iter().enumerate() {
+            let mut rec = test_record(&format!("read{}", i), 10 * i as i64, "AAAA");
+            rec.set_flags(*flags);
+            records.push(rec);
+        }
+        write_bam(&input, TEST_HEADER, &records);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let written = |mate: &str, mate_keep_unpaired| {
+            let out = dir.path().join("out.bam");
+            let opts = SubsampleOptions {
+                mate: Some(mate.parse().unwrap()),
+                mate_keep_unpaired,
+                ..Default::default()
+            };
+            run_subsample(&input, Some(barcodes.clone()), &out, &opts).unwrap();
+            read_all(&out)
+                .iter()
+                .map(|r| String::from_utf8_lossy(r.qname()).into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(written("read1", false), vec!["read0", "read2"]);
+        assert_eq!(written("r2", false), vec!["read1", "read3"]);
+        assert_eq!(written("read2", true), vec!["read1", "read3", "read4"]);
+        assert!("read3".parse::<Mate>().is_err());
+    }
+
+    /// Collects records, failing to write the ones named in `fail`.
+    struct FailingSink {
+        fail: Vec<&'static [u8]>,
+        written: Vec<Record>,
+    }
+
+    impl RecordSink for FailingSink {
+        fn write_record(&mut self, rec: &Record) -> Result<(), Error> {
+            if self.fail.iter().any(|name| *name == rec.qname()) {
+                bail!("injected failure");
+            }
+            self.written.push(rec.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_error_policy_skips_up_to_the_limit() {
+        let matcher = ExactMatcher::new(
+            vec![b"AAAA".to_vec(), b"CCCC".to_vec()]
+                .into_iter()
+                .collect(),
+        );
+        let run = |max_write_errors| {
+            let opts = SubsampleOptions {
+                max_write_errors,
+                ..Default::default()
+            };
+            let mut sink = FailingSink {
+                fail: vec![&b"read3"[..], &b"read7"[..]],
+                written: Vec::new(),
+            };
+            subsample_from_reader(
+                &mut memory_reader(10),
+                &mut sink,
+                &test_selector(&matcher, &opts),
+            )
+            .map(|counts| (counts, sink.written.len()))
+        };
+        assert_eq!(run(Some(2)).unwrap(), ((10, 8), 8));
+        let err = run(Some(1)).unwrap_err().to_string();
+        assert!(err.contains("read7"));
+        assert!(err.contains("more than 1 records failed to write"));
+        let err = run(None).unwrap_err().to_string();
+        assert_eq!(err, "Cannot write record read3: injected failure");
+    }
+
+    #[test]
+    fn source_tag_names_the_matched_whitelist() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let mut records: Vec<_> = (0..4)
+            .map(|i| {
+                let barcode = if i % 2 == 0 { "AAAA" } else { "CCCC" };
+                test_record(&format!("read{}", i), 10 * i, barcode)
+            })
+            .collect();
+        // an existing value is replaced, not duplicated
+        records[2].push_aux(b"SM", Aux::String("old")).unwrap();
+        write_bam(&input, TEST_HEADER, &records);
+        let whitelist = write_barcodes(&dir.path().join("A.txt"), &["AAAA"]);
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            annotate_source_tag: Some("SM".to_string()),
+            ..Default::default()
+        };
+        run_subsample(&input, Some(whitelist), &out, &opts).unwrap();
+
+        let written = read_all(&out);
+        assert_eq!(written.len(), 2);
+        for rec in &written {
+            assert_eq!(rec.aux(b"SM").unwrap(), Aux::String("A"));
+            assert_eq!(tag_names(rec).iter().filter(|t| *t == "SM").count(), 1);
+        }
+    }
+
+    #[test]
+    fn missing_output_directory_is_created_or_reported() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_test_bam(&input, 10);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let out = dir.path().join("nested").join("deeper").join("out.bam");
+
+        let err = run_subsample(
+            &input,
+            Some(barcodes.clone()),
+            &out,
+            &SubsampleOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Output directory {} does not exist",
+                out.parent().unwrap().display()
+            )
+        );
+        assert!(!dir.path().join("nested").exists());
+
+        let opts = SubsampleOptions {
+            create_output_dirs: true,
+            ..Default::default()
+        };
+        let stats = run_subsample(&input, Some(barcodes), &out, &opts).unwrap();
+        assert_eq!(stats.reads_written, 5);
+        assert_eq!(read_all(&out).len(), 5);
+    }
+
+    #[test]
+    fn temp_dir_carries_the_prefix() {
+        let opts = SubsampleOptions {
+            temp_prefix: Some("tenant42-".to_string()),
+            ..Default::default()
+        };
+        let tmp = run_temp_dir(&opts).unwrap().unwrap();
+        let name = tmp
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert!(name.starts_with("tenant42-"), "{}", name);
+        assert!(tmp.path().is_dir());
+        let path = tmp.path().to_path_buf();
+        drop(tmp);
+        assert!(!path.exists());
+
+        let opts = SubsampleOptions {
+            resume_dir: Some(PathBuf::from("resume")),
+            ..opts
+        };
+        assert!(run_temp_dir(&opts).unwrap().is_none());
+
+        // and a run with the prefix still completes and cleans up
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_test_bam(&input, 10);
+        let opts = SubsampleOptions {
+            temp_prefix: Some("tenant42-".to_string()),
+            rewrite_only: true,
+            ..Default::default()
+        };
+        run_subsample(&input, None, &dir.path().join("out.bam"), &opts).unwrap();
+    }
+
+    #[test]
+    fn regex_routes_write_each_tag_to_its_output() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_test_bam(&input, 10);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA", "CCCC"]);
+        let a_out = dir.path().join("a.bam");
+        let c_out = dir.path().join("c.bam");
+        let opts = SubsampleOptions {
+            regex_routes: vec![
+                ("^AA".to_string(), a_out.clone()),
+                ("^CC".to_string(), c_out.clone()),
+            ],
+            ..Default::default()
+        };
+        run_subsample(&input, Some(barcodes), &dir.path().join("out.bam"), &opts).unwrap();
+
+        for (path, barcode) in [(&a_out, "AAAA"), (&c_out, "CCCC")].iter() {
+            let records = read_all(path);
+            assert_eq!(records.len(), 5);
+            for rec in &records {
+                assert_eq!(rec.aux(b"CB").unwrap(), Aux::String(*barcode));
+            }
+        }
+        assert_eq!(read_all(&dir.path().join("out.bam")).len(), 10);
+    }
+
+    #[test]
+    fn small_memory_budget_drops_no_records() {
+        let dir = tempdir().unwrap();
+        let run = |name: &str, max_memory_mb| {
+            let split_dir = dir.path().join(format!("split_{}", name));
+            let out = dir.path().join(format!("{}.bam", name));
+            let opts = SubsampleOptions {
+                split_output_dir: Some(split_dir.clone()),
+                sort_by_tag: true,
+                max_memory_mb,
+                ..Default::default()
+            };
+            subsample_bam(
+                &[fixture("eFL1_test.bam")],
+                Some(fixture("eFL1_barcodes_revert.tsv")),
+                "CB".to_string(),
+                None,
+                None,
+                out.clone(),
+                4,
+                &opts,
+            )
+            .unwrap();
+            let split_total: usize = fs::read_dir(&split_dir)
+                .unwrap()
+                .map(|e| read_all(&e.unwrap().path()).len())
+                .sum();
+            (read_all(&out).len(), split_total)
+        };
+
+        let unbounded = run("unbounded", None);
+        assert!(unbounded.0 > 0);
+        assert_eq!(unbounded.0, unbounded.1);
+        // one open split writer per chunk and a sort that spills several runs
+        assert_eq!(run("bounded", Some(1)), unbounded);
+    }
+
+    /// Tagged reads `read0`..`read2` (AAAA, CCCC, GGGG) and untagged
+    /// `untagged0`, `untagged1`.
+    fn partly_tagged_records() -> Vec<Record> {
+        let mut records = Vec::new();
+        for (i, barcode) in ["AAAA", "CCCC", "GGGG"].iter().enumerate() {
+            records.push(test_record(&format!("read{}", i), 10 * i as i64, barcode));
+        }
+        for i in 0..2 {
+            let mut rec = test_record(&format!("untagged{}", i), 100 + i as i64, "AAAA");
+            rec.remove_aux(b"CB").unwrap();
+            records.push(rec);
+        }
+        records
+    }
+
+    fn qnames(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|r| String::from_utf8_lossy(r.qname()).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn untagged_only_keeps_only_untagged_reads() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(&input, TWO_CONTIG_HEADER, &partly_tagged_records());
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            untagged_only: true,
+            ..Default::default()
+        };
+        let stats = run_subsample(&input, None, &out, &opts).unwrap();
+
+        let written = read_all(&out);
+        assert_eq!(qnames(&written), vec!["untagged0", "untagged1"]);
+        assert!(written.iter().all(|r| r.aux(b"CB").is_err()));
+        assert_eq!(stats.reads_written, 2);
+    }
+
+    #[test]
+    fn denylist_drops_listed_barcodes_and_keeps_untagged_reads() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(&input, TWO_CONTIG_HEADER, &partly_tagged_records());
+        let barcodes = write_barcodes(&dir.path().join("deny.txt"), &["AAAA", "GGGG"]);
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            denylist: true,
+            ..Default::default()
+        };
+        run_subsample(&input, Some(barcodes), &out, &opts).unwrap();
+
+        assert_eq!(
+            qnames(&read_all(&out)),
+            vec!["read1", "untagged0", "untagged1"]
+        );
+    }
+
+    #[test]
+    fn throughput_rate_and_eta_from_known_samples() {
+        let mut throughput = Throughput::default();
+        assert_eq!(throughput.rate(), None);
+        throughput.add(1.0, 1000);
+        assert_eq!(throughput.rate(), None);
+        throughput.add(2.0, 3000);
+        assert_eq!(throughput.rate(), Some(2000.0));
+        // the overall 1500 reads/s is slower than the rolling 2000, so it is used
+        assert_eq!(throughput.eta_seconds(6000), Some(2.0));
+
+        // only the last THROUGHPUT_WINDOW samples count towards the rate
+        for i in 0..THROUGHPUT_WINDOW as u64 {
+            throughput.add(3.0 + i as f64, 3000 + 100 * (i + 1));
+        }
+        assert_eq!(throughput.rate(), Some(100.0));
+
+        // the total of the unindexed fixture is read off directly, as it is
+        // shorter than the density sample
+        let total = estimate_total_reads(&[fixture("eFL1_test.bam")]).unwrap();
+        assert_eq!(total, read_all(&fixture("eFL1_test.bam")).len() as u64);
+        assert_eq!(estimate_total_reads(&[Path::new("-")]), None);
+    }
+
+    #[test]
+    fn barcodes_in_array_tags_are_matched() {
+        let spelled: &[u8] = b"AAAA";
+        let numbers: &[u16] = &[1, 2, 3];
+        let mut bytes_rec = test_record("bytes", 10, "AAAA");
+        bytes_rec.remove_aux(b"CB").unwrap();
+        bytes_rec
+            .push_aux(b"CB", Aux::ArrayU8(spelled.into()))
+            .unwrap();
+        let mut numbers_rec = test_record("numbers", 20, "AAAA");
+        numbers_rec.remove_aux(b"CB").unwrap();
+        numbers_rec
+            .push_aux(b"CB", Aux::ArrayU16(numbers.into()))
+            .unwrap();
+        assert_eq!(get_record_tag(&bytes_rec, "CB"), Some(b"AAAA".to_vec()));
+        assert_eq!(get_record_tag(&numbers_rec, "CB"), Some(b"1,2,3".to_vec()));
+
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let other = test_record("other", 30, "CCCC");
+        write_bam(&input, TWO_CONTIG_HEADER, &[bytes_rec, numbers_rec, other]);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA", "1,2,3"]);
+        let out = dir.path().join("out.bam");
+        run_subsample(&input, Some(barcodes), &out, &SubsampleOptions::default()).unwrap();
+        assert_eq!(qnames(&read_all(&out)), vec!["bytes", "numbers"]);
+    }
+
+    #[test]
+    fn gc_range_drops_high_and_low_gc_reads() {
+        let with_seq = |name: &str, seq: &[u8]| {
+            let mut rec = Record::new();
+            let cigar = CigarString(vec![Cigar::Match(seq.len() as u32)]);
+            rec.set(name.as_bytes(), Some(&cigar), seq, &vec![30; seq.len()]);
+            rec.set_tid(0);
+            rec.set_pos(10);
+            rec.set_mtid(-1);
+            rec.set_mpos(-1);
+            rec.push_aux(b"CB", Aux::String("AAAA")).unwrap();
+            rec
+        };
+        let records = vec![
+            with_seq("high", b"GGCCGCGC"),
+            with_seq("low", b"AATTATAT"),
+            with_seq("balanced", b"ACGTACGT"),
+            with_seq("high_with_n", b"GCNNNNNA"),
+            with_seq("all_n", b"NNNNNNNN"),
+        ];
+        assert_eq!(gc_content(&records[0]), Some(1.0));
+        assert_eq!(gc_content(&records[1]), Some(0.0));
+        // Ns are left out of the denominator
+        assert_eq!(gc_content(&records[3]), Some(2.0 / 3.0));
+        assert_eq!(gc_content(&records[4]), None);
+
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(&input, TWO_CONTIG_HEADER, &records);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            min_gc: Some(0.2),
+            max_gc: Some(0.6),
+            ..Default::default()
+        };
+        run_subsample(&input, Some(barcodes), &out, &opts).unwrap();
+        assert_eq!(qnames(&read_all(&out)), vec!["balanced", "all_n"]);
+    }
+
+    #[test]
+    fn region_filters_reject_name_sorted_and_unindexed_input() {
+        let dir = tempdir().unwrap();
+        let bed = dir.path().join("regions.bed");
+        fs::write(&bed, "chr1\t0\t100\n").unwrap();
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let opts = SubsampleOptions {
+            bed_file: Some(bed),
+            ..Default::default()
+        };
+
+        let name_sorted = dir.path().join("name_sorted.bam");
+        write_bam(
+            &name_sorted,
+            b"@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:1000\n",
+            &[test_record("a", 50, "AAAA"), test_record("b", 10, "AAAA")],
+        );
+        let out = dir.path().join("out.bam");
+        let err = run_subsample(&name_sorted, Some(barcodes.clone()), &out, &opts).unwrap_err();
+        assert!(err.to_string().contains("sorted by queryname"), "{}", err);
+        assert!(!out.exists());
+
+        let unindexed = dir.path().join("unindexed.bam");
+        write_bam(
+            &unindexed,
+            TWO_CONTIG_HEADER,
+            &[test_record("a", 10, "AAAA")],
+        );
+        let err = run_subsample(&unindexed, Some(barcodes.clone()), &out, &opts).unwrap_err();
+        assert!(err.to_string().contains("has no index"), "{}", err);
+
+        bam::index::build(&unindexed, None, bam::index::Type::Bai, 1).unwrap();
+        run_subsample(&unindexed, Some(barcodes), &out, &opts).unwrap();
+        assert_eq!(read_all(&out).len(), 1);
+    }
+
+    #[test]
+    fn reads_carry_the_chunk_they_were_read_in() {
+        let input = fixture("eFL1_test.bam");
+        // records are told apart by name, flags and position
+        let key = |rec: &Record| (rec.qname().to_vec(), rec.flags(), rec.tid(), rec.pos());
+        let mut chunk_of = HashMap::new();
+        let offsets = bgzf_noffsets(&input, &4).unwrap();
+        assert!(offsets.len() > 1);
+        for (i, (start, stop)) in offsets.iter().enumerate() {
+            let mut bam = bam::Reader::from_path(&input).unwrap();
+            for r in bam.iter_chunk(*start, *stop) {
+                chunk_of.insert(key(&r.unwrap()), i as i32);
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            tag_chunk: Some("xk".to_string()),
+            ..Default::default()
+        };
+        subsample_bam(
+            &[input.clone()],
+            Some(fixture("eFL1_barcodes_revert.tsv")),
+            "CB".to_string(),
+            None,
+            None,
+            out.clone(),
+            4,
+            &opts,
+        )
+        .unwrap();
+
+        let written = read_all(&out);
+        assert!(!written.is_empty());
+        let mut seen = HashSet::new();
+        for rec in &written {
+            let chunk = match rec.aux(b"xk").unwrap() {
+                Aux::I32(chunk) => chunk,
+                other => panic!("unexpected xk tag {:?}", other),
+            };
+            assert_eq!(chunk_of[&key(rec)], chunk);
+            seen.insert(chunk);
+        }
+        assert!(seen.len() > 1);
+    }
+
+    #[test]
+    fn indel_only_keeps_reads_with_an_insertion_or_deletion() {
+        let with_cigar = |name: &str, barcode: &str, cigar: Vec<Cigar>| {
+            let mut rec = test_record(name, 10, barcode);
+            rec.set_cigar(Some(&CigarString(cigar)));
+            rec
+        };
+        let records = vec![
+            with_cigar(
+                "deletion",
+                "AAAA",
+                vec![Cigar::Match(2), Cigar::Del(3), Cigar::Match(2)],
+            ),
+            with_cigar("plain", "AAAA", vec![Cigar::Match(4)]),
+            with_cigar(
+                "insertion",
+                "AAAA",
+                vec![Cigar::Match(2), Cigar::Ins(1), Cigar::Match(1)],
+            ),
+            with_cigar(
+                "other_barcode",
+                "CCCC",
+                vec![Cigar::Match(2), Cigar::Del(3), Cigar::Match(2)],
+            ),
+        ];
+        assert!(has_indel(&records[0], 1));
+        assert!(!has_indel(&records[1], 1));
+
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(&input, TWO_CONTIG_HEADER, &records);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            indel_only: true,
+            ..Default::default()
+        };
+        run_subsample(&input, Some(barcodes.clone()), &out, &opts).unwrap();
+        assert_eq!(qnames(&read_all(&out)), vec!["deletion", "insertion"]);
+
+        let opts = SubsampleOptions {
+            min_indel_length: Some(2),
+            ..opts
+        };
+        run_subsample(&input, Some(barcodes), &out, &opts).unwrap();
+        assert_eq!(qnames(&read_all(&out)), vec!["deletion"]);
+    }
+
+    #[test]
+    fn max_output_bytes_stops_near_the_cap() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.bam");
+        let cap = 1024 * 1024;
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            max_output_bytes: Some(cap),
+            ..Default::default()
+        };
+        let stats = subsample_bam(
+            &[fixture("eFL1_test.bam")],
+            None,
+            "CB".to_string(),
+            None,
+            None,
+            out.clone(),
+            4,
+            &opts,
+        )
+        .unwrap();
+
+        // the cap is checked every OUTPUT_SIZE_CHECK_RECORDS reads against flushed blocks
+        let size = fs::metadata(&out).unwrap().len();
+        assert!(size >= cap, "{} bytes", size);
+        assert!(size < cap + 256 * 1024, "{} bytes", size);
+        let written = read_all(&out).len() as u64;
+        assert_eq!(written, stats.reads_written);
+        assert!(written < read_all(&fixture("eFL1_test.bam")).len() as u64);
+        assert_eq!(written % OUTPUT_SIZE_CHECK_RECORDS, 0);
+    }
+
+    #[test]
+    fn uppercase_tag_is_applied_after_the_substitution() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(
+            &input,
+            TWO_CONTIG_HEADER,
+            &[
+                test_record("lower", 10, "aacc-1"),
+                test_record("upper", 20, "GGTT-1"),
+            ],
+        );
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            uppercase_tag: true,
+            ..Default::default()
+        };
+        subsample_bam(
+            &[input],
+            None,
+            "CB".to_string(),
+            Some("-1".to_string()),
+            Some("-2".to_string()),
+            out.clone(),
+            1,
+            &opts,
+        )
+        .unwrap();
+
+        let written = read_all(&out);
+        assert_eq!(written[0].aux(b"CB").unwrap(), Aux::String("AACC-2"));
+        assert_eq!(written[1].aux(b"CB").unwrap(), Aux::String("GGTT-2"));
+    }
+
+    #[test]
+    fn duplicated_tags_error_under_strict_tags() {
+        let mut duplicated = test_record("duplicated", 10, "AAAA");
+        duplicated
+            .push_aux_unchecked(b"CB", Aux::String("CCCC"))
+            .unwrap();
+        assert!(has_duplicate_tag(&duplicated, "CB"));
+        assert!(!has_duplicate_tag(&test_record("single", 20, "AAAA"), "CB"));
+
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(
+            &input,
+            TWO_CONTIG_HEADER,
+            &[duplicated, test_record("single", 20, "CCCC")],
+        );
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let out = dir.path().join("out.bam");
+
+        // the first copy is matched
+        run_subsample(
+            &input,
+            Some(barcodes.clone()),
+            &out,
+            &SubsampleOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(qnames(&read_all(&out)), vec!["duplicated"]);
+
+        let opts = SubsampleOptions {
+            strict_tags: true,
+            ..Default::default()
+        };
+        let err = run_subsample(&input, Some(barcodes), &out, &opts).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Read duplicated has more than one CB tag"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn sort_by_name_gives_non_decreasing_names() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            sort_by_name: true,
+            ..Default::default()
+        };
+        subsample_bam(
+            &[fixture("eFL1_test.bam")],
+            Some(fixture("eFL1_barcodes_revert.tsv")),
+            "CB".to_string(),
+            None,
+            None,
+            out.clone(),
+            4,
+            &opts,
+        )
+        .unwrap();
+
+        let written = read_all(&out);
+        assert!(written.len() > 1);
+        assert!(written.windows(2).all(|w| w[0].qname() <= w[1].qname()));
+        let bam = bam::Reader::from_path(&out).unwrap();
+        assert_eq!(
+            header_sort_order(bam.header()).as_deref(),
+            Some("queryname")
+        );
+    }
+
+    #[test]
+    fn cell_ranger_barcodes_tsv_gz_loads_directly() {
+        // gzipped, one `-1`-suffixed barcode per line, as in filtered_feature_bc_matrix
+        let cell_ranger = fixture("eFL1_barcodes.tsv.gz");
+        let plain = fixture("eFL1_barcodes_revert.tsv");
+        let opts = SubsampleOptions {
+            barcode_suffix_delimiter: Some(b'-'),
+            ..Default::default()
+        };
+        let barcodes = load_barcodes(&cell_ranger, &opts).unwrap();
+        assert_eq!(barcodes.len(), 767);
+        assert!(barcodes.contains(&b"AACCATGTCCCTCTTT".to_vec()));
+        assert!(barcodes.iter().all(|b| b.len() == 16));
+
+        let dir = tempdir().unwrap();
+        let written = |barcodes: PathBuf, opts: &SubsampleOptions| {
+            run_subsample(
+                &fixture("eFL1_test.bam"),
+                Some(barcodes),
+                &dir.path().join("out.bam"),
+                opts,
+            )
+            .unwrap()
+            .reads_written
+        };
+        let expected = written(plain, &SubsampleOptions::default());
+        assert!(expected > 0);
+        assert_eq!(
+            written(cell_ranger.clone(), &SubsampleOptions::default()),
+            expected
+        );
+        assert_eq!(written(cell_ranger, &opts), expected);
+    }
+
+    #[test]
+    fn extra_tag_whitelists_must_all_match() {
+        let with_bc = |name: &str, cb: &str, bc: Option<&str>| {
+            let mut rec = test_record(name, 10, cb);
+            if let Some(bc) = bc {
+                rec.push_aux(b"BC", Aux::String(bc)).unwrap();
+            }
+            rec
+        };
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(
+            &input,
+            TWO_CONTIG_HEADER,
+            &[
+                with_bc("both", "AAAA", Some("GG")),
+                with_bc("cb_only", "AAAA", Some("TT")),
+                with_bc("bc_only", "CCCC", Some("GG")),
+                with_bc("no_bc", "AAAA", None),
+                with_bc("neither", "CCCC", Some("TT")),
+            ],
+        );
+        let cb_whitelist = write_barcodes(&dir.path().join("cb.txt"), &["AAAA"]);
+        let bc_whitelist = write_barcodes(&dir.path().join("bc.txt"), &["GG"]);
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            extra_tag_whitelists: Some(vec![("BC".to_string(), bc_whitelist)]),
+            ..Default::default()
+        };
+        run_subsample(&input, Some(cb_whitelist.clone()), &out, &opts).unwrap();
+        assert_eq!(qnames(&read_all(&out)), vec!["both"]);
+
+        let opts = SubsampleOptions {
+            match_any_tag: true,
+            ..opts
+        };
+        run_subsample(&input, Some(cb_whitelist), &out, &opts).unwrap();
+        assert_eq!(
+            qnames(&read_all(&out)),
+            vec!["both", "cb_only", "bc_only", "no_bc"]
+        );
+    }
+
+    #[test]
+    fn mapped_only_skips_unmapped_reads_and_stops_at_the_unplaced_tail() {
+        let mut records = two_contig_records();
+        let mut placed_unmapped = test_record("placed_unmapped", 15, "AAAA");
+        placed_unmapped.set_flags(0x4);
+        records.insert(1, placed_unmapped);
+        // out of order, so only a scan past the first unplaced read reaches it
+        let mut after_tail = test_record("after_tail", 40, "AAAA");
+        after_tail.set_tid(1);
+        records.push(after_tail);
+
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            rewrite_only: true,
+            mapped_only: true,
+            ..Default::default()
+        };
+        let run = |header: &[u8]| {
+            let input = dir.path().join("input.bam");
+            write_bam(&input, header, &records);
+            let stats = run_subsample(&input, None, &out, &opts).unwrap();
+            (stats.reads_scanned, qnames(&read_all(&out)))
+        };
+
+        let (scanned, written) = run(TWO_CONTIG_HEADER);
+        assert_eq!(scanned, 5);
+        assert_eq!(written, vec!["read0", "read1", "read2", "read3", "read4"]);
+
+        // without SO:coordinate the unplaced reads are skipped one by one
+        let (scanned, written) = run(b"@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n");
+        assert_eq!(scanned, 6);
+        assert_eq!(written.last().unwrap(), "after_tail");
+    }
+
+    #[test]
+    fn discordant_only_keeps_pairs_across_contigs() {
+        let mate =
+            |name: &str, tid: i32, pos: i64, mtid: i32, mpos: i64, insert: i64, flags: u16| {
+                let mut rec = test_record(name, pos, "AAAA");
+                rec.set_tid(tid);
+                rec.set_mtid(mtid);
+                rec.set_mpos(mpos);
+                rec.set_insert_size(insert);
+                rec.set_flags(0x1 | flags);
+                rec
+            };
+        let records = vec![
+            mate("concordant", 0, 100, 0, 300, 204, 0x40),
+            mate("discordant", 0, 150, 1, 50, 0, 0x40),
+            mate("far", 0, 200, 0, 900, 704, 0x40),
+            mate("lonely", 0, 250, 0, 250, 0, 0x40 | 0x8),
+            mate("concordant", 0, 300, 0, 100, -204, 0x80),
+            mate("far", 0, 900, 0, 200, -704, 0x80),
+            mate("discordant", 1, 50, 0, 150, 0, 0x80),
+        ];
+
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_bam(&input, TWO_CONTIG_HEADER, &records);
+        let barcodes = write_barcodes(&dir.path().join("barcodes.txt"), &["AAAA"]);
+        let out = dir.path().join("out.bam");
+        let opts = SubsampleOptions {
+            discordant_only: true,
+            ..Default::default()
+        };
+        run_subsample(&input, Some(barcodes.clone()), &out, &opts).unwrap();
+        assert_eq!(qnames(&read_all(&out)), vec!["discordant", "discordant"]);
+
+        let opts = SubsampleOptions {
+            discordant_min_insert: Some(500),
+            ..opts
+        };
+        run_subsample(&input, Some(barcodes), &out, &opts).unwrap();
+        assert_eq!(
+            qnames(&read_all(&out)),
+            vec!["discordant", "far", "far", "discordant"]
+        );
+    }
+
+    #[test]
+    fn streamed_split_outputs_sum_to_the_input() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let barcodes = ["AAAA", "CCCC", "GGGG"];
+        let records: Vec<Record> = (0..12)
+            .map(|i| test_record(&format!("read{}", i), i as i64, barcodes[i % 3]))
+            .collect();
+        write_bam(&input, TWO_CONTIG_HEADER, &records);
+        let whitelist = write_barcodes(&dir.path().join("barcodes.txt"), &barcodes);
+        let split_dir = dir.path().join("split");
+        let opts = SubsampleOptions {
+            split_output_dir: Some(split_dir.clone()),
+            // fewer handles than groups, so each group is written in several parts
+            max_open_files: 1,
+            ..Default::default()
+        };
+        let stats =
+            run_subsample(&input, Some(whitelist), &dir.path().join("out.bam"), &opts).unwrap();
+
+        // one pass: every input read was scanned exactly once
+        assert_eq!(stats.reads_scanned, 12);
+        let mut total = 0;
+        for barcode in &barcodes {
+            let group = read_all(&split_dir.join(format!("{}.bam", barcode)));
+            assert_eq!(group.len(), 4);
+            assert!(group
+                .iter()
+                .all(|r| r.aux(b"CB").unwrap() == Aux::String(*barcode)));
+            total += group.len();
+        }
+        assert_eq!(total, records.len());
+        assert_eq!(fs::read_dir(&split_dir).unwrap().count(), 3);
+        let split_counts = stats.split_counts.unwrap();
+        assert_eq!(split_counts.values().sum::<u64>(), 12);
+    }
 }
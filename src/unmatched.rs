@@ -0,0 +1,88 @@
+use crate::fastq::sidecar_writer;
+use failure::Error;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Most frequent unmatched barcodes written to the histogram.
+const UNMATCHED_TOP_BARCODES: usize = 10_000;
+
+/// Distinct barcodes counted before the rarest are pruned.
+const UNMATCHED_CAPACITY: usize = 10 * UNMATCHED_TOP_BARCODES;
+
+/// Counts of tag values that were not in the whitelist.
+///
+/// Memory is bounded by pruning the rarer half of the barcodes whenever more
+/// than `UNMATCHED_CAPACITY` are held, so counts of barcodes first seen late
+/// can be a little low, but frequent barcodes, the ones worth reporting, are
+/// kept.
+#[derive(Default)]
+pub struct UnmatchedBarcodes {
+    counts: HashMap<Vec<u8>, u64>,
+}
+
+impl UnmatchedBarcodes {
+    pub fn add(&mut self, tag: &[u8]) {
+        match self.counts.get_mut(tag) {
+            Some(n) => *n += 1,
+            None => {
+                self.counts.insert(tag.to_vec(), 1);
+                if self.counts.len() > UNMATCHED_CAPACITY {
+                    self.prune(UNMATCHED_CAPACITY / 2);
+                }
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: UnmatchedBarcodes) {
+        for (tag, n) in other.counts {
+            *self.counts.entry(tag).or_insert(0) += n;
+        }
+        if self.counts.len() > UNMATCHED_CAPACITY {
+            self.prune(UNMATCHED_CAPACITY / 2);
+        }
+    }
+
+    /// Keeps the `keep` most frequent barcodes, ties at the cut going to the
+    /// smaller barcode so the same counts always keep the same barcodes.
+    fn prune(&mut self, keep: usize) {
+        if self.counts.len() <= keep {
+            return;
+        }
+        let mut counts: Vec<(Vec<u8>, u64)> = self.counts.drain().collect();
+        counts.select_nth_unstable_by(keep, |a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(keep);
+        self.counts = counts.into_iter().collect();
+    }
+
+    /// Writes the most frequent barcodes as `barcode<TAB>count`, most frequent first.
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut counts: Vec<(&Vec<u8>, &u64)> = self.counts.iter().collect();
+        counts.sort_unstable_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let mut out = sidecar_writer(path)?;
+        writeln!(out, "barcode\tcount")?;
+        for (tag, n) in counts.into_iter().take(UNMATCHED_TOP_BARCODES) {
+            writeln!(out, "{}\t{}", String::from_utf8_lossy(tag), n)?;
+        }
+        out.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_keeps_exactly_keep_barcodes_when_counts_tie() {
+        let mut unmatched = UnmatchedBarcodes::default();
+        for tag in &[b"AAAA", b"CCCC", b"GGGG", b"TTTT"] {
+            unmatched.add(*tag);
+        }
+        unmatched.add(b"TTTT");
+        unmatched.prune(2);
+        let mut kept: Vec<_> = unmatched.counts.into_iter().collect();
+        kept.sort();
+        assert_eq!(kept, vec![(b"AAAA".to_vec(), 1), (b"TTTT".to_vec(), 2)]);
+    }
+}
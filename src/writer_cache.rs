@@ -0,0 +1,126 @@
+use failure::Error;
+use rust_htslib::bam::{self, Read, Record};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Keeps at most `max_open` BAM writers open, keyed by e.g. barcode or contig.
+///
+/// BAM files cannot be appended to, so a writer that is evicted and later
+/// needed again starts a new part file. `finish` returns every part per key,
+/// in write order, for `concat_parts` to join.
+pub struct WriterCache {
+    dir: PathBuf,
+    header: bam::Header,
+    max_open: usize,
+    /// Open writers with the write count at their last use, oldest evicted first.
+    open: HashMap<Vec<u8>, (bam::Writer, u64)>,
+    generation: u64,
+    parts: HashMap<Vec<u8>, Vec<PathBuf>>,
+    counts: HashMap<Vec<u8>, u64>,
+}
+
+/// File name stem for `key`. Letters, digits, `-`, `_` and `.` are kept and
+/// every other byte is percent-encoded, so distinct keys never share a file.
+pub fn sanitize_key(key: &[u8]) -> String {
+    let mut name = String::with_capacity(key.len());
+    for &b in key {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.' {
+            name.push(b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
+    }
+    name
+}
+
+impl WriterCache {
+    pub fn new(dir: &Path, header: bam::Header, max_open: usize) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        Ok(WriterCache {
+            dir: dir.to_path_buf(),
+            header,
+            max_open: max_open.max(1),
+            open: HashMap::new(),
+            generation: 0,
+            parts: HashMap::new(),
+            counts: HashMap::new(),
+        })
+    }
+
+    pub fn write(&mut self, key: &[u8], rec: &Record) -> Result<(), Error> {
+        self.generation += 1;
+        if !self.open.contains_key(key) {
+            if self.open.len() >= self.max_open {
+                let oldest = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    // dropping the writer flushes and closes it
+                    self.open.remove(&oldest);
+                }
+            }
+            let parts = self.parts.entry(key.to_vec()).or_insert_with(Vec::new);
+            let path = self
+                .dir
+                .join(format!("{}.{}.bam", sanitize_key(key), parts.len()));
+            let writer = bam::Writer::from_path(&path, &self.header, bam::Format::Bam)?;
+            parts.push(path);
+            self.open.insert(key.to_vec(), (writer, 0));
+        }
+        let (writer, last_used) = self.open.get_mut(key).unwrap();
+        *last_used = self.generation;
+        writer.write(rec)?;
+        *self.counts.entry(key.to_vec()).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Closes all writers, returning the part files and record count per key.
+    pub fn finish(mut self) -> (HashMap<Vec<u8>, Vec<PathBuf>>, HashMap<Vec<u8>, u64>) {
+        self.open.clear();
+        (self.parts, self.counts)
+    }
+}
+
+/// Joins part files into `out_path`, holding only one reader open at a time.
+/// The parts are removed afterwards unless `keep_parts` is set, as a resumable
+/// run still needs them.
+pub fn concat_parts(
+    parts: &[PathBuf],
+    header: &bam::Header,
+    out_path: &Path,
+    keep_parts: bool,
+) -> Result<(), Error> {
+    if parts.len() == 1 && !keep_parts {
+        if fs::rename(&parts[0], out_path).is_ok() {
+            return Ok(());
+        }
+    }
+    let mut out = bam::Writer::from_path(out_path, header, bam::Format::Bam)?;
+    for part in parts {
+        let mut rdr = bam::Reader::from_path(part)?;
+        for r in rdr.records() {
+            out.write(&r?)?;
+        }
+        if !keep_parts {
+            fs::remove_file(part)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitized_keys_stay_distinct() {
+        assert_eq!(sanitize_key(b"ACGT-1"), "ACGT-1");
+        assert_eq!(sanitize_key(b"A:B"), "A%3AB");
+        assert_eq!(sanitize_key(b"A%3AB"), "A%253AB");
+        assert_ne!(sanitize_key(b"A:B"), sanitize_key(b"A_B"));
+        assert_ne!(sanitize_key(b"A/B"), sanitize_key(b"A:B"));
+    }
+}
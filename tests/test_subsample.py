@@ -0,0 +1,97 @@
+"""Tests of the Python API, run with `maturin develop && pytest`."""
+
+from pathlib import Path
+
+import pytest
+
+import rust_bam_tools
+
+FIXTURES = Path(__file__).resolve().parent.parent / "test"
+BAM = str(FIXTURES / "eFL1_test.bam")
+BARCODES = str(FIXTURES / "eFL1_barcodes_revert.tsv")
+
+
+def test_keyword_arguments_and_omitted_optionals(tmp_path):
+    out = tmp_path / "out.bam"
+    result = rust_bam_tools.subsample_bam(
+        BAM, "CB", str(out), barcodes_file=BARCODES, n_threads=2
+    )
+    assert isinstance(result, rust_bam_tools.SubsampleResult)
+    assert result.output_path == str(out)
+    assert 0 < result.reads_written < result.reads_scanned
+    assert result.barcode_counts is None
+    assert result.shard_counts is None
+
+    # with nothing to match against, barcodes_file can be left out entirely
+    copy = rust_bam_tools.subsample_bam(BAM, "CB", str(tmp_path / "copy.bam"), rewrite_only=True)
+    assert copy.reads_written == copy.reads_scanned == result.reads_scanned
+
+
+def test_optional_arguments_are_keyword_only(tmp_path):
+    with pytest.raises(TypeError):
+        rust_bam_tools.subsample_bam(BAM, "CB", str(tmp_path / "out.bam"), BARCODES)
+
+
+def test_sequential_calls_share_a_thread_pool(tmp_path):
+    pool = rust_bam_tools.ThreadPoolHandle(2)
+    assert pool.n_threads == 2
+    # calls reusing the pool match one that builds its own
+    own = rust_bam_tools.subsample_bam(
+        BAM, "CB", str(tmp_path / "own.bam"), barcodes_file=BARCODES, n_threads=2
+    )
+    for name in ("first.bam", "second.bam"):
+        shared = rust_bam_tools.subsample_bam(
+            BAM, "CB", str(tmp_path / name), barcodes_file=BARCODES, n_threads=2, thread_pool=pool
+        )
+        assert shared.reads_written == own.reads_written
+    assert (tmp_path / "first.bam").read_bytes() == (tmp_path / "second.bam").read_bytes()
+    assert pool.n_threads == 2
+
+
+def test_matched_record_iterator_yields_read_fields(tmp_path):
+    reads = list(rust_bam_tools.MatchedRecordIterator(BAM, BARCODES))
+    written = rust_bam_tools.subsample_bam(BAM, "CB", str(tmp_path / "out.bam"), barcodes_file=BARCODES)
+    assert len(reads) == written.reads_written == 8240
+
+    # the first whitelisted read of the fixture
+    assert reads[0] == {
+        "qname": "K00198:208:HKJVYBBXX:7:2109:26930:2053",
+        "flag": 16,
+        "tid": 0,
+        "pos": 11843,
+        "mapq": 1,
+        "barcode": "TACGGGCTCAGCAACT-1",
+        "seq": "GGTATCATTCACCATTTTTCTTTTCGGTAACTTGCCGTCAGCCTTTTCTTTGACCTCTTCTTTC"
+        "TGTTCATGTGTATTTGCTGTCTCTTAGCCCAGAC",
+    }
+    whitelist = set((FIXTURES / "eFL1_barcodes_revert.tsv").read_text().split())
+    assert all(read["barcode"] in whitelist for read in reads)
+
+
+def run_seeded(tmp_path, name, seed):
+    out = tmp_path / name
+    rust_bam_tools.subsample_bam(
+        BAM,
+        "CB",
+        str(out),
+        barcodes_file=BARCODES,
+        sample_n_barcodes=100,
+        subsample_fraction=0.5,
+        seed=seed,
+    )
+    sampled = Path(str(out) + ".sampled_barcodes.txt").read_text()
+    return out.read_bytes(), sampled
+
+
+def test_seed_reproduces_the_output(tmp_path):
+    first = run_seeded(tmp_path, "first.bam", 42)
+    assert run_seeded(tmp_path, "second.bam", 42) == first
+    assert run_seeded(tmp_path, "other.bam", 43) != first
+
+
+def test_seeds_differing_in_the_high_bits_differ(tmp_path):
+    low = run_seeded(tmp_path, "low.bam", 1)
+    high = run_seeded(tmp_path, "high.bam", 1 + (1 << 32))
+    # both the barcode sample and the read name hash see the upper 32 bits
+    assert high[0] != low[0]
+    assert high[1] != low[1]